@@ -1,12 +1,21 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, time::Duration};
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use khimeradb::{streams::FileSegmentStream, log::Log};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use khimeradb::{streams::FileSegmentStream, kv::SSTable, log::Log, LogSync, SSTEngineOptions};
 use tempfile::tempfile;
 
 const MESSAGE_SIZE: usize = 1024;
 const ITERATIONS: usize = 1000;
 const SEGMENT_SIZE: u64 = 1024 * 1024;  // 1 MiB
+const ENGINE_INSERTS: usize = 10000;
+const SMALL_ENTRIES: usize = 10000;
+const LARGE_VALUE_SIZE: usize = 1024 * 1024;
+const LARGE_VALUE_READS: usize = 10000;
+const LOOKUP_SEGMENTS: usize = 100;
+const LOOKUPS: usize = 10000;
+const COMPACTION_ENTRIES: usize = 64 * 1024;
+const COMPACTION_VALUE_SIZE: usize = 4096;  // 256 MiB in total
+const COMPACTION_SEGMENT_SIZE: usize = 4 * 1024 * 1024;
 
 pub fn bench_memory_log_10000_appends(c: &mut Criterion) {
     c.bench_function("MemoryLog appends", |b| b.iter(|| {
@@ -35,6 +44,26 @@ pub fn bench_memory_log_10000_iterator(c: &mut Criterion) {
     }));
 }
 
+pub fn bench_memory_log_small_entries(c: &mut Criterion) {
+    let mut log = Log::new(RefCell::new(std::io::Cursor::new(Vec::new())));
+    for i in 0..SMALL_ENTRIES {
+        log.append(&(i as u64).to_le_bytes()).unwrap();
+    }
+
+    let mut group = c.benchmark_group("MemoryLog small entries");
+    group.bench_function("iterator", |b| b.iter(|| {
+        for entry in &log {
+            black_box(entry.unwrap());
+        }
+    }));
+    group.bench_function("for_each_entry", |b| b.iter(|| {
+        log.for_each_entry(|entry| {
+            black_box(entry);
+        }).unwrap();
+    }));
+    group.finish();
+}
+
 pub fn bench_file_log_10000_iterator(c: &mut Criterion) {
     c.bench_function("File Log iterator", |b| b.iter(|| {
         let file = tempfile().unwrap();
@@ -63,6 +92,21 @@ pub fn bench_file_segment_log_10000_appends(c: &mut Criterion) {
     }));
 }
 
+pub fn bench_file_segment_log_10000_appends_flushed(c: &mut Criterion) {
+    c.bench_function("FileSegmentLog appends flushing every append", |b| b.iter(|| {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = FileSegmentStream::new(tempdir.path().to_path_buf(), SEGMENT_SIZE);
+        let mut log = Log::new(RefCell::new(storage));
+
+        let data = [0; MESSAGE_SIZE];
+
+        for _ in 0..black_box(ITERATIONS) {
+            let _ = log.append(&data);
+            let _ = log.flush();
+        }
+    }));
+}
+
 pub fn bench_file_segment_log_10000_iterator(c: &mut Criterion) {
     c.bench_function("FileSegmentLog iterations", |b| b.iter(|| {
         let tempdir = tempfile::tempdir().unwrap();
@@ -80,11 +124,197 @@ pub fn bench_file_segment_log_10000_iterator(c: &mut Criterion) {
     }));
 }
 
+pub fn bench_file_segment_log_append_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("FileSegmentLog batch");
+    let entries = vec![[0u8; MESSAGE_SIZE]; ITERATIONS];
+    let entries: Vec<&[u8]> = entries.iter().map(|e| &e[..]).collect();
+    group.bench_function("append in a loop", |b| b.iter(|| {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = FileSegmentStream::new(tempdir.path().to_path_buf(), SEGMENT_SIZE);
+        let mut log = Log::new(RefCell::new(storage));
+        for entry in black_box(&entries) {
+            let _ = log.append(entry);
+        }
+    }));
+    group.bench_function("append_batch", |b| b.iter(|| {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = FileSegmentStream::new(tempdir.path().to_path_buf(), SEGMENT_SIZE);
+        let mut log = Log::new(RefCell::new(storage));
+        let _ = log.append_batch(black_box(&entries));
+    }));
+    group.finish();
+}
+
+fn engine_inserts(log_sync: LogSync) {
+    let tempdir = tempfile::tempdir().unwrap();
+    let options = SSTEngineOptions { log_sync, ..Default::default() };
+    let mut engine = options.open(tempdir.path()).unwrap();
+    let value = [0; 100];
+
+    for i in 0..black_box(ENGINE_INSERTS) {
+        engine.insert(&format!("key{}", i), &value).unwrap();
+    }
+    engine.sync().unwrap();
+}
+
+pub fn bench_engine_10000_inserts_log_sync(c: &mut Criterion) {
+    let mut group = c.benchmark_group("SSTEngine inserts");
+    group.sample_size(10);
+    group.bench_function("sync every operation", |b| b.iter(|| engine_inserts(LogSync::EveryOperation)));
+    group.bench_function("group commit", |b| b.iter(|| engine_inserts(LogSync::GroupCommit {
+        max_operations: 256,
+        max_bytes: usize::MAX,
+        max_delay: Duration::from_millis(10),
+    })));
+    // Framing and appending log entries without syncing in between
+    group.bench_function("single sync", |b| b.iter(|| engine_inserts(LogSync::GroupCommit {
+        max_operations: usize::MAX,
+        max_bytes: usize::MAX,
+        max_delay: Duration::MAX,
+    })));
+    group.finish();
+}
+
+pub fn bench_sstable_large_value_reads(c: &mut Criterion) {
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut table = SSTable::try_new(tempdir.path(), 4 * LARGE_VALUE_SIZE).unwrap();
+    table.insert("large", &vec![0; LARGE_VALUE_SIZE]).unwrap();
+
+    let mut group = c.benchmark_group("SSTable 1 MiB value reads");
+    group.sample_size(10);
+    group.bench_function("copied", |b| b.iter(|| {
+        for _ in 0..LARGE_VALUE_READS {
+            black_box(table.try_get(black_box("large")).unwrap());
+        }
+    }));
+    group.bench_function("shared", |b| b.iter(|| {
+        for _ in 0..LARGE_VALUE_READS {
+            black_box(table.get_shared(black_box("large")).unwrap());
+        }
+    }));
+    group.finish();
+}
+
+fn segmented_table(path: &std::path::Path, key_index: bool) -> SSTable {
+    // Every segment spans most of the key space, so bounds alone skip none of them
+    let mut table = SSTable::try_new(path, usize::MAX).unwrap().with_key_index(key_index);
+    for segment in 0..LOOKUP_SEGMENTS {
+        for i in (segment..1000).step_by(LOOKUP_SEGMENTS) {
+            table.insert(&format!("key{:04}", i), b"value").unwrap();
+        }
+        table.flush().unwrap();
+    }
+    table
+}
+
+pub fn bench_sstable_missing_key_lookups(c: &mut Criterion) {
+    let tempdir = tempfile::tempdir().unwrap();
+    let scanned = segmented_table(&tempdir.path().join("scan"), false);
+    let indexed = segmented_table(&tempdir.path().join("index"), true);
+    // Nine in ten keys are missing
+    let keys: Vec<String> = (0..LOOKUPS).map(|i| format!("key{:04}", i)).collect();
+
+    let mut group = c.benchmark_group("SSTable lookups over 100 segments");
+    group.bench_function("reverse scan", |b| b.iter(|| {
+        for key in &keys {
+            black_box(scanned.get_shared(black_box(key)).unwrap());
+        }
+    }));
+    group.bench_function("key index", |b| b.iter(|| {
+        for key in &keys {
+            black_box(indexed.get_shared(black_box(key)).unwrap());
+        }
+    }));
+    group.finish();
+}
+
+pub fn bench_sstable_file_segment_lookups(c: &mut Criterion) {
+    let tempdir = tempfile::tempdir().unwrap();
+    {
+        let mut table = SSTable::try_new(tempdir.path(), 64 * 1024).unwrap();
+        for i in 0..LOOKUPS {
+            table.insert(&format!("key{:05}", i), &[0; 100]).unwrap();
+        }
+        table.flush().unwrap();
+    }
+    let keys: Vec<String> = (0..LOOKUPS).map(|i| format!("key{:05}", i * 7 % LOOKUPS)).collect();
+
+    let mut group = c.benchmark_group("SSTable file segment lookups");
+    group.sample_size(10);
+    let lazy = SSTable::try_new_lazy(tempdir.path(), 64 * 1024).unwrap();
+    group.bench_function("read", |b| b.iter(|| {
+        for key in &keys {
+            black_box(lazy.get_shared(black_box(key)).unwrap());
+        }
+    }));
+    #[cfg(feature = "mmap")]
+    {
+        let mapped = SSTable::try_new_mapped(tempdir.path(), 64 * 1024).unwrap();
+        group.bench_function("mmap", |b| b.iter(|| {
+            for key in &keys {
+                black_box(mapped.get_shared(black_box(key)).unwrap());
+            }
+        }));
+    }
+    group.finish();
+}
+
+// Lazy table opened on a copy of the segment files of another
+fn copy_table(from: &std::path::Path) -> (tempfile::TempDir, SSTable) {
+    let dir = tempfile::tempdir().unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), dir.path().join(entry.file_name())).unwrap();
+    }
+    let table = SSTable::try_new_lazy(dir.path(), COMPACTION_SEGMENT_SIZE).unwrap();
+    (dir, table)
+}
+
+pub fn bench_sstable_full_compaction(c: &mut Criterion) {
+    // Every key is written twice, in segments far apart, so compaction has to shadow half
+    let source = tempfile::tempdir().unwrap();
+    {
+        let mut table = SSTable::try_new_lazy(source.path(), COMPACTION_SEGMENT_SIZE).unwrap();
+        let value = vec![0; COMPACTION_VALUE_SIZE];
+        for round in 0..2 {
+            for i in (round..COMPACTION_ENTRIES).step_by(2) {
+                table.insert(&format!("key{:06}", i), &value).unwrap();
+            }
+            for i in ((1 - round)..COMPACTION_ENTRIES).step_by(2) {
+                table.insert(&format!("key{:06}", i), &value).unwrap();
+            }
+        }
+        table.flush().unwrap();
+    }
+
+    let mut group = c.benchmark_group("SSTable full compaction of 256 MiB");
+    group.sample_size(10);
+    group.bench_function("serial", |b| b.iter_batched(|| copy_table(source.path()), |(dir, mut table)| {
+        table.compact().unwrap();
+        dir
+    }, BatchSize::PerIteration));
+    #[cfg(feature = "parallel")]
+    group.bench_function("parallel", |b| b.iter_batched(|| copy_table(source.path()), |(dir, table)| {
+        let shards = std::thread::available_parallelism().map_or(4, |n| n.get());
+        table.with_compaction_shards(shards).compact().unwrap();
+        dir
+    }, BatchSize::PerIteration));
+    group.finish();
+}
+
 criterion_group!(benches,
     bench_memory_log_10000_appends,
     bench_memory_log_10000_iterator,
+    bench_memory_log_small_entries,
     bench_file_log_10000_iterator,
     bench_file_segment_log_10000_appends,
-    bench_file_segment_log_10000_iterator
+    bench_file_segment_log_10000_appends_flushed,
+    bench_file_segment_log_10000_iterator,
+    bench_file_segment_log_append_batch,
+    bench_engine_10000_inserts_log_sync,
+    bench_sstable_large_value_reads,
+    bench_sstable_missing_key_lookups,
+    bench_sstable_file_segment_lookups,
+    bench_sstable_full_compaction
 );
 criterion_main!(benches);
@@ -0,0 +1,98 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+
+use khimeradb::SSTEngine;
+
+const USAGE: &str = "Usage: khimera --path <dir> (get <key> | put <key> <value> | del <key> | scan [prefix])";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args, &mut std::io::stdout().lock()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+// Run a command against the store, returning false if get found no value
+fn run<W: Write>(args: &[String], out: &mut W) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let Some(flag) = args.iter().position(|arg| *arg == "--path").filter(|&i| i + 1 < args.len()) else {
+        return Err(USAGE.into());
+    };
+    let path = args.drain(flag..flag + 2).nth(1).unwrap();
+
+    let mut engine = SSTEngine::try_new(Path::new(path))?;
+    match args[..] {
+        ["get", key] => match engine.get(key)? {
+            Some(value) => {
+                out.write_all(&value)?;
+                writeln!(out)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        },
+        ["put", key, value] => {
+            engine.insert(key, value.as_bytes())?;
+            engine.flush()?;
+            Ok(true)
+        }
+        ["del", key] => {
+            engine.delete(key)?;
+            engine.flush()?;
+            Ok(true)
+        }
+        ["scan"] | ["scan", _] => {
+            for (key, value) in engine.scan_prefix(args.get(1).copied().unwrap_or_default()) {
+                write!(out, "{}\t", key)?;
+                out.write_all(&value)?;
+                writeln!(out)?;
+            }
+            Ok(true)
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_command(dir: &Path, command: &[&str]) -> (bool, String) {
+        let mut args = vec!["--path".to_string(), dir.to_str().unwrap().to_string()];
+        args.extend(command.iter().map(|arg| arg.to_string()));
+        let mut out = Vec::new();
+        let found = run(&args, &mut out).unwrap();
+        (found, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(run_command(dir.path(), &["get", "user:1"]), (false, String::new()));
+
+        run_command(dir.path(), &["put", "user:1", "alice"]);
+        run_command(dir.path(), &["put", "user:2", "bob"]);
+        run_command(dir.path(), &["put", "group:1", "admins"]);
+        assert_eq!(run_command(dir.path(), &["get", "user:1"]), (true, "alice\n".to_string()));
+        assert_eq!(run_command(dir.path(), &["scan", "user:"]).1, "user:1\talice\nuser:2\tbob\n");
+
+        run_command(dir.path(), &["del", "user:1"]);
+        assert_eq!(run_command(dir.path(), &["get", "user:1"]), (false, String::new()));
+        assert_eq!(run_command(dir.path(), &["scan"]).1, "group:1\tadmins\nuser:2\tbob\n");
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap().to_string();
+        for args in [vec!["get", "key"], vec!["--path"], vec!["--path", &path, "put", "key"], vec!["--path", &path]] {
+            let args: Vec<String> = args.into_iter().map(String::from).collect();
+            assert!(run(&args, &mut Vec::new()).is_err());
+        }
+    }
+}
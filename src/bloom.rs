@@ -0,0 +1,92 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+const BITS_PER_KEY: usize = 10;
+const HASH_COUNT: u64 = 7;
+
+#[derive(Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    // Number of keys the filter was sized for
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    pub fn new(capacity: usize) -> BloomFilter {
+        let capacity = capacity.max(1);
+        let words = (capacity * BITS_PER_KEY).div_ceil(64);
+        BloomFilter {
+            bits: vec![0; words],
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub fn from_keys<'a>(keys: impl Iterator<Item = &'a str>, capacity: usize) -> BloomFilter {
+        let mut filter = BloomFilter::new(capacity);
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.bit_indexes(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    // False means the key was never inserted, true means it may have been
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_indexes(key).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn bit_indexes(&self, key: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash & 0xFFFF_FFFF, hash >> 32);
+        let bit_count = (self.bits.len() * 64) as u64;
+        (0..HASH_COUNT).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(100);
+        filter.insert("key1");
+        filter.insert("key2");
+
+        assert!(filter.may_contain("key1"));
+        assert!(filter.may_contain("key2"));
+        assert_eq!(filter.len(), 2);
+    }
+
+    #[test]
+    fn test_bloom_filter_false_positive_rate() {
+        let keys: Vec<_> = (0..1000).map(|i| format!("key{}", i)).collect();
+        let filter = BloomFilter::from_keys(keys.iter().map(|k| k.as_str()), keys.len());
+
+        let false_positives = (0..1000)
+            .filter(|i| filter.may_contain(&format!("missing{}", i)))
+            .count();
+        assert!(false_positives < 50);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{Error, Result, SSTEngine};
+
+// Keys of a column family are stored as the marker, the family name, the marker again and the
+// key, so every family is a contiguous key range of its own. Column family names can't contain
+// the marker, and the default key space leaves out keys starting with it.
+pub(crate) const COLUMN_FAMILY_MARKER: char = '\u{1}';
+
+// Separate key space within an engine, sharing its log and segments. Operations go through the
+// engine like any other, the log records the family as part of the key.
+pub struct ColumnFamily<'a> {
+    engine: &'a mut SSTEngine,
+    prefix: String,
+}
+
+impl<'a> ColumnFamily<'a> {
+    pub(crate) fn new(engine: &'a mut SSTEngine, name: &str) -> Result<ColumnFamily<'a>> {
+        if name.is_empty() || name.contains(COLUMN_FAMILY_MARKER) {
+            return Err(Error::InvalidKey(name.to_string()));
+        }
+        let prefix = format!("{}{}{}", COLUMN_FAMILY_MARKER, name, COLUMN_FAMILY_MARKER);
+        Ok(ColumnFamily { engine, prefix })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.prefix[1..self.prefix.len() - 1]
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Box<[u8]>>> {
+        self.engine.get(&self.key(key))
+    }
+
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        self.engine.contains_key(&self.key(key))
+    }
+
+    // Returns the value the key had before, if any
+    pub fn insert(&mut self, key: &str, value: &[u8]) -> Result<Option<Box<[u8]>>> {
+        let key = self.write_key(key)?;
+        self.engine.insert(&key, value)
+    }
+
+    // Returns the removed value, None if the key had no live value
+    pub fn delete(&mut self, key: &str) -> Result<Option<Box<[u8]>>> {
+        let key = self.write_key(key)?;
+        self.engine.delete(&key)
+    }
+
+    // Live entries of the family in ascending key order
+    pub fn iter(&self) -> impl Iterator<Item = (String, Box<[u8]>)> + '_ {
+        self.engine.kv.scan_prefix(&self.prefix).map(|(key, value)| (self.strip(key), value))
+    }
+
+    // Live entries of the family with start <= key < end in ascending key order
+    pub fn scan(&self, start: &str, end: &str) -> impl Iterator<Item = (String, Box<[u8]>)> + '_ {
+        self.engine.kv.scan(&self.key(start), &self.key(end)).map(|(key, value)| (self.strip(key), value))
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    // The prefix keeps the stored key from being empty, empty keys are rejected here instead
+    fn write_key(&self, key: &str) -> Result<String> {
+        if key.is_empty() {
+            return Err(Error::InvalidKey(String::new()));
+        }
+        Ok(self.key(key))
+    }
+
+    fn strip(&self, key: String) -> String {
+        key[self.prefix.len()..].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::SSTEngine;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_column_families_are_independent() {
+        let root = tempdir().unwrap();
+        {
+            let mut engine = SSTEngine::try_new(root.path()).unwrap();
+            engine.insert("alice", b"default").unwrap();
+            engine.cf("users").unwrap().insert("alice", b"user").unwrap();
+            let mut sessions = engine.cf("sessions").unwrap();
+            assert_eq!(sessions.name(), "sessions");
+            sessions.insert("alice", b"session").unwrap();
+            sessions.insert("bob", b"session").unwrap();
+            sessions.delete("bob").unwrap();
+            sessions.insert("carol", b"session").unwrap();
+        }
+
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        assert_eq!(&*engine.get("alice").unwrap().unwrap(), b"default");
+        assert_eq!(&*engine.cf("users").unwrap().get("alice").unwrap().unwrap(), b"user");
+        assert!(!engine.cf("users").unwrap().contains_key("carol").unwrap());
+
+        let sessions = engine.cf("sessions").unwrap();
+        assert_eq!(&*sessions.get("alice").unwrap().unwrap(), b"session");
+        assert!(sessions.get("bob").unwrap().is_none());
+        let keys: Vec<_> = sessions.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["alice", "carol"]);
+        let keys: Vec<_> = sessions.scan("b", "z").map(|(key, _)| key).collect();
+        assert_eq!(keys, ["carol"]);
+
+        let keys: Vec<_> = engine.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["alice"]);
+        assert_eq!(engine.scan("", "z").unwrap().count(), 1);
+        assert!(engine.cf("").is_err());
+        assert!(engine.cf("a\u{1}b").is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use std::{fmt, io, path::PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    // A path that must be a directory is something else
+    NotADirectory(PathBuf),
+    // A segment file that can't be decoded
+    CorruptSegment { path: PathBuf, reason: String },
+    // A table manifest that can't be decoded
+    CorruptManifest { path: PathBuf, reason: String },
+    // Stored data doesn't match the checksum written with it
+    ChecksumMismatch,
+    // A key that can't be stored, such as one containing a NUL byte
+    InvalidKey(String),
+    // A typed value that can't be serialized or deserialized
+    Serialization(String),
+    // A write to a store opened read-only
+    ReadOnly,
+    // A file written in a newer format version than this build supports
+    UnsupportedVersion { found: u64, supported: u64 },
+    Io(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // The closest io::ErrorKind, used when converting into an io::Error
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::NotADirectory(_) => io::ErrorKind::NotADirectory,
+            Error::CorruptSegment { .. } | Error::CorruptManifest { .. } | Error::ChecksumMismatch => {
+                io::ErrorKind::InvalidData
+            }
+            Error::InvalidKey(_) => io::ErrorKind::InvalidInput,
+            Error::Serialization(_) => io::ErrorKind::InvalidData,
+            Error::ReadOnly => io::ErrorKind::PermissionDenied,
+            Error::UnsupportedVersion { .. } => io::ErrorKind::Unsupported,
+            Error::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotADirectory(path) => write!(f, "{:?} is not a directory", path),
+            Error::CorruptSegment { path, reason } => write!(f, "Corrupt segment {:?}: {}", path, reason),
+            Error::CorruptManifest { path, reason } => write!(f, "Corrupt manifest {:?}: {}", path, reason),
+            Error::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            Error::InvalidKey(key) => write!(f, "Invalid key {:?}", key),
+            Error::Serialization(reason) => write!(f, "Serialization failed: {}", reason),
+            Error::ReadOnly => write!(f, "Store is opened read-only"),
+            Error::UnsupportedVersion { found, supported } => {
+                write!(f, "Unsupported format version {}, this build supports up to {}", found, supported)
+            }
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// An Error carried inside an io::Error, as made by the conversion below, is taken back out
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(e)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Serialization(e.to_string())
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error_round_trip() {
+        let error = Error::from(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert!(matches!(&error, Error::Io(e) if e.kind() == io::ErrorKind::NotFound));
+
+        let error = io::Error::from(Error::ChecksumMismatch);
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Checksum mismatch");
+        assert!(matches!(Error::from(error), Error::ChecksumMismatch));
+    }
+}
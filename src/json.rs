@@ -0,0 +1,246 @@
+use std::io::{self, BufRead, Write};
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Write one `{"key": ..., "value": <base64>}` object
+pub fn write_entry<W: Write>(writer: &mut W, key: &str, value: &[u8]) -> io::Result<()> {
+    writer.write_all(b"{\"key\": ")?;
+    write_string(writer, key)?;
+    writer.write_all(b", \"value\": \"")?;
+    writer.write_all(base64_encode(value).as_bytes())?;
+    writer.write_all(b"\"}")
+}
+
+fn write_string<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    writer.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            '\n' => writer.write_all(b"\\n")?,
+            '\r' => writer.write_all(b"\\r")?,
+            '\t' => writer.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+    writer.write_all(b"\"")
+}
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (i, chunk) in encoded.chunks(4).enumerate() {
+        let last = i == encoded.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&b| b == b'=').count();
+        if padding > 2 || padding > 0 && !last {
+            return None;
+        }
+        let mut bits = 0u32;
+        for &byte in &chunk[..4 - padding] {
+            let value = BASE64_ALPHABET.iter().position(|&b| b == byte)?;
+            bits = bits << 6 | value as u32;
+        }
+        bits <<= 6 * padding;
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+// Read a JSON array of objects with string fields, calling back with the fields of every object.
+// Values of any other type are rejected.
+pub fn read_objects<R, F>(reader: R, mut f: F) -> crate::Result<()>
+    where R: BufRead, F: FnMut(Vec<(String, String)>) -> crate::Result<()> {
+    let mut parser = Parser { bytes: reader.bytes().peekable() };
+    parser.expect(b'[')?;
+    if parser.peek()? == Some(b']') {
+        parser.next()?;
+    } else {
+        loop {
+            f(parser.object()?)?;
+            match parser.next()? {
+                b',' => continue,
+                b']' => break,
+                _ => return Err(invalid("expected ',' or ']'")),
+            }
+        }
+    }
+    match parser.peek()? {
+        None => Ok(()),
+        Some(_) => Err(invalid("trailing characters")),
+    }
+}
+
+fn invalid(reason: &str) -> crate::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid JSON: {}", reason)).into()
+}
+
+struct Parser<R: BufRead> {
+    bytes: std::iter::Peekable<io::Bytes<R>>,
+}
+
+impl<R: BufRead> Parser<R> {
+    // Next byte that is not whitespace, without consuming it
+    fn peek(&mut self) -> crate::Result<Option<u8>> {
+        loop {
+            match self.bytes.peek() {
+                Some(Ok(b' ' | b'\n' | b'\r' | b'\t')) => {
+                    self.bytes.next();
+                }
+                Some(Ok(byte)) => return Ok(Some(*byte)),
+                Some(Err(_)) => return Err(self.bytes.next().unwrap().unwrap_err().into()),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // Next byte that is not whitespace
+    fn next(&mut self) -> crate::Result<u8> {
+        self.peek()?.ok_or_else(|| invalid("unexpected end of input"))?;
+        Ok(self.bytes.next().unwrap()?)
+    }
+
+    fn next_raw(&mut self) -> crate::Result<u8> {
+        Ok(self.bytes.next().ok_or_else(|| invalid("unexpected end of input"))??)
+    }
+
+    fn expect(&mut self, expected: u8) -> crate::Result<()> {
+        if self.next()? != expected {
+            return Err(invalid(&format!("expected '{}'", expected as char)));
+        }
+        Ok(())
+    }
+
+    fn object(&mut self) -> crate::Result<Vec<(String, String)>> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        if self.peek()? == Some(b'}') {
+            self.next()?;
+            return Ok(fields);
+        }
+        loop {
+            let name = self.string()?;
+            self.expect(b':')?;
+            fields.push((name, self.string()?));
+            match self.next()? {
+                b',' => continue,
+                b'}' => return Ok(fields),
+                _ => return Err(invalid("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> crate::Result<String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.next_raw()? {
+                b'"' => break,
+                b'\\' => match self.next_raw()? {
+                    b'"' => bytes.push(b'"'),
+                    b'\\' => bytes.push(b'\\'),
+                    b'/' => bytes.push(b'/'),
+                    b'b' => bytes.push(0x08),
+                    b'f' => bytes.push(0x0c),
+                    b'n' => bytes.push(b'\n'),
+                    b'r' => bytes.push(b'\r'),
+                    b't' => bytes.push(b'\t'),
+                    b'u' => {
+                        let c = self.escaped_char()?;
+                        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
+                    _ => return Err(invalid("unknown escape sequence")),
+                },
+                byte => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| invalid("string is not UTF-8"))
+    }
+
+    // Character of a \u escape, which takes two escapes outside of the basic multilingual plane
+    fn escaped_char(&mut self) -> crate::Result<char> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if self.next_raw()? != b'\\' || self.next_raw()? != b'u' {
+                return Err(invalid("unpaired surrogate"));
+            }
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(invalid("unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| invalid("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> crate::Result<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = (self.next_raw()? as char).to_digit(16).ok_or_else(|| invalid("invalid unicode escape"))?;
+            code = code << 4 | digit;
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        for (data, encoded) in [(&b""[..], ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"foobar", "Zm9vYmFy")] {
+            assert_eq!(base64_encode(data), encoded);
+            assert_eq!(base64_decode(encoded).unwrap(), data);
+        }
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(base64_decode(&base64_encode(&data)).unwrap(), data);
+        assert_eq!(base64_decode("Zg="), None);
+        assert_eq!(base64_decode("Z==="), None);
+        assert_eq!(base64_decode("Zg==Zg=="), None);
+        assert_eq!(base64_decode("Z!=="), None);
+    }
+
+    #[test]
+    fn test_read_objects() {
+        let input = br#" [ {"key": "a\"b\\c\n\u00e9\ud83d\ude00", "value": "x"}, {} ] "#;
+        let mut objects = Vec::new();
+        read_objects(&input[..], |fields| {
+            objects.push(fields);
+            Ok(())
+        }).unwrap();
+        assert_eq!(objects, vec![
+            vec![("key".to_string(), "a\"b\\c\né😀".to_string()), ("value".to_string(), "x".to_string())],
+            vec![],
+        ]);
+
+        let mut written = Vec::new();
+        write_string(&mut written, "a\"b\\c\n\u{1}é").unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), r#""a\"b\\c\n\u0001é""#);
+
+        for input in [&b""[..], b"[", b"[{]", b"[{\"key\": 1}]", b"[] x", b"[{\"key\": \"\\ud800\"}]"] {
+            assert!(read_objects(input, |_| Ok(())).is_err());
+        }
+    }
+}
@@ -1,275 +1,2426 @@
-use std::collections::BTreeMap;
-use std::io::{self, Write, Read};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::bloom::BloomFilter;
+use crate::naming::FileNaming;
+use crate::Error;
+
+// Default size threshold in bytes at which the active segment is rolled over
+pub const DEFAULT_MAX_SEGMENT_SIZE: usize = 1024 * 1024;
+
+// Extension of segment files unless the table is opened with a naming of its own
+pub const SEGMENT_EXTENSION: &str = "sst";
+
+// Every segment file starts with the magic bytes followed by the format version
+const SEGMENT_MAGIC: [u8; 4] = *b"KSST";
+// Magic of segment files in the block layout
+const BLOCK_SEGMENT_MAGIC: [u8; 4] = *b"KSSB";
+// Version 3 widened value lengths from u32 to u64, version 4 added the codec of every value,
+// version 5 flags merge operands in the codec byte
+const SEGMENT_FORMAT_VERSION: u8 = 5;
+const FIRST_MERGE_VERSION: u8 = 5;
+// Set in the codec byte of values holding merge operands
+const MERGE_FLAG: u8 = 0x80;
+const MIN_SEGMENT_FORMAT_VERSION: u8 = 2;
+const SEGMENT_HEADER_LEN: u64 = 5;
+// Footer offset (u64) and CRC32 (u32) at the very end of a segment file
+const SEGMENT_TRAILER_LEN: u64 = 12;
+
+// Key length value marking the end of the entries and the start of the segment footer
+const FOOTER_MARKER: u32 = u32::MAX;
+// Bytes allocated at once for a key or value read from a segment, longer ones grow as they're read
+const MAX_PREALLOCATED_LEN: u64 = 1024 * 1024;
+
+// Default number of entries between two keys of the sparse index in a segment file
+pub const DEFAULT_INDEX_INTERVAL: usize = 16;
+
+// Size in bytes at which a block of the block layout is closed
+const BLOCK_SIZE: usize = 4096;
+// Number of entries of a block after which a key is stored in full again
+const BLOCK_RESTART_INTERVAL: usize = 16;
+
+// File listing the live segments of a table
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_VERSION: u32 = 1;
+
+// Archives start with the magic bytes followed by the archive version
+const ARCHIVE_MAGIC: [u8; 4] = *b"KARC";
+const ARCHIVE_VERSION: u8 = 1;
+
+// A key with its value, or None for a tombstone
+type Entry = (String, Option<Value>);
+
+// A stored value with its expiry time in milliseconds since the Unix epoch, 0 for never. The
+// bytes are shared, so reads and merges of segments don't copy them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Value {
+    data: Arc<[u8]>,
+    expires_at: u64,
+    // Whether data holds merge operands still to be applied to the value below, oldest first,
+    // each as its u32 length and bytes
+    merge: bool,
+}
+
+impl Value {
+    fn new(data: Vec<u8>, expires_at: u64) -> Self {
+        Value { data: data.into(), expires_at, merge: false }
+    }
+
+    fn operand(operand: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(4 + operand.len());
+        data.extend_from_slice(&(operand.len() as u32).to_le_bytes());
+        data.extend_from_slice(operand);
+        Value { data: data.into(), expires_at: 0, merge: true }
+    }
+
+    fn operands(&self) -> Vec<&[u8]> {
+        let mut operands = Vec::new();
+        let mut rest = &self.data[..];
+        while let Some((len, tail)) = rest.split_first_chunk::<4>() {
+            let (operand, tail) = tail.split_at((u32::from_le_bytes(*len) as usize).min(tail.len()));
+            operands.push(operand);
+            rest = tail;
+        }
+        operands
+    }
+
+    // Operands of both, followed by the newer ones
+    fn followed_by(self, newer: &Value) -> Self {
+        Value { data: [&self.data[..], &newer.data[..]].concat().into(), ..self }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
+
+// Combines the existing value of a key, None if it has none, with the operands merged into it
+// since, oldest first. Tables holding merge operands must always be opened with the function
+// they were merged with.
+#[derive(Clone)]
+pub struct MergeFn(Arc<MergeFnType>);
+
+type MergeFnType = dyn Fn(Option<&[u8]>, &[&[u8]]) -> Vec<u8> + Send + Sync;
+
+impl MergeFn {
+    pub fn new<F>(f: F) -> Self
+        where F: Fn(Option<&[u8]>, &[&[u8]]) -> Vec<u8> + Send + Sync + 'static {
+        MergeFn(Arc::new(f))
+    }
+
+    pub fn apply(&self, existing: Option<&[u8]>, operands: &[&[u8]]) -> Vec<u8> {
+        (self.0)(existing, operands)
+    }
+
+    // Value left by applying merge operands to the value below them. Deleted and expired values
+    // count as none.
+    fn resolve(&self, base: Option<&Value>, operands: &Value, now: u64) -> Value {
+        let base = base.filter(|v| !v.is_expired(now)).map(|v| &v.data[..]);
+        Value::new(self.apply(base, &operands.operands()), 0)
+    }
+}
+
+impl std::fmt::Debug for MergeFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MergeFn")
+    }
+}
+
+// Callbacks observing the work of a table or engine, for debugging read amplification and
+// open handles. Every method does nothing unless overridden.
+pub trait Metrics: Send + Sync {
+    // A lookup consulted the segment with this serial
+    fn on_segment_read(&self, _serial: u64) {}
+    fn on_get(&self, _found: bool) {}
+    fn on_compaction(&self, _stats: &CompactionStats) {}
+    // An entry of this many bytes was appended to the write-ahead log
+    fn on_wal_append(&self, _bytes: usize) {}
+    // The write-ahead log was synced, to the disk or only to the OS depending on the fsync option
+    fn on_wal_sync(&self) {}
+}
+
+impl std::fmt::Debug for dyn Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+// Current time in milliseconds since the Unix epoch, the clock used for expiry times
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// The value bytes unless the entry is a tombstone or has expired. Merge operands found no value
+// below them, without a merge function they read as absent.
+fn live_value(value: Option<Value>, now: u64, merge_fn: Option<&MergeFn>) -> Option<Arc<[u8]>> {
+    match value {
+        Some(value) if value.merge => merge_fn.map(|f| f.resolve(None, &value, now).data),
+        value => value.filter(|v| !v.is_expired(now)).map(|v| v.data),
+    }
+}
+
+#[derive(Clone)]
 struct SSTableSegment {
-    data: BTreeMap<String, Option<Vec<u8>>>,
+    data: SegmentStore,
     size: usize,
+    // Serial of the last operation written to the segment, or the one it was given when a
+    // compaction or bulk load made it. Also the number of its file.
     serial: u64,
+    // Whether the segment has been written to disk and must not change anymore
+    persisted: bool,
+    // Keys of live values and tombstones, used to skip the segment on lookups
+    bloom: Option<BloomFilter>,
+    // Smallest and largest key in the segment, tombstones included
+    min_key: Option<String>,
+    max_key: Option<String>,
+    // Compaction level, 0 for the active segment and segments flushed from it
+    level: u32,
 }
 
 impl SSTableSegment {
     fn new(serial: u64) -> Self {
         SSTableSegment {
-            data: BTreeMap::new(),
+            data: SegmentStore::Memory(BTreeMap::new()),
             size: 0,
             serial,
+            persisted: false,
+            bloom: None,
+            min_key: None,
+            max_key: None,
+            level: 0,
         }
     }
 
-    fn insert(&mut self, key: String, value: Option<Vec<u8>>) {
-        if let Some(Some(old_value)) = self.data.get(&key) {
-            self.size -= old_value.len();
-        } else {
-            self.size += key.len();
+    fn track_bounds(&mut self, key: &str) {
+        if self.min_key.as_deref().is_none_or(|min| key < min) {
+            self.min_key = Some(key.to_owned());
+        }
+        if self.max_key.as_deref().is_none_or(|max| key > max) {
+            self.max_key = Some(key.to_owned());
+        }
+    }
+
+    fn in_bounds(&self, key: &str) -> bool {
+        match (&self.min_key, &self.max_key) {
+            (Some(min), Some(max)) => min.as_str() <= key && key <= max.as_str(),
+            _ => false,
+        }
+    }
+
+    // A filter missing keys would hide them, so a segment file that cannot be read gets none
+    fn enable_bloom(&mut self) {
+        let mut bloom = BloomFilter::new((self.data.len() * 2).max(1024));
+        let errors = ReadErrors::default();
+        for (key, _) in self.data.iter(&errors) {
+            bloom.insert(&key);
+        }
+        self.bloom = errors.check().is_ok().then_some(bloom);
+    }
+
+    // Add a key to the bloom filter, growing it once it is full
+    fn track_key(&mut self, key: &str) {
+        let Some(bloom) = &self.bloom else {
+            return;
+        };
+        if bloom.len() >= bloom.capacity() {
+            self.enable_bloom();
+        }
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(key);
+        }
+    }
+
+    fn may_contain(&self, key: &str) -> bool {
+        self.bloom.as_ref().is_none_or(|b| b.may_contain(key))
+    }
+
+    fn overlaps(&self, min: &str, max: &str) -> bool {
+        match (&self.min_key, &self.max_key) {
+            (Some(own_min), Some(own_max)) => own_min.as_str() <= max && min <= own_max.as_str(),
+            _ => true,
+        }
+    }
+
+    // Whether the segment has a value or tombstone for the key. Read errors count as holding it.
+    fn holds(&self, key: &str) -> bool {
+        self.in_bounds(key) && self.may_contain(key) && !matches!(self.data.get(key), Ok(None))
+    }
+
+    fn insert(&mut self, key: String, value: Option<Value>) {
+        self.track_key(&key);
+        self.track_bounds(&key);
+        let data = self.data.memory_mut();
+        match data.get(&key) {
+            Some(Some(old_value)) => self.size -= old_value.data.len(),
+            Some(None) => {}
+            None => self.size += key.len(),
         }
         if let Some(new_value) = &value {
-            self.size += new_value.len();
+            self.size += new_value.data.len();
         }
-        self.data.insert(key, value);
-        self.serial += 1;
+        data.insert(key, value);
     }
 
     fn delete(&mut self, key: String) {
-        if let Some(Some(old_value)) = self.data.get(&key) {
-            self.size -= old_value.len();
+        self.insert(key, None);
+    }
+}
+
+// Where the entries of a segment live
+#[derive(Clone)]
+enum SegmentStore {
+    // Decoded into memory, the only kind the active segment can be
+    Memory(BTreeMap<String, Option<Value>>),
+    // Left in the segment file and decoded on demand
+    File(FileSegment),
+}
+
+impl SegmentStore {
+    // Some(None) is a tombstone, None means the segment has no entry for the key
+    fn get(&self, key: &str) -> io::Result<Option<Option<Value>>> {
+        match self {
+            SegmentStore::Memory(data) => Ok(data.get(key).cloned()),
+            SegmentStore::File(file) => file.get(key),
+        }
+    }
+
+    // Whether the key has a live value, a tombstone or expired value, or no entry at all (None).
+    // In memory values are not copied.
+    fn contains(&self, key: &str, now: u64) -> io::Result<Option<bool>> {
+        let live = |value: &Option<Value>| value.as_ref().is_some_and(|v| !v.is_expired(now));
+        match self {
+            SegmentStore::Memory(data) => Ok(data.get(key).map(live)),
+            SegmentStore::File(file) => Ok(file.get(key)?.as_ref().map(live)),
+        }
+    }
+
+    // Same as get for every key. File-backed segments are opened only once.
+    fn get_many(&self, keys: &[&str]) -> io::Result<Vec<Option<Option<Value>>>> {
+        match self {
+            SegmentStore::Memory(data) => Ok(keys.iter().map(|key| data.get(*key).cloned()).collect()),
+            SegmentStore::File(file) => file.get_many(keys),
+        }
+    }
+
+    // Entries within the bounds. A read error ends them early and is recorded in errors.
+    fn range(&self, bounds: (Bound<String>, Bound<String>), errors: &ReadErrors) -> Box<dyn Iterator<Item = Entry> + '_> {
+        match self {
+            SegmentStore::Memory(data) => {
+                let range = (bounds.0.as_ref().map(|s| s.as_str()), bounds.1.as_ref().map(|s| s.as_str()));
+                Box::new(data.range::<str, _>(range).map(|(k, v)| (k.clone(), v.clone())))
+            }
+            SegmentStore::File(file) => Box::new(file.range(bounds, errors)),
+        }
+    }
+
+    fn iter(&self, errors: &ReadErrors) -> Box<dyn Iterator<Item = Entry> + '_> {
+        self.range((Bound::Unbounded, Bound::Unbounded), errors)
+    }
+
+    // Keys spread over the segment, found without reading a segment file
+    #[cfg(feature = "parallel")]
+    fn sample_keys(&self) -> Vec<String> {
+        match self {
+            SegmentStore::Memory(data) => data.keys().step_by((data.len() / 64).max(1)).cloned().collect(),
+            SegmentStore::File(file) => file.index.iter().map(|(key, _)| key.clone()).collect(),
         }
-        self.data.insert(key, None);
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            SegmentStore::Memory(data) => data.len(),
+            SegmentStore::File(file) => file.entry_count,
+        }
+    }
 
-        self.serial += 1;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn memory_mut(&mut self) -> &mut BTreeMap<String, Option<Value>> {
+        match self {
+            SegmentStore::Memory(data) => data,
+            SegmentStore::File(_) => panic!("File-backed segments are immutable"),
+        }
     }
 }
 
-pub struct SSTable {
+// A flushed segment whose entries are read from its file, or its mapping, on every access
+#[derive(Clone)]
+struct FileSegment {
     path: PathBuf,
-    segments: Vec<SSTableSegment>,
-    max_segment_size: usize,
+    version: u8,
+    layout: SegmentLayout,
+    entry_count: usize,
+    index: Vec<IndexEntry>,
+    // Mapping of the file, shared with every reader so it outlives the file being compacted away
+    #[cfg(feature = "mmap")]
+    map: Option<MappedFile>,
 }
 
-impl SSTable {
-    pub fn try_new(path: &Path, max_segment_size: usize) -> io::Result<Self> {
-        if !path.exists() {
-            std::fs::create_dir_all(path)?;
+#[cfg(feature = "mmap")]
+#[derive(Clone)]
+struct MappedFile(Arc<memmap2::Mmap>);
+
+#[cfg(feature = "mmap")]
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// Reader over a segment file, or over its mapping once it is mapped
+enum SegmentFileReader {
+    File(BufReader<File>),
+    #[cfg(feature = "mmap")]
+    Mapped(io::Cursor<MappedFile>),
+}
+
+impl Read for SegmentFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SegmentFileReader::File(reader) => reader.read(buf),
+            #[cfg(feature = "mmap")]
+            SegmentFileReader::Mapped(reader) => reader.read(buf),
         }
-        let mut segments = SSTable::read(path).unwrap_or_default();
-        if segments.is_empty() {
-            segments.push(SSTableSegment::new(0));
+    }
+}
+
+impl Seek for SegmentFileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            SegmentFileReader::File(reader) => reader.seek(pos),
+            #[cfg(feature = "mmap")]
+            SegmentFileReader::Mapped(reader) => reader.seek(pos),
         }
-        Ok(SSTable {
-            path: path.to_path_buf(),
-            segments,
-            max_segment_size
-        })
     }
+}
 
-    pub fn insert(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
-        let key = key.to_owned();
-        let last_index = self.segments.len() - 1;
-        
-        self.segments[last_index].insert(key, Some(value.to_vec()));
+// Key of every Nth entry with the file offset of that entry, or of the first entry of every
+// block with the offset of the block
+type IndexEntry = (String, u64);
 
-        if self.segments[last_index].size > self.max_segment_size {
-            self.add_segment()?;
+struct SegmentFooter {
+    serial: u64,
+    level: u32,
+    entry_count: u64,
+    min_key: String,
+    max_key: String,
+    index: Vec<IndexEntry>,
+}
+
+impl FileSegment {
+    // Verify the checksum and read the footer without decoding the entries
+    fn open(path: &Path) -> io::Result<(FileSegment, SegmentFooter)> {
+        let mut file = BufReader::new(File::open(path)?);
+        let len = file.get_ref().metadata()?.len();
+        if len < SEGMENT_HEADER_LEN + SEGMENT_TRAILER_LEN {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Segment file is truncated"));
+        }
+
+        let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        let (version, layout) = check_header(&header)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut payload = (&mut file).take(len - SEGMENT_HEADER_LEN - 4);
+        let mut buffer = [0u8; 8192];
+        loop {
+            let read = payload.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
         }
+        let mut checksum_bytes = [0u8; 4];
+        file.read_exact(&mut checksum_bytes)?;
+        if u32::from_le_bytes(checksum_bytes) != hasher.finalize() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Segment checksum mismatch"));
+        }
+
+        let mut offset_bytes = [0u8; 8];
+        file.seek(SeekFrom::Start(len - SEGMENT_TRAILER_LEN))?;
+        file.read_exact(&mut offset_bytes)?;
+        file.seek(SeekFrom::Start(u64::from_le_bytes(offset_bytes)))?;
+        // The footer marker takes the place of a key length or block length
+        if read_entry(&mut file, version)?.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid segment footer offset"));
+        }
+        let mut footer = read_footer(&mut file)?;
+
+        let segment = FileSegment {
+            path: path.to_path_buf(),
+            version,
+            layout,
+            entry_count: footer.entry_count as usize,
+            index: std::mem::take(&mut footer.index),
+            #[cfg(feature = "mmap")]
+            map: None,
+        };
+        Ok((segment, footer))
+    }
+
+    // Map the file so reads copy from memory instead of going through read calls
+    #[cfg(feature = "mmap")]
+    fn map(&mut self) -> io::Result<()> {
+        let file = File::open(&self.path)?;
+        // Segment files are never written again once renamed into place, and a removed file
+        // stays mapped, so the mapped bytes don't change under the table
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        self.map = Some(MappedFile(Arc::new(map)));
         Ok(())
     }
 
-    pub fn get(&self, key: &str) -> Option<Box<[u8]>> {
-        for segment in self.segments.iter().rev() {
-            if let Some(value) = segment.data.get(key) {
-                return value.as_ref().map(|v| v.clone().into_boxed_slice());
-            }
+    fn open_reader(&self) -> io::Result<SegmentFileReader> {
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.map {
+            return Ok(SegmentFileReader::Mapped(io::Cursor::new(map.clone())));
         }
-        None
+        Ok(SegmentFileReader::File(BufReader::new(File::open(&self.path)?)))
     }
 
-    pub fn delete(&mut self, key: &str) {
-        let key = key.to_owned();
-        let last_segment = self.segments.len() - 1;
-        self.segments[last_segment].delete(key);
+    fn get(&self, key: &str) -> io::Result<Option<Option<Value>>> {
+        self.lookup(&mut self.open_reader()?, key)
     }
 
-    pub fn compact(&mut self) {
-        let mut merged = BTreeMap::new();
-        
-        for segment in &self.segments {
-            for (key, value) in &segment.data {
-                merged.insert(key.clone(), value.clone());
-            }
+    fn lookup<R: Read + Seek>(&self, reader: &mut R, key: &str) -> io::Result<Option<Option<Value>>> {
+        match self.layout {
+            SegmentLayout::Flat => get_from_file(reader, self.version, &self.index, key),
+            SegmentLayout::Block => get_from_block_file(reader, self.version, &self.index, key),
         }
+    }
 
-        let last_serial = self.segments.last().unwrap().serial;
+    fn get_many(&self, keys: &[&str]) -> io::Result<Vec<Option<Option<Value>>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut reader = self.open_reader()?;
+        keys.iter().map(|key| self.lookup(&mut reader, key)).collect()
+    }
 
-        let mut new_segments = vec![SSTableSegment::new(last_serial)];
-        let mut current_segment = 0;
+    fn range(&self, bounds: (Bound<String>, Bound<String>), errors: &ReadErrors) -> FileEntries {
+        let start = match &bounds.0 {
+            Bound::Included(start) | Bound::Excluded(start) => index_offset(&self.index, start),
+            Bound::Unbounded => None,
+        };
+        let reader = self.open_reader().and_then(|mut reader| {
+            reader.seek(SeekFrom::Start(start.unwrap_or(SEGMENT_HEADER_LEN)))?;
+            Ok(reader)
+        });
+        FileEntries {
+            reader: reader.map_err(|e| errors.record(e)).ok(),
+            version: self.version,
+            layout: self.layout,
+            block: Vec::new().into_iter(),
+            bounds,
+            errors: errors.clone(),
+        }
+    }
+}
 
-        for (key, value) in merged {
-            let segment = &mut new_segments[current_segment];
-            let entry_size = key.len() + value.as_ref().map_or(0, |v| v.len());
+// Offset of the last indexed entry with a key not greater than the given one
+fn index_offset(index: &[IndexEntry], key: &str) -> Option<u64> {
+    let position = index.partition_point(|(k, _)| k.as_str() <= key);
+    position.checked_sub(1).map(|i| index[i].1)
+}
 
-            segment.insert(key, value);
+// Look a key up in a segment file by seeking to the closest indexed entry and scanning forward
+fn get_from_file<R: Read + Seek>(file: &mut R, version: u8, index: &[IndexEntry], key: &str) -> io::Result<Option<Option<Value>>> {
+    // Keys before the first indexed one are not in the segment
+    let Some(offset) = index_offset(index, key) else {
+        return Ok(None);
+    };
+    file.seek(SeekFrom::Start(offset))?;
 
-            if segment.size + entry_size > self.max_segment_size {
-                let segment_serial = segment.serial;
-                new_segments.push(SSTableSegment::new(segment_serial));
-                current_segment += 1;
-            }
+    // Entries are sorted, so the scan stops at the first larger key
+    while let Some((k, v)) = read_entry(file, version)? {
+        match k.as_str().cmp(key) {
+            std::cmp::Ordering::Less => continue,
+            std::cmp::Ordering::Equal => return Ok(Some(v)),
+            std::cmp::Ordering::Greater => break,
         }
+    }
+    Ok(None)
+}
 
-        self.segments = new_segments;
+// Look a key up in a block layout segment file by binary searching the one block that can hold it
+fn get_from_block_file<R: Read + Seek>(file: &mut R, version: u8, index: &[IndexEntry], key: &str) -> io::Result<Option<Option<Value>>> {
+    let Some(offset) = index_offset(index, key) else {
+        return Ok(None);
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    match read_block(file)? {
+        Some(block) => search_block(&block, version, key),
+        None => Ok(None),
     }
+}
 
-    fn add_segment(&mut self) -> io::Result<()> {
-        let last_index = self.segments.len() - 1;
-        self.segments.push(SSTableSegment::new(self.segments[last_index].serial));
-        self.write(&self.path)?;
-        Ok(())
+// First read error of the segment files being iterated, shared by their iterators so callers
+// can tell an early end of the entries from the real one
+#[derive(Clone, Default)]
+struct ReadErrors(Arc<Mutex<Option<io::Error>>>);
+
+impl ReadErrors {
+    fn record(&self, error: io::Error) {
+        self.0.lock().unwrap().get_or_insert(error);
     }
 
-    fn read(path: &Path) -> io::Result<Vec<SSTableSegment>> {
-        if !path.is_dir() {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Path is not a directory"));
+    // Take the recorded error, if any
+    fn check(&self) -> io::Result<()> {
+        self.0.lock().unwrap().take().map_or(Ok(()), Err)
+    }
+}
+
+// Entries of a segment file within bounds. A read error ends the iteration and is recorded.
+struct FileEntries {
+    reader: Option<SegmentFileReader>,
+    version: u8,
+    layout: SegmentLayout,
+    // Entries of the current block not returned yet
+    block: std::vec::IntoIter<Entry>,
+    bounds: (Bound<String>, Bound<String>),
+    errors: ReadErrors,
+}
+
+impl FileEntries {
+    fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(None);
+        };
+        match self.layout {
+            SegmentLayout::Flat => read_entry(reader, self.version),
+            SegmentLayout::Block => loop {
+                if let Some(entry) = self.block.next() {
+                    return Ok(Some(entry));
+                }
+                match read_block(reader)? {
+                    Some(block) => self.block = decode_block(&block, self.version)?.into_iter(),
+                    None => return Ok(None),
+                }
+            },
         }
+    }
+}
 
-        let mut segments = Vec::new();
-        let mut serial = 0;
+impl Iterator for FileEntries {
+    type Item = Entry;
 
-        // Helper function to parse segment serial from path
-        fn parse_serial(path: &Path) -> Option<u64> {
-            path.file_stem()
-                .and_then(|s| s.to_str())
-                .and_then(|s| s.parse::<u64>().ok())
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.next_entry().unwrap_or_else(|e| {
+                self.errors.record(e);
+                None
+            });
+            let Some((key, value)) = entry else {
+                self.reader = None;
+                return None;
+            };
+
+            let before_start = match &self.bounds.0 {
+                Bound::Included(start) => key < *start,
+                Bound::Excluded(start) => key <= *start,
+                Bound::Unbounded => false,
+            };
+            if before_start {
+                continue;
+            }
+
+            let past_end = match &self.bounds.1 {
+                Bound::Included(end) => key > *end,
+                Bound::Excluded(end) => key >= *end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                self.reader = None;
+                return None;
+            }
+
+            return Some((key, value));
         }
+    }
+}
 
-        // Collect and validate files
-        let mut entries: Vec<_> = path.read_dir()?
-            .filter_map(|e| e.ok())
-            .map(|e| e.path())
-            .filter(|p| p.is_file())
-            .collect();
+// Outcome of a compaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    // Non-empty segments before and after merging
+    pub segments_before: usize,
+    pub segments_after: usize,
+    // Shrinkage of the segment files on disk
+    pub bytes_reclaimed: u64,
+    pub tombstones_dropped: usize,
+}
 
-        // Validate files before processing
-        for path in &entries {
-            if path.extension().and_then(|s| s.to_str()) != Some("sst") {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, 
-                    format!("Invalid file extension: {:?}", path)));
+// Layout of a segment as reported by SSTable::segments_meta
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentMeta {
+    pub serial: u64,
+    // Entries in the segment, tombstones included
+    pub key_count: usize,
+    // Key and value bytes of a segment held in memory, tombstone keys included, the file size of
+    // one left in its file
+    pub size_bytes: usize,
+    // Smallest and largest key, None for an empty segment
+    pub min_key: Option<String>,
+    pub max_key: Option<String>,
+    pub level: u32,
+    // Whether it is the segment writes go to, which isn't written to a file yet
+    pub is_active: bool,
+}
+
+// How compact merges segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionStrategy {
+    // Merge every segment into one sorted run
+    Full,
+    // Merge flushed level 0 segments into level 1 once there are enough of them, and push
+    // segments of a level into the next one while the level is over its size budget.
+    // Segments within a level other than 0 never overlap.
+    Leveled {
+        // Number of flushed level 0 segments that triggers a merge into level 1
+        level0_segments: usize,
+        // Size budget of level 1 in bytes
+        base_level_size: usize,
+        // Growth factor of the size budget from one level to the next
+        level_size_multiplier: usize,
+    },
+}
+
+// How values are compressed in written segment files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => 1,
+        }
+    }
+}
+
+// How entries are laid out in written segment files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SegmentLayout {
+    // One entry after another with full keys
+    #[default]
+    Flat,
+    // Entries grouped into blocks with every key stored as the length of the prefix it shares with
+    // the previous key plus the rest. Restart points holding full keys let a block be binary searched.
+    Block,
+}
+
+// How segment files are written
+#[derive(Debug, Clone, Copy)]
+struct SegmentWriteOptions {
+    // Number of entries between two keys of the sparse index, the block layout indexes every block
+    index_interval: usize,
+    compression: Option<Codec>,
+    layout: SegmentLayout,
+}
+
+impl Default for SegmentWriteOptions {
+    fn default() -> Self {
+        SegmentWriteOptions {
+            index_interval: DEFAULT_INDEX_INTERVAL,
+            compression: None,
+            layout: SegmentLayout::Flat,
+        }
+    }
+}
+
+pub struct SSTable {
+    path: PathBuf,
+    // Shared with snapshots, a segment held by one is copied before being changed
+    segments: Vec<Arc<SSTableSegment>>,
+    max_segment_size: usize,
+    bloom: bool,
+    write_options: SegmentWriteOptions,
+    compaction: CompactionStrategy,
+    // Key ranges merged on separate threads by a compaction
+    #[cfg(feature = "parallel")]
+    compaction_shards: usize,
+    // Highest serial written to disk, recorded in the manifest
+    persisted_serial: u64,
+    merge_fn: Option<MergeFn>,
+    // Position of the newest segment holding each key, tombstones included, if enabled
+    key_index: Option<HashMap<String, usize>>,
+    metrics: Option<Arc<dyn Metrics>>,
+    // Names of the segment files
+    naming: FileNaming,
+}
+
+// Segment serials of a table as of its last write. Segment files it doesn't list, such as
+// leftovers of an interrupted compaction, are ignored.
+struct Manifest {
+    serial: u64,
+    segments: Vec<u64>,
+}
+
+impl SSTable {
+    pub fn try_new(path: &Path, max_segment_size: usize) -> crate::Result<Self> {
+        SSTable::open(path, max_segment_size, false, FileNaming::unpadded(SEGMENT_EXTENSION))
+    }
+
+    // Like try_new, with segment files named by the naming instead of {serial}.sst. A table must
+    // always be opened with the naming it was written with, files named otherwise are stray.
+    pub fn try_new_with_naming(path: &Path, max_segment_size: usize, naming: FileNaming) -> crate::Result<Self> {
+        SSTable::open(path, max_segment_size, false, naming)
+    }
+
+    // Open a table whose flushed segments stay on disk instead of being decoded into memory
+    pub fn try_new_lazy(path: &Path, max_segment_size: usize) -> crate::Result<Self> {
+        SSTable::open(path, max_segment_size, true, FileNaming::unpadded(SEGMENT_EXTENSION))
+    }
+
+    // Like try_new_lazy, with the segment files memory-mapped. Segments written later, by flushes
+    // or compaction, are held in memory.
+    #[cfg(feature = "mmap")]
+    pub fn try_new_mapped(path: &Path, max_segment_size: usize) -> crate::Result<Self> {
+        let mut table = SSTable::open(path, max_segment_size, true, FileNaming::unpadded(SEGMENT_EXTENSION))?;
+        for segment in &mut table.segments {
+            if let SegmentStore::File(file) = &mut Arc::make_mut(segment).data {
+                file.map()?;
             }
-            if parse_serial(path).is_none() {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, 
-                    format!("Invalid segment file name: {:?}", path)));
+        }
+        Ok(table)
+    }
+
+    fn open(path: &Path, max_segment_size: usize, lazy: bool, naming: FileNaming) -> crate::Result<Self> {
+        if !path.exists() {
+            std::fs::create_dir_all(path)?;
+        }
+        let mut segments: Vec<_> = SSTable::read_segments(path, lazy, &naming)?
+            .into_iter()
+            .map(Arc::new)
+            .collect();
+        // Serials keep counting from the manifest even if compaction left no segment behind
+        let manifest_serial = SSTable::read_manifest(path)?.map_or(0, |m| m.serial);
+        let persisted_serial = segments.iter().map(|s| s.serial).max().unwrap_or(0).max(manifest_serial);
+        if segments.is_empty() {
+            segments.push(Arc::new(SSTableSegment::new(persisted_serial)));
+        }
+        Ok(SSTable {
+            path: path.to_path_buf(),
+            segments,
+            max_segment_size,
+            bloom: false,
+            write_options: SegmentWriteOptions::default(),
+            compaction: CompactionStrategy::Full,
+            #[cfg(feature = "parallel")]
+            compaction_shards: 1,
+            persisted_serial,
+            merge_fn: None,
+            key_index: None,
+            metrics: None,
+            naming,
+        })
+    }
+
+    // Keep a bloom filter per segment so lookups can skip segments without the key
+    pub fn with_bloom(mut self, enabled: bool) -> Self {
+        self.bloom = enabled;
+        for segment in &mut self.segments {
+            let segment = Arc::make_mut(segment);
+            if enabled {
+                segment.enable_bloom();
+            } else {
+                segment.bloom = None;
             }
         }
+        self
+    }
+
+    // Keep the newest segment of every key in memory, so a lookup goes straight to it and a
+    // missing key reads no segment at all. Enabling it reads the keys of all segments, if a
+    // segment file cannot be read the index stays off rather than miss its keys.
+    pub fn with_key_index(mut self, enabled: bool) -> Self {
+        self.key_index = enabled.then(HashMap::new);
+        if self.rebuild_key_index().is_err() {
+            self.key_index = None;
+        }
+        self
+    }
 
-        // Sort by serial number
-        entries.sort_by_key(|p| parse_serial(p).unwrap());
+    fn rebuild_key_index(&mut self) -> io::Result<()> {
+        if let Some(index) = &mut self.key_index {
+            index.clear();
+            let errors = ReadErrors::default();
+            for (i, segment) in self.segments.iter().enumerate() {
+                for (key, _) in segment.data.iter(&errors) {
+                    index.insert(key, i);
+                }
+            }
+            errors.check()?;
+        }
+        Ok(())
+    }
 
-        // Process files in order
-        for path in entries {
-            let mut file = std::fs::File::open(&path)?;
-            let file_serial = parse_serial(&path).unwrap();
-            let segment = SSTable::read_segment(&mut file, serial)?;
-            
-            if file_serial != segment.serial {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid segment serial number"));
+    fn index_key(&mut self, key: &str, segment: usize) {
+        if let Some(index) = &mut self.key_index {
+            match index.get_mut(key) {
+                Some(newest) => *newest = segment,
+                None => {
+                    index.insert(key.to_owned(), segment);
+                }
             }
+        }
+    }
+
+    // Index every Nth key of written segment files. Smaller intervals make file-backed lookups
+    // scan fewer entries at the cost of a larger footer.
+    pub fn with_index_interval(mut self, interval: usize) -> Self {
+        self.write_options.index_interval = interval.max(1);
+        self
+    }
+
+    pub fn with_compaction(mut self, strategy: CompactionStrategy) -> Self {
+        self.compaction = strategy;
+        self
+    }
+
+    // Split the keys merged by a full compaction into this many ranges, merged in parallel on the
+    // rayon thread pool. The result is the same as merging them on one thread.
+    #[cfg(feature = "parallel")]
+    pub fn with_compaction_shards(mut self, shards: usize) -> Self {
+        self.compaction_shards = shards.max(1);
+        self
+    }
+
+    // Compress values of segment files written from now on. Reads handle any codec.
+    pub fn with_compression(mut self, compression: Option<Codec>) -> Self {
+        self.write_options.compression = compression;
+        self
+    }
+
+    // Lay segment files written from now on out this way. Reads handle any layout.
+    pub fn with_layout(mut self, layout: SegmentLayout) -> Self {
+        self.write_options.layout = layout;
+        self
+    }
+
+    // Function combining merge operands with the value below them, needed to merge
+    pub fn with_merge_fn(mut self, merge_fn: MergeFn) -> Self {
+        self.merge_fn = Some(merge_fn);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn merge_fn(&self) -> Option<&MergeFn> {
+        self.merge_fn.as_ref()
+    }
+
+    fn new_segment(&self, serial: u64) -> SSTableSegment {
+        let mut segment = SSTableSegment::new(serial);
+        if self.bloom {
+            segment.enable_bloom();
+        }
+        segment
+    }
+
+    pub fn insert(&mut self, key: &str, value: &[u8]) -> crate::Result<()> {
+        self.insert_with_expiry(key, value, 0)
+    }
+
+    // Insert a value that reads as absent once now_millis reaches expires_at, 0 for never
+    pub fn insert_with_expiry(&mut self, key: &str, value: &[u8], expires_at: u64) -> crate::Result<()> {
+        let last_index = self.active_segment_index();
+        self.index_key(key, last_index);
+        let key = key.to_owned();
+        let serial = self.next_serial();
+
+        let segment = Arc::make_mut(&mut self.segments[last_index]);
+        segment.insert(key, Some(Value::new(value.to_vec(), expires_at)));
+        segment.serial = serial;
+
+        if self.segments[last_index].size > self.max_segment_size {
+            self.add_segment()?;
+        }
+        Ok(())
+    }
+
+    // Record an operand to combine with the value of the key through the merge function. It is
+    // applied right away to a value in the active segment, otherwise kept until the key is read
+    // or compacted, so no older value needs to be read now.
+    pub fn merge(&mut self, key: &str, operand: &[u8]) -> crate::Result<()> {
+        let Some(merge_fn) = self.merge_fn.clone() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No merge function set").into());
+        };
+        let last_index = self.active_segment_index();
+        self.index_key(key, last_index);
+        let serial = self.next_serial();
+        let segment = Arc::make_mut(&mut self.segments[last_index]);
+        let operand = Value::operand(operand);
+        let value = match segment.data.get(key)? {
+            Some(Some(existing)) if existing.merge => existing.followed_by(&operand),
+            Some(existing) => merge_fn.resolve(existing.as_ref(), &operand, now_millis()),
+            None => operand,
+        };
+        segment.insert(key.to_owned(), Some(value));
+        segment.serial = serial;
+
+        if self.segments[last_index].size > self.max_segment_size {
+            self.add_segment()?;
+        }
+        Ok(())
+    }
+
+    // Errors reading file-backed segments are reported as a missing key, use try_get to see them
+    pub fn get(&self, key: &str) -> Option<Box<[u8]>> {
+        self.try_get(key).ok().flatten()
+    }
+
+    pub fn try_get(&self, key: &str) -> crate::Result<Option<Box<[u8]>>> {
+        Ok(self.get_shared(key)?.map(|value| Box::from(&*value)))
+    }
+
+    // Like try_get, but a value of a segment held in memory is shared instead of copied
+    pub fn get_shared(&self, key: &str) -> crate::Result<Option<Arc<[u8]>>> {
+        let segments = self.segments_up_to_newest(key).unwrap_or_default();
+        let value = get_from_segments(segments, key, self.merge_fn.as_ref(), self.metrics.as_deref())?;
+        if let Some(metrics) = &self.metrics {
+            metrics.on_get(value.is_some());
+        }
+        Ok(value)
+    }
+
+    // Whether the newest entry of the key is this value, stored without an expiry time. Merge
+    // operands and values that expire never count, so writes replacing them aren't skipped.
+    pub fn holds_value(&self, key: &str, value: &[u8]) -> crate::Result<bool> {
+        let segments = self.segments_up_to_newest(key).unwrap_or_default();
+        for segment in segments.iter().rev() {
+            if !segment.in_bounds(key) || !segment.may_contain(key) {
+                continue;
+            }
+            if let Some(entry) = segment.data.get(key)? {
+                return Ok(entry.is_some_and(|entry| !entry.merge && entry.expires_at == 0 && *entry.data == *value));
+            }
+        }
+        Ok(false)
+    }
+
+    // Segments that may hold the key, up to the newest one that does according to the key
+    // index. None if the index knows the key is nowhere.
+    fn segments_up_to_newest(&self, key: &str) -> Option<&[Arc<SSTableSegment>]> {
+        match &self.key_index {
+            Some(index) => index.get(key).map(|&newest| &self.segments[..=newest]),
+            None => Some(&self.segments),
+        }
+    }
+
+    // Whether the newest entry for the key is a live value
+    pub fn contains_key(&self, key: &str) -> crate::Result<bool> {
+        let now = now_millis();
+        for segment in self.segments_up_to_newest(key).unwrap_or_default().iter().rev() {
+            if !segment.in_bounds(key) || !segment.may_contain(key) {
+                continue;
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.on_segment_read(segment.serial);
+            }
+            if let Some(live) = segment.data.contains(key, now)? {
+                return Ok(live);
+            }
+        }
+        Ok(false)
+    }
+
+    // Look several keys up in a single pass over the segments, newest first. Results follow
+    // the order of the keys.
+    pub fn get_many(&self, keys: &[&str]) -> crate::Result<Vec<Option<Box<[u8]>>>> {
+        let now = now_millis();
+        let mut results = vec![None; keys.len()];
+        // Positions of the keys not resolved by a newer segment yet, sorted by key
+        let mut pending: Vec<usize> = (0..keys.len()).collect();
+        pending.sort_by_key(|&i| keys[i]);
+
+        for segment in self.segments.iter().rev() {
+            if pending.is_empty() {
+                break;
+            }
+            let candidates: Vec<usize> = pending.iter()
+                .copied()
+                .filter(|&i| segment.in_bounds(keys[i]) && segment.may_contain(keys[i]))
+                .collect();
+            let lookups: Vec<&str> = candidates.iter().map(|&i| keys[i]).collect();
+
+            let mut resolved = Vec::new();
+            for (i, entry) in candidates.into_iter().zip(segment.data.get_many(&lookups)?) {
+                match entry {
+                    // Merge operands need the older segments too
+                    Some(Some(value)) if value.merge => {
+                        results[i] = self.try_get(keys[i])?;
+                        resolved.push(i);
+                    }
+                    Some(value) => {
+                        results[i] = live_value(value, now, None).map(|value| Box::from(&*value));
+                        resolved.push(i);
+                    }
+                    None => {}
+                }
+            }
+            pending.retain(|i| !resolved.contains(i));
+        }
+
+        Ok(results)
+    }
+
+    // Live entries of all segments in ascending key order. A segment file that cannot be read
+    // ends them early, try_iter reports the error instead.
+    pub fn iter(&self) -> impl Iterator<Item = (String, Box<[u8]>)> + '_ {
+        self.live_range(..)
+    }
+
+    // Live entries in ascending key order like iter, ending with the error if a segment file
+    // cannot be read
+    pub fn try_iter(&self) -> impl Iterator<Item = crate::Result<(String, Box<[u8]>)>> + '_ {
+        let errors = ReadErrors::default();
+        self.merge_iter_with(.., &errors)
+            .filter_map(|(key, value)| Some(Ok((key, Box::from(&*value?)))))
+            .chain(std::iter::from_fn(move || errors.check().err().map(Error::from)).map(Err))
+    }
+
+    // Live entries with start <= key < end in ascending key order
+    pub fn scan(&self, start: &str, end: &str) -> impl Iterator<Item = (String, Box<[u8]>)> + '_ {
+        let end = end.max(start);
+        self.live_range((Bound::Included(start.to_owned()), Bound::Excluded(end.to_owned())))
+    }
+
+    // Live entries whose key starts with the prefix in ascending key order
+    pub fn scan_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (String, Box<[u8]>)> + 'a {
+        self.live_range((Bound::Included(prefix.to_owned()), Bound::Unbounded))
+            .take_while(move |(key, _)| key.starts_with(prefix))
+    }
+
+    // Smallest key with a live value
+    pub fn first_key(&self) -> Option<String> {
+        self.iter().next().map(|(key, _)| key)
+    }
+
+    // Largest key with a live value. The largest key of any segment usually is one, otherwise
+    // all keys are merged.
+    pub fn last_key(&self) -> Option<String> {
+        let max_key = self.segments.iter().filter_map(|s| s.max_key.as_deref()).max()?;
+        if self.contains_key(max_key).unwrap_or(false) {
+            return Some(max_key.to_owned());
+        }
+        self.iter().last().map(|(key, _)| key)
+    }
+
+    // Newest entry per key within the bounds across all segments in ascending key order, None
+    // for a tombstone. Merge operands come combined with the values below them and expired values
+    // read as tombstones.
+    // A segment file that cannot be read ends its entries early, use try_iter to see the error.
+    pub fn merge_iter(&self, bounds: impl RangeBounds<String>) -> impl Iterator<Item = (String, Option<Arc<[u8]>>)> + '_ {
+        self.merge_iter_with(bounds, &ReadErrors::default())
+    }
+
+    fn merge_iter_with(&self, bounds: impl RangeBounds<String>, errors: &ReadErrors)
+        -> impl Iterator<Item = (String, Option<Arc<[u8]>>)> + '_ {
+        let bounds = (bounds.start_bound().cloned(), bounds.end_bound().cloned());
+        let now = now_millis();
+        let merge_fn = self.merge_fn.clone();
+        merge_range(&self.segments, bounds, self.merge_fn.as_ref(), errors)
+            .map(move |(key, value)| (key, live_value(value, now, merge_fn.as_ref())))
+    }
+
+    // Entries of merge_iter with a live value
+    fn live_range(&self, bounds: impl RangeBounds<String>) -> impl Iterator<Item = (String, Box<[u8]>)> + '_ {
+        self.merge_iter(bounds).filter_map(|(key, value)| Some((key, Box::from(&*value?))))
+    }
+
+    // Write a tombstone for the key. Tombstones take up the key bytes, so deletes fill the active
+    // segment and roll it over like inserts do.
+    pub fn delete(&mut self, key: &str) -> crate::Result<()> {
+        let last_segment = self.active_segment_index();
+        self.index_key(key, last_segment);
+        let key = key.to_owned();
+        let serial = self.next_serial();
+        let segment = Arc::make_mut(&mut self.segments[last_segment]);
+        segment.delete(key);
+        segment.serial = serial;
+
+        if self.segments[last_segment].size > self.max_segment_size {
+            self.add_segment()?;
+        }
+        Ok(())
+    }
+
+    // Write the active segment to disk. Further mutations go to a new segment.
+    pub fn flush(&mut self) -> crate::Result<()> {
+        let active = self.segments.last_mut().unwrap();
+        if active.persisted || active.data.is_empty() {
+            return Ok(());
+        }
+        SSTable::write_segment_file(&self.path, active, &self.write_options, &self.naming)?;
+        Arc::make_mut(active).persisted = true;
+        self.write_manifest()?;
+        Ok(())
+    }
+
+    fn active_segment_index(&mut self) -> usize {
+        let last = self.segments.last().unwrap();
+        if last.persisted {
+            self.segments.push(Arc::new(self.new_segment(last.serial)));
+        }
+        self.segments.len() - 1
+    }
+
+    // Merge segments according to the compaction strategy, keeping tombstones with the full one,
+    // and rewrite the segment files
+    pub fn compact(&mut self) -> crate::Result<CompactionStats> {
+        self.compact_with(|table| match table.compaction {
+            CompactionStrategy::Full => table.merge_segments(0..table.segments.len(), false),
+            CompactionStrategy::Leveled { level0_segments, base_level_size, level_size_multiplier } => {
+                table.compact_leveled(level0_segments, base_level_size, level_size_multiplier)
+            }
+        })
+    }
+
+    // Merge all segments and drop tombstones, which have nothing left to shadow
+    pub fn compact_dropping_tombstones(&mut self) -> crate::Result<CompactionStats> {
+        self.compact_with(|table| table.merge_segments(0..table.segments.len(), true))
+    }
+
+    // Add entries in ascending key order as new segments written straight to files, bypassing
+    // the active segment. They are newer than everything already in the table. A key that is not
+    // greater than the one before it fails the load, keeping the segments written so far.
+    pub fn bulk_load(&mut self, sorted: impl IntoIterator<Item = (String, Vec<u8>)>) -> crate::Result<()> {
+        // Written first so the loaded segments come after it in serial order
+        self.flush()?;
+        let mut segment = self.new_segment(self.max_serial() + 1);
+        let mut previous_key: Option<String> = None;
+        for (key, value) in sorted {
+            if previous_key.as_ref().is_some_and(|previous| *previous >= key) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("Bulk load key {:?} is not in ascending order", key)).into());
+            }
+            previous_key = Some(key.clone());
+            segment.insert(key, Some(Value::new(value, 0)));
+            if segment.size > self.max_segment_size {
+                let serial = segment.serial;
+                self.push_persisted(std::mem::replace(&mut segment, self.new_segment(serial + 1)))?;
+            }
+        }
+        if !segment.data.is_empty() {
+            self.push_persisted(segment)?;
+        }
+        Ok(())
+    }
+
+    fn push_persisted(&mut self, mut segment: SSTableSegment) -> crate::Result<()> {
+        SSTable::write_segment_file(&self.path, &segment, &self.write_options, &self.naming)?;
+        segment.persisted = true;
+        for (key, _) in segment.data.iter(&ReadErrors::default()) {
+            self.index_key(&key, self.segments.len());
+        }
+        self.segments.push(Arc::new(segment));
+        self.write_manifest()?;
+        Ok(())
+    }
+
+    fn compact_with(&mut self, merge: impl FnOnce(&mut Self) -> io::Result<()>) -> crate::Result<CompactionStats> {
+        // Merged segments get serials above every existing one, so the active segment is
+        // written first to keep its serial from running into theirs
+        self.flush()?;
+        let segments_before = self.segment_count();
+        let tombstones_before = self.tombstone_count();
+        let bytes_before = self.file_size()?;
+
+        // A segment that could not be read in full must keep its file, so nothing is written or
+        // removed unless every segment was merged
+        let segments = self.segments.clone();
+        if let Err(e) = merge(self).and_then(|()| self.rebuild_key_index()) {
+            self.segments = segments;
+            // The index may have been cleared, it stays off if it cannot be rebuilt either
+            if self.rebuild_key_index().is_err() {
+                self.key_index = None;
+            }
+            return Err(e.into());
+        }
+
+        for segment in &mut self.segments {
+            if !segment.persisted && !segment.data.is_empty() {
+                SSTable::write_segment_file(&self.path, segment, &self.write_options, &self.naming)?;
+                Arc::make_mut(segment).persisted = true;
+            }
+        }
+        self.write_manifest()?;
+        self.remove_obsolete_files()?;
+
+        let stats = CompactionStats {
+            segments_before,
+            segments_after: self.segment_count(),
+            bytes_reclaimed: bytes_before.saturating_sub(self.file_size()?),
+            tombstones_dropped: tombstones_before.saturating_sub(self.tombstone_count()),
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.on_compaction(&stats);
+        }
+        Ok(stats)
+    }
+
+    // Delete segment files no segment of the table was read from or written to
+    fn remove_obsolete_files(&self) -> io::Result<()> {
+        let live: Vec<String> = self.segments.iter()
+            .filter(|s| s.persisted)
+            .map(|s| self.naming.file_name(s.serial))
+            .collect();
+        let extension = format!(".{}", self.naming.extension());
+        for entry in self.path.read_dir()? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+            let temporary = name.strip_suffix(".tmp").is_some_and(|name| name.ends_with(&extension));
+            if temporary || name.ends_with(&extension) && !live.iter().any(|l| l == name) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Segments holding any entry, the active one included
+    pub fn segment_count(&self) -> usize {
+        self.segments.iter().filter(|s| !s.data.is_empty()).count()
+    }
+
+    // Entries of all segments, counting a key once for every segment holding it
+    pub fn entry_count(&self) -> usize {
+        self.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    // Tombstones of all segments, found by reading every entry
+    pub fn tombstone_count(&self) -> usize {
+        self.segments.iter()
+            .map(|s| s.data.iter(&ReadErrors::default()).filter(|(_, value)| value.is_none()).count())
+            .sum()
+    }
+
+    // Every segment from oldest to newest, the active one last if there is one
+    pub fn segments_meta(&self) -> Vec<SegmentMeta> {
+        self.segments.iter()
+            .map(|s| SegmentMeta {
+                serial: s.serial,
+                key_count: s.data.len(),
+                size_bytes: s.size,
+                min_key: s.min_key.clone(),
+                max_key: s.max_key.clone(),
+                level: s.level,
+                is_active: !s.persisted,
+            })
+            .collect()
+    }
+
+    // Key and value bytes held by the active segment, not yet written to a file. Tombstones
+    // count with their keys.
+    pub fn active_segment_size(&self) -> usize {
+        self.segments.last().filter(|s| !s.persisted).map_or(0, |s| s.size)
+    }
+
+    // Size of the segment files
+    pub fn file_size(&self) -> io::Result<u64> {
+        let mut size = 0;
+        for entry in self.path.read_dir()? {
+            let entry = entry?;
+            if self.naming.has_extension(&entry.path()) {
+                size += entry.metadata()?.len();
+            }
+        }
+        Ok(size)
+    }
+
+    // Serial above which merged segments are numbered
+    fn max_serial(&self) -> u64 {
+        self.segments.iter().map(|s| s.serial).max().unwrap_or(0)
+    }
+
+    // Replace a run of consecutive segments with their merged entries. Tombstones are dropped
+    // on request unless a segment older than the run still holds the key.
+    fn merge_segments(&mut self, range: Range<usize>, drop_tombstones: bool) -> io::Result<()> {
+        let (older, rest) = self.segments.split_at(range.start);
+        let compacted = &rest[..range.len()];
+        let now = now_millis();
+        let merge_fn = self.merge_fn.as_ref();
+        let unbounded = (Bound::Unbounded, Bound::Unbounded);
+
+        #[cfg(not(feature = "parallel"))]
+        let merged = compacted_entries(older, compacted, unbounded, drop_tombstones, merge_fn, now)?;
+        #[cfg(feature = "parallel")]
+        let merged = if self.compaction_shards > 1 {
+            use rayon::prelude::*;
+            let shards: Vec<Vec<Entry>> = shard_bounds(compacted, self.compaction_shards)
+                .into_par_iter()
+                .map(|bounds| compacted_entries(older, compacted, bounds, drop_tombstones, merge_fn, now))
+                .collect::<io::Result<_>>()?;
+            shards.concat()
+        } else {
+            compacted_entries(older, compacted, unbounded, drop_tombstones, merge_fn, now)?
+        };
+
+        let new_segments = self.build_segments(merged, self.max_serial(), 0);
+        self.segments.splice(range, new_segments.into_iter().map(Arc::new));
+        Ok(())
+    }
+
+    // Segments are kept ordered from the deepest level to level 0 so that newer data still
+    // comes last. The active segment is never compacted.
+    fn compact_leveled(&mut self, level0_segments: usize, base_level_size: usize, level_size_multiplier: usize) -> io::Result<()> {
+        let active = self.segments.len() - usize::from(!self.segments.last().unwrap().persisted);
+        let level0: Vec<usize> = (0..active).filter(|&i| self.segments[i].level == 0).collect();
+        if !level0.is_empty() && level0.len() >= level0_segments {
+            self.merge_into_level(level0, 1)?;
+        }
+
+        let mut level = 1;
+        let mut budget = base_level_size;
+        loop {
+            let members: Vec<usize> = (0..self.segments.len())
+                .filter(|&i| self.segments[i].level == level)
+                .collect();
+            if members.is_empty() {
+                break;
+            }
+            let size: usize = members.iter().map(|&i| self.segments[i].size).sum();
+            if size > budget {
+                self.merge_into_level(vec![members[0]], level + 1)?;
+                continue;
+            }
+            level += 1;
+            budget = budget.saturating_mul(level_size_multiplier);
+        }
+
+        // Further mutations must not land in a compacted segment
+        if self.segments.last().is_none_or(|s| s.level != 0) {
+            self.segments.push(Arc::new(self.new_segment(self.max_serial())));
+        }
+        Ok(())
+    }
+
+    // Merge the selected segments with the segments of the target level they overlap
+    fn merge_into_level(&mut self, mut selected: Vec<usize>, level: u32) -> io::Result<()> {
+        let min = selected.iter().filter_map(|&i| self.segments[i].min_key.clone()).min();
+        let max = selected.iter().filter_map(|&i| self.segments[i].max_key.clone()).max();
+        if let (Some(min), Some(max)) = (min, max) {
+            selected.extend((0..self.segments.len())
+                .filter(|&i| self.segments[i].level == level && self.segments[i].overlaps(&min, &max)));
+        }
+
+        let (compacted, remaining): (Vec<_>, Vec<_>) = std::mem::take(&mut self.segments)
+            .into_iter()
+            .enumerate()
+            .partition(|(i, _)| selected.contains(i));
+        let compacted: Vec<_> = compacted.into_iter().map(|(_, s)| s).collect();
+        let mut remaining: Vec<_> = remaining.into_iter().map(|(_, s)| s).collect();
+
+        // Only deeper levels hold data older than the merged segments. Expired values are
+        // handled like tombstones.
+        let now = now_millis();
+        let errors = ReadErrors::default();
+        let merged: Vec<Entry> = merge_all(&compacted, self.merge_fn.as_ref(), &errors)
+            .map(|(key, value)| (key, value.filter(|v| !v.is_expired(now))))
+            .map(|(key, value)| match (value, &self.merge_fn) {
+                (Some(value), Some(merge_fn)) if value.merge
+                    && !remaining.iter().any(|s| s.level > level && s.holds(&key)) => {
+                    (key, Some(merge_fn.resolve(None, &value, now)))
+                }
+                (value, _) => (key, value),
+            })
+            .filter(|(key, value)| {
+                value.is_some() || remaining.iter().any(|s| s.level > level && s.holds(key))
+            })
+            .collect();
+        errors.check()?;
+
+        let serial = compacted.iter().chain(&remaining).map(|s| s.serial).max().unwrap_or(0);
+        let new_segments: Vec<_> = self.build_segments(merged, serial, level)
+            .into_iter()
+            .filter(|s| !s.data.is_empty())
+            .map(Arc::new)
+            .collect();
+
+        let position = remaining.iter().position(|s| s.level < level).unwrap_or(remaining.len());
+        remaining.splice(position..position, new_segments);
+        self.segments = remaining;
+        Ok(())
+    }
+
+    // Split sorted entries into new segments of at most max_segment_size, numbered from the
+    // serial after the given one
+    fn build_segments(&self, entries: Vec<Entry>, serial: u64, level: u32) -> Vec<SSTableSegment> {
+        let mut new_segments = vec![self.new_segment(serial + 1)];
+        let mut current_segment = 0;
+
+        for (key, value) in entries {
+            let segment = &mut new_segments[current_segment];
+            let entry_size = key.len() + value.as_ref().map_or(0, |v| v.data.len());
+
+            segment.insert(key, value);
+
+            if segment.size + entry_size > self.max_segment_size {
+                let segment_serial = segment.serial;
+                new_segments.push(self.new_segment(segment_serial + 1));
+                current_segment += 1;
+            }
+        }
+
+        for segment in &mut new_segments {
+            segment.level = level;
+        }
+        new_segments
+    }
+
+    fn add_segment(&mut self) -> crate::Result<()> {
+        let last_index = self.segments.len() - 1;
+        self.segments.push(Arc::new(self.new_segment(self.segments[last_index].serial)));
+        self.write(&self.path)?;
+        for segment in &mut self.segments[..=last_index] {
+            if !segment.persisted {
+                Arc::make_mut(segment).persisted = true;
+            }
+        }
+        self.write_manifest()?;
+        Ok(())
+    }
+
+    // Replace the manifest with one listing the written segments, atomically through a rename
+    fn write_manifest(&mut self) -> io::Result<()> {
+        let live: Vec<_> = self.segments.iter().filter(|s| s.persisted).map(|s| s.serial).collect();
+        self.persisted_serial = live.iter().copied().fold(self.persisted_serial, u64::max);
+        let live: Vec<String> = live.iter().map(u64::to_string).collect();
+        let contents = format!("version {}\nserial {}\nsegments {}\n",
+            MANIFEST_VERSION, self.persisted_serial, live.join(" "));
+
+        let temp_path = self.path.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = File::create(&temp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, self.path.join(MANIFEST_FILE))
+    }
+
+    fn read_manifest(path: &Path) -> crate::Result<Option<Manifest>> {
+        let manifest_path = path.join(MANIFEST_FILE);
+        let contents = match std::fs::read_to_string(&manifest_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let corrupt = |reason: &str| Error::CorruptManifest { path: manifest_path.clone(), reason: reason.to_owned() };
+
+        let mut lines = contents.lines();
+        let mut field = |name: &str| -> crate::Result<Vec<u64>> {
+            lines.next()
+                .and_then(|line| line.strip_prefix(name))
+                .and_then(|values| values.split_whitespace().map(|v| v.parse().ok()).collect())
+                .ok_or_else(|| corrupt(&format!("invalid {} line", name)))
+        };
+        match field("version")?[..] {
+            [version] if version == MANIFEST_VERSION as u64 => {}
+            [version] if version > MANIFEST_VERSION as u64 => {
+                return Err(Error::UnsupportedVersion { found: version, supported: MANIFEST_VERSION as u64 });
+            }
+            _ => return Err(corrupt("unsupported manifest version")),
+        }
+        let serial = match field("serial")?[..] {
+            [serial] => serial,
+            _ => return Err(corrupt("invalid serial line")),
+        };
+        let segments = field("segments")?;
+        Ok(Some(Manifest { serial, segments }))
+    }
+
+    fn read_segments(path: &Path, lazy: bool, naming: &FileNaming) -> crate::Result<Vec<SSTableSegment>> {
+        if !path.is_dir() {
+            return Err(Error::NotADirectory(path.to_path_buf()));
+        }
+
+        let mut segments = Vec::new();
+
+        // Collect and validate files, only the ones listed by the manifest if there is one
+        let entries: Vec<_> = match SSTable::read_manifest(path)? {
+            Some(manifest) => manifest.segments.iter().map(|&serial| naming.path(path, serial)).collect(),
+            None => path.read_dir()?
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.is_file())
+                .filter(|p| !p.file_name().and_then(|s| s.to_str()).is_some_and(|s| s.starts_with(MANIFEST_FILE)))
+                // Leftovers of interrupted writes
+                .filter(|p| p.extension().and_then(|s| s.to_str()) != Some("tmp"))
+                .collect(),
+        };
+
+        // Validate files before processing
+        for path in &entries {
+            if !naming.has_extension(path) {
+                return Err(corrupt_segment(path, "invalid file extension"));
+            }
+            if naming.parse_path(path).is_none() {
+                return Err(corrupt_segment(path, "invalid segment file name"));
+            }
+        }
+
+        for path in entries {
+            let file_serial = naming.parse_path(&path).unwrap();
+            let segment = if lazy {
+                SSTable::open_segment_file(&path)
+            } else {
+                File::open(&path).and_then(|file| SSTable::read_segment(&mut BufReader::new(file)))
+            };
+            let mut segment = segment.map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => corrupt_segment(&path, &e.to_string()),
+                _ => e.into(),
+            })?;
+            
+            if file_serial != segment.serial {
+                return Err(corrupt_segment(&path, "invalid segment serial number"));
+            }
+
+            segment.persisted = true;
+            segments.push(segment);
+        }
+
+        // Newer segments shadow older ones. Deeper levels hold older data, and within level 0
+        // the serial the segments were written with tells their age.
+        segments.sort_by_key(|s| (std::cmp::Reverse(s.level), s.serial));
+        Ok(segments)
+    }
+
+    // Open a segment file as a file-backed segment
+    fn open_segment_file(path: &Path) -> io::Result<SSTableSegment> {
+        let (file, footer) = FileSegment::open(path)?;
+        let mut segment = SSTableSegment::new(footer.serial);
+        segment.level = footer.level;
+        segment.size = std::fs::metadata(path)?.len() as usize;
+        if footer.entry_count > 0 {
+            segment.min_key = Some(footer.min_key);
+            segment.max_key = Some(footer.max_key);
+        }
+        segment.data = SegmentStore::File(file);
+        Ok(segment)
+    }
+
+    fn write(&self, path: &Path) -> crate::Result<()> {
+        if !path.is_dir() {
+            return Err(Error::NotADirectory(path.to_path_buf()));
+        }
+
+        for s in &self.segments[..self.segments.len()-1] {
+            // Files of persisted segments are live already, any other file under a segment's name
+            // is a leftover the manifest does not list and gets replaced
+            if s.persisted && path == self.path {
+                continue;
+            }
+            SSTable::write_segment_file(path, s, &self.write_options, &self.naming)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_segment_file(path: &Path, segment: &SSTableSegment, options: &SegmentWriteOptions, naming: &FileNaming)
+        -> io::Result<()> {
+        let filename = naming.file_name(segment.serial);
+        let file_path = path.join(&filename);
+        // Write under a temporary name so readers never see a partial segment. A crash before the
+        // data reaches the disk is still caught by the checksum trailer.
+        let temp_path = path.join(format!("{}.tmp", filename));
+        let mut file = std::fs::File::create(&temp_path)?;
+        SSTable::write_segment(&mut file, segment, options)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &file_path)
+    }
+
+    fn write_segment<W: Write>(writer: &mut W, segment: &SSTableSegment, options: &SegmentWriteOptions) -> io::Result<()> {
+        let magic = match options.layout {
+            SegmentLayout::Flat => SEGMENT_MAGIC,
+            SegmentLayout::Block => BLOCK_SEGMENT_MAGIC,
+        };
+        writer.write_all(&magic)?;
+        writer.write_all(&[SEGMENT_FORMAT_VERSION])?;
+
+        // Everything between the header and the trailing CRC32 is checksummed
+        let mut writer = ChecksumWriter::new(writer);
+        let writer = &mut writer;
+        let index = match options.layout {
+            SegmentLayout::Flat => write_entries(writer, segment, options)?,
+            SegmentLayout::Block => write_blocks(writer, segment, options.compression)?,
+        };
+
+        // Footer with the serial, level, entry count, key bounds and sparse index so the segment can be
+        // opened without decoding it, followed by its offset so it can be found from the end of
+        // the file
+        let footer_offset = SEGMENT_HEADER_LEN + writer.written;
+        writer.write_all(&FOOTER_MARKER.to_le_bytes())?;
+        writer.write_all(&segment.serial.to_le_bytes())?;
+        writer.write_all(&segment.level.to_le_bytes())?;
+        writer.write_all(&(segment.data.len() as u64).to_le_bytes())?;
+        write_string(writer, segment.min_key.as_deref().unwrap_or_default())?;
+        write_string(writer, segment.max_key.as_deref().unwrap_or_default())?;
+        writer.write_all(&(index.len() as u32).to_le_bytes())?;
+        for (key, offset) in &index {
+            write_string(writer, key)?;
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+        writer.write_all(&footer_offset.to_le_bytes())?;
+
+        let checksum = writer.hasher.clone().finalize();
+        writer.inner.write_all(&checksum.to_le_bytes())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn read_segment<R: Read>(reader: &mut R) -> io::Result<SSTableSegment> {
+        let mut segment = SSTableSegment::new(0);
+
+        // An empty input holds an empty segment
+        let mut header = [0u8; SEGMENT_HEADER_LEN as usize];
+        match reader.read_exact(&mut header[..1]) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(segment),
+            Err(e) => return Err(e),
+        }
+        reader.read_exact(&mut header[1..])?;
+        let (version, layout) = check_header(&header)?;
+
+        let mut reader = ChecksumReader::new(reader);
+        let reader = &mut reader;
+        match layout {
+            SegmentLayout::Flat => {
+                while let Some((key, value)) = read_entry(reader, version)? {
+                    segment.insert(key, value);
+                }
+            }
+            SegmentLayout::Block => {
+                while let Some(block) = read_block(reader)? {
+                    for (key, value) in decode_block(&block, version)? {
+                        segment.insert(key, value);
+                    }
+                }
+            }
+        }
+
+        let footer = read_footer(reader)?;
+        segment.serial = footer.serial;
+        segment.level = footer.level;
+        if !segment.data.is_empty() {
+            segment.min_key = Some(footer.min_key);
+            segment.max_key = Some(footer.max_key);
+        }
+
+        let checksum = reader.hasher.clone().finalize();
+        let mut checksum_bytes = [0u8; 4];
+        reader.inner.read_exact(&mut checksum_bytes)?;
+        if u32::from_le_bytes(checksum_bytes) != checksum {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Segment checksum mismatch"));
+        }
+
+        Ok(segment)
+    }
+
+    // Frozen view of the table as of now, unaffected by later changes
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            segments: self.segments.clone(),
+            serial: self.latest_serial(),
+            merge_fn: self.merge_fn.clone(),
+        }
+    }
+
+    // Cheaply cloned view of the flushed segments, to be shared with other threads. Unlike a
+    // snapshot it leaves out the active segment, so writes to the table never copy it on its
+    // account. It doesn't see changes made after it was taken either.
+    pub fn read_view(&self) -> SSTableView {
+        SSTableView {
+            segments: self.segments.iter().filter(|s| s.persisted).cloned().collect(),
+            merge_fn: self.merge_fn.clone(),
+        }
+    }
+
+    // Serial of the newest segment, that of the last insert, delete or merge unless segments
+    // were compacted or bulk loaded since. An empty table reports 0.
+    pub fn latest_serial(&self) -> u64 {
+        self.segments.last()
+            .map(|s| s.serial)
+            .unwrap_or(0)
+    }
+
+    // Serial the next insert, delete or merge is stamped with. Each of them is one operation
+    // and takes one serial, new segment files made by compactions and bulk loads take one each.
+    pub fn next_serial(&self) -> u64 {
+        self.latest_serial() + 1
+    }
+
+    // Raise the latest serial so the next insert or delete gets serial + 1. Serials never go
+    // back, a lower one is ignored.
+    pub fn advance_serial(&mut self, serial: u64) {
+        if serial > self.latest_serial() {
+            let active = self.active_segment_index();
+            Arc::make_mut(&mut self.segments[active]).serial = serial;
+        }
+    }
+}
+
+// Read access to the flushed segments of a table, see SSTable::read_view
+#[derive(Clone)]
+pub struct SSTableView {
+    segments: Arc<[Arc<SSTableSegment>]>,
+    merge_fn: Option<MergeFn>,
+}
+
+impl SSTableView {
+    pub fn get(&self, key: &str) -> crate::Result<Option<Box<[u8]>>> {
+        Ok(get_from_segments(&self.segments, key, self.merge_fn.as_ref(), None)?.map(|value| Box::from(&*value)))
+    }
+
+    // Live entries in ascending key order
+    pub fn iter(&self) -> impl Iterator<Item = (String, Box<[u8]>)> + '_ {
+        live_entries(merge_all(&self.segments, self.merge_fn.as_ref(), &ReadErrors::default()), self.merge_fn.as_ref())
+    }
+
+    // Live entries with start <= key < end in ascending key order
+    pub fn scan(&self, start: &str, end: &str) -> impl Iterator<Item = (String, Box<[u8]>)> + '_ {
+        let bounds = (Bound::Included(start.to_owned()), Bound::Excluded(end.max(start).to_owned()));
+        live_entries(merge_range(&self.segments, bounds, self.merge_fn.as_ref(), &ReadErrors::default()), self.merge_fn.as_ref())
+    }
+}
+
+// Point-in-time view of a table that sees no change made after it was taken
+pub struct Snapshot {
+    segments: Vec<Arc<SSTableSegment>>,
+    serial: u64,
+    merge_fn: Option<MergeFn>,
+}
+
+impl Snapshot {
+    // Serial of the last change visible through the snapshot
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    pub fn get(&self, key: &str) -> crate::Result<Option<Box<[u8]>>> {
+        Ok(get_from_segments(&self.segments, key, self.merge_fn.as_ref(), None)?.map(|value| Box::from(&*value)))
+    }
+
+    // Live entries in ascending key order
+    pub fn iter(&self) -> impl Iterator<Item = (String, Box<[u8]>)> + '_ {
+        live_entries(merge_all(&self.segments, self.merge_fn.as_ref(), &ReadErrors::default()), self.merge_fn.as_ref())
+    }
+
+    // Write the snapshot as a single archive: magic, version, serial and segment count, then
+    // every segment holding entries in the segment file format, preceded by its length
+    pub fn write_archive<W: Write>(&self, writer: W) -> crate::Result<()> {
+        let mut writer = io::BufWriter::new(writer);
+        writer.write_all(&ARCHIVE_MAGIC)?;
+        writer.write_all(&[ARCHIVE_VERSION])?;
+        writer.write_all(&self.serial.to_le_bytes())?;
+        let segments: Vec<_> = self.segments.iter().filter(|s| !s.data.is_empty()).collect();
+        writer.write_all(&(segments.len() as u32).to_le_bytes())?;
+        for segment in segments {
+            let mut data = Vec::new();
+            SSTable::write_segment(&mut data, segment, &SegmentWriteOptions::default())?;
+            writer.write_all(&(data.len() as u64).to_le_bytes())?;
+            writer.write_all(&data)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl SSTable {
+    // Write the segments of an archive into the segment directory of a new table, named and
+    // compressed as given, and return the serial the archive was taken at. Every segment is
+    // verified before any file is written.
+    pub fn restore_archive<R: Read>(path: &Path, reader: R, naming: &FileNaming, compression: Option<Codec>)
+        -> crate::Result<u64> {
+        let mut reader = io::BufReader::new(reader);
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid archive: {}", reason));
+        let mut header = [0u8; 17];
+        reader.read_exact(&mut header)?;
+        if header[..4] != ARCHIVE_MAGIC {
+            return Err(invalid("bad magic").into());
+        }
+        if header[4] > ARCHIVE_VERSION {
+            return Err(Error::UnsupportedVersion { found: header[4].into(), supported: ARCHIVE_VERSION.into() });
+        }
+        if header[4] != ARCHIVE_VERSION {
+            return Err(invalid(&format!("unsupported version {}", header[4])).into());
+        }
+        let serial = u64::from_le_bytes(header[5..13].try_into().unwrap());
+        let count = u32::from_le_bytes(header[13..17].try_into().unwrap());
+
+        let mut segments = Vec::new();
+        for _ in 0..count {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            let data = read_body(&mut reader, u64::from_le_bytes(len))?;
+            segments.push(SSTable::read_segment(&mut &data[..])?);
+        }
+        if reader.read(&mut [0])? != 0 {
+            return Err(invalid("trailing data").into());
+        }
+
+        std::fs::create_dir_all(path)?;
+        if path.read_dir()?.next().is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} is not empty", path)).into());
+        }
+        let options = SegmentWriteOptions { compression, ..Default::default() };
+        for segment in &segments {
+            SSTable::write_segment_file(path, segment, &options, naming)?;
+        }
+        Ok(serial)
+    }
+}
+
+fn corrupt_segment(path: &Path, reason: &str) -> Error {
+    Error::CorruptSegment { path: path.to_path_buf(), reason: reason.to_owned() }
+}
+
+// Returns the format version and layout of the segment
+fn check_header(header: &[u8; SEGMENT_HEADER_LEN as usize]) -> io::Result<(u8, SegmentLayout)> {
+    let layout = if header[..4] == SEGMENT_MAGIC {
+        SegmentLayout::Flat
+    } else if header[..4] == BLOCK_SEGMENT_MAGIC {
+        SegmentLayout::Block
+    } else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid segment magic"));
+    };
+    // Carried by the io::Error, converting it into an Error takes it back out
+    if header[4] > SEGMENT_FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion { found: header[4].into(), supported: SEGMENT_FORMAT_VERSION.into() }.into());
+    }
+    if header[4] < MIN_SEGMENT_FORMAT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Unsupported segment format version {}", header[4])));
+    }
+    Ok((header[4], layout))
+}
+
+// Read the next entry, or None once the footer marker is reached
+fn read_entry<R: Read>(reader: &mut R, version: u8) -> io::Result<Option<Entry>> {
+    // Read key length
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let key_len = u32::from_le_bytes(len_bytes);
+    if key_len == FOOTER_MARKER {
+        return Ok(None);
+    }
+
+    // Read key
+    let key = read_string_body(reader, key_len as usize)?;
+    Ok(Some((key, read_value(reader, version)?)))
+}
+
+// Read a value, or None for a tombstone
+fn read_value<R: Read>(reader: &mut R, version: u8) -> io::Result<Option<Value>> {
+    // Read value length, a u32 before version 3
+    let value_len = if version < 3 {
+        let mut len_bytes = [0u8; 4];
+        reader.read_exact(&mut len_bytes)?;
+        u32::from_le_bytes(len_bytes) as u64
+    } else {
+        let mut value_len_bytes = [0u8; 8];
+        reader.read_exact(&mut value_len_bytes)?;
+        u64::from_le_bytes(value_len_bytes)
+    };
+    if value_len == 0 {
+        return Ok(None);
+    }
+
+    // Read expiry
+    let mut expiry_bytes = [0u8; 8];
+    reader.read_exact(&mut expiry_bytes)?;
+
+    // Read codec, values were stored raw before version 4
+    let mut codec = [0u8];
+    if version >= 4 {
+        reader.read_exact(&mut codec)?;
+    }
+    let merge = version >= FIRST_MERGE_VERSION && codec[0] & MERGE_FLAG != 0;
+    if merge {
+        codec[0] &= !MERGE_FLAG;
+    }
+
+    // Read value
+    usize::try_from(value_len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Value too large for this platform"))?;
+    let value = decompress(codec[0], read_body(reader, value_len)?)?;
+    Ok(Some(Value { data: value.into(), expires_at: u64::from_le_bytes(expiry_bytes), merge }))
+}
+
+// Write the entries one after another, returning every Nth key with its offset for the index
+fn write_entries<W: Write>(writer: &mut ChecksumWriter<W>, segment: &SSTableSegment, options: &SegmentWriteOptions) -> io::Result<Vec<IndexEntry>> {
+    let mut index = Vec::new();
+    let errors = ReadErrors::default();
+    for (i, (key, value)) in segment.data.iter(&errors).enumerate() {
+        if i % options.index_interval == 0 {
+            index.push((key.clone(), SEGMENT_HEADER_LEN + writer.written));
+        }
+
+        // Write key length as u32 (4 bytes) followed by the UTF-8 key
+        write_string(writer, &key)?;
+        write_value(writer, &value, options.compression)?;
+    }
+    errors.check()?;
+    Ok(index)
+}
+
+fn write_value<W: Write>(writer: &mut W, value: &Option<Value>, compression: Option<Codec>) -> io::Result<()> {
+    match value {
+        Some(v) => {
+            let (codec, data) = compress(compression.unwrap_or(Codec::None), &v.data)?;
+            // Write stored value length as u64 (8 bytes)
+            writer.write_all(&(data.len() as u64).to_le_bytes())?;
+            // Write expiry as u64 (8 bytes)
+            writer.write_all(&v.expires_at.to_le_bytes())?;
+            // Write codec with the merge flag (1 byte) and value bytes
+            writer.write_all(&[codec.id() | if v.merge { MERGE_FLAG } else { 0 }])?;
+            writer.write_all(&data)
+        }
+        None => {
+            // For deleted entries, write length as 0
+            writer.write_all(&0u64.to_le_bytes())
+        }
+    }
+}
+
+// Write the entries grouped into blocks, returning the first key and offset of every block for
+// the index. A block is its length as u32, the entries, the u32 offsets of its restart points and
+// their count.
+fn write_blocks<W: Write>(writer: &mut ChecksumWriter<W>, segment: &SSTableSegment, compression: Option<Codec>) -> io::Result<Vec<IndexEntry>> {
+    let mut index = Vec::new();
+    let mut block = Vec::new();
+    let mut restarts = Vec::new();
+    let mut entries = 0;
+    let mut previous_key = String::new();
+    let errors = ReadErrors::default();
+    for (key, value) in segment.data.iter(&errors) {
+        if entries == 0 {
+            index.push((key.clone(), SEGMENT_HEADER_LEN + writer.written));
+        }
+        if entries % BLOCK_RESTART_INTERVAL == 0 {
+            restarts.push(block.len() as u32);
+            previous_key.clear();
+        }
+
+        // Write shared prefix length and suffix length as u32 (4 bytes each) followed by the suffix
+        let shared = previous_key.bytes().zip(key.bytes()).take_while(|(a, b)| a == b).count();
+        block.extend_from_slice(&(shared as u32).to_le_bytes());
+        block.extend_from_slice(&((key.len() - shared) as u32).to_le_bytes());
+        block.extend_from_slice(&key.as_bytes()[shared..]);
+        write_value(&mut block, &value, compression)?;
+        previous_key = key;
+        entries += 1;
+
+        if block.len() >= BLOCK_SIZE {
+            write_block(writer, &mut block, &mut restarts)?;
+            entries = 0;
+        }
+    }
+    errors.check()?;
+    if entries > 0 {
+        write_block(writer, &mut block, &mut restarts)?;
+    }
+    Ok(index)
+}
+
+fn write_block<W: Write>(writer: &mut W, block: &mut Vec<u8>, restarts: &mut Vec<u32>) -> io::Result<()> {
+    for restart in restarts.iter() {
+        block.extend_from_slice(&restart.to_le_bytes());
+    }
+    block.extend_from_slice(&(restarts.len() as u32).to_le_bytes());
+    let len = u32::try_from(block.len()).ok().filter(|&len| len != FOOTER_MARKER)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Segment block too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(block)?;
+    block.clear();
+    restarts.clear();
+    Ok(())
+}
+
+// Read the next block, or None once the footer marker is reached
+fn read_block<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let len = u32::from_le_bytes(len_bytes);
+    if len == FOOTER_MARKER {
+        return Ok(None);
+    }
+    read_body(reader, len.into()).map(Some)
+}
+
+// Split a block into its entries and the offsets of its restart points
+fn split_block(block: &[u8]) -> io::Result<(&[u8], Vec<usize>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid segment block");
+    let count_start = block.len().checked_sub(4).ok_or_else(invalid)?;
+    let count = u32::from_le_bytes(block[count_start..].try_into().unwrap()) as usize;
+    let restarts_start = count.checked_mul(4)
+        .and_then(|len| count_start.checked_sub(len))
+        .ok_or_else(invalid)?;
+    let restarts: Vec<usize> = block[restarts_start..count_start].chunks_exact(4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        .collect();
+    if restarts.iter().any(|&restart| restart >= restarts_start) {
+        return Err(invalid());
+    }
+    Ok((&block[..restarts_start], restarts))
+}
+
+// Read the key of the block entry at the reader position, sharing a prefix with the previous key
+fn read_block_key(reader: &mut &[u8], previous_key: &str) -> io::Result<String> {
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let shared = u32::from_le_bytes(len_bytes) as usize;
+    reader.read_exact(&mut len_bytes)?;
+    let suffix_len = u32::from_le_bytes(len_bytes) as usize;
+
+    let prefix = previous_key.as_bytes().get(..shared)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid shared key prefix length"))?;
+    let suffix = reader.get(..suffix_len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Block entry is truncated"))?;
+    let key = [prefix, suffix].concat();
+    *reader = &reader[suffix_len..];
+    String::from_utf8(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode_block(block: &[u8], version: u8) -> io::Result<Vec<Entry>> {
+    let (mut reader, _) = split_block(block)?;
+    let mut entries: Vec<Entry> = Vec::new();
+    while !reader.is_empty() {
+        let key = read_block_key(&mut reader, entries.last().map_or("", |(key, _)| key))?;
+        let value = read_value(&mut reader, version)?;
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+// Find a key in a block by binary searching its restart points and scanning from the closest one
+fn search_block(block: &[u8], version: u8, key: &str) -> io::Result<Option<Option<Value>>> {
+    let (entries, restarts) = split_block(block)?;
+
+    // Number of restart points with a key not greater than the given one
+    let (mut low, mut high) = (0, restarts.len());
+    while low < high {
+        let middle = (low + high) / 2;
+        if read_block_key(&mut &entries[restarts[middle]..], "")?.as_str() <= key {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let Some(restart) = low.checked_sub(1) else {
+        return Ok(None);
+    };
+
+    let mut reader = &entries[restarts[restart]..];
+    let mut previous_key = String::new();
+    while !reader.is_empty() {
+        let k = read_block_key(&mut reader, &previous_key)?;
+        let value = read_value(&mut reader, version)?;
+        match k.as_str().cmp(key) {
+            std::cmp::Ordering::Less => previous_key = k,
+            std::cmp::Ordering::Equal => return Ok(Some(value)),
+            std::cmp::Ordering::Greater => break,
+        }
+    }
+    Ok(None)
+}
+
+// Codec and bytes to store for a value. Values that don't shrink are stored raw.
+fn compress(codec: Codec, data: &[u8]) -> io::Result<(Codec, Cow<'_, [u8]>)> {
+    match codec {
+        Codec::None => Ok((Codec::None, Cow::Borrowed(data))),
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => {
+            let compressed = zstd::bulk::compress(data, 0)?;
+            if compressed.len() < data.len() {
+                Ok((Codec::Zstd, Cow::Owned(compressed)))
+            } else {
+                Ok((Codec::None, Cow::Borrowed(data)))
+            }
+        }
+    }
+}
+
+fn decompress(codec: u8, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    match codec {
+        0 => Ok(data),
+        #[cfg(feature = "zstd")]
+        1 => zstd::stream::decode_all(&data[..]),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported value codec {}", codec))),
+    }
+}
+
+// Read the footer fields following the footer marker
+fn read_footer<R: Read>(reader: &mut R) -> io::Result<SegmentFooter> {
+    let mut serial_bytes = [0u8; 8];
+    reader.read_exact(&mut serial_bytes)?;
+    let mut level_bytes = [0u8; 4];
+    reader.read_exact(&mut level_bytes)?;
+    let mut count_bytes = [0u8; 8];
+    reader.read_exact(&mut count_bytes)?;
+    let min_key = read_string(reader)?;
+    let max_key = read_string(reader)?;
+
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let mut index = Vec::new();
+    let mut offset_bytes = [0u8; 8];
+    for _ in 0..u32::from_le_bytes(len_bytes) {
+        let key = read_string(reader)?;
+        reader.read_exact(&mut offset_bytes)?;
+        index.push((key, u64::from_le_bytes(offset_bytes)));
+    }
+
+    reader.read_exact(&mut offset_bytes)?;
+    Ok(SegmentFooter {
+        serial: u64::from_le_bytes(serial_bytes),
+        level: u32::from_le_bytes(level_bytes),
+        entry_count: u64::from_le_bytes(count_bytes),
+        min_key,
+        max_key,
+        index,
+    })
+}
+
+// Computes a CRC32 over everything written through it
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+    written: u64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        ChecksumWriter { inner, hasher: crc32fast::Hasher::new(), written: 0 }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Computes a CRC32 over everything read through it
+struct ChecksumReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        ChecksumReader { inner, hasher: crc32fast::Hasher::new() }
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    writer.write_all(&(s.len() as u32).to_le_bytes())?;
+    writer.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    read_string_body(reader, u32::from_le_bytes(len_bytes) as usize)
+}
+
+fn read_string_body<R: Read>(reader: &mut R, len: usize) -> io::Result<String> {
+    String::from_utf8(read_body(reader, len as u64)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Read a key or value of the given length into a vector of its own. Lengths come from the input,
+// so one past the end of it fails once the input runs out instead of being allocated up front.
+fn read_body<R: Read>(reader: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(len.min(MAX_PREALLOCATED_LEN) as usize);
+    reader.take(len).read_to_end(&mut body)?;
+    if (body.len() as u64) < len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Length runs past the end of the segment"));
+    }
+    Ok(body)
+}
+
+fn live_entries<'a, I>(entries: I, merge_fn: Option<&MergeFn>) -> impl Iterator<Item = (String, Box<[u8]>)> + 'a
+    where I: Iterator<Item = Entry> + 'a {
+    let now = now_millis();
+    let merge_fn = merge_fn.cloned();
+    entries.filter_map(move |(key, value)| live_value(value, now, merge_fn.as_ref()).map(|v| (key, Box::from(&*v))))
+}
+
+// Live value of the key in the newest segment holding it, with the merge operands of newer
+// segments applied to it
+fn get_from_segments(segments: &[Arc<SSTableSegment>], key: &str, merge_fn: Option<&MergeFn>,
+    metrics: Option<&dyn Metrics>) -> crate::Result<Option<Arc<[u8]>>> {
+    let now = now_millis();
+    let mut operands: Option<Value> = None;
+    for segment in segments.iter().rev() {
+        if !segment.in_bounds(key) || !segment.may_contain(key) {
+            continue;
+        }
+        if let Some(metrics) = metrics {
+            metrics.on_segment_read(segment.serial);
+        }
+        match (segment.data.get(key)?, operands.take()) {
+            (Some(Some(older)), newer) if older.merge => {
+                operands = Some(match newer {
+                    Some(newer) => older.followed_by(&newer),
+                    None => older,
+                });
+            }
+            (Some(value), None) => return Ok(live_value(value, now, merge_fn)),
+            (Some(value), Some(operands)) => {
+                let merge_fn = merge_fn.ok_or_else(no_merge_fn)?;
+                return Ok(Some(merge_fn.resolve(value.as_ref(), &operands, now).data));
+            }
+            (None, newer) => operands = newer,
+        }
+    }
+    match operands {
+        Some(operands) => Ok(live_value(Some(operands), now, Some(merge_fn.ok_or_else(no_merge_fn)?))),
+        None => Ok(None),
+    }
+}
+
+fn no_merge_fn() -> Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Merge operands found without a merge function").into()
+}
+
+// Entries a compaction keeps of the keys of segments within the bounds, with the segments older
+// than them deciding which tombstones still shadow something
+fn compacted_entries(older: &[Arc<SSTableSegment>], compacted: &[Arc<SSTableSegment>],
+    bounds: (Bound<String>, Bound<String>), drop_tombstones: bool, merge_fn: Option<&MergeFn>, now: u64) -> io::Result<Vec<Entry>> {
+    let errors = ReadErrors::default();
+    let mut entries = merge_range(compacted, bounds, merge_fn, &errors);
+    entries.now = now;
+    let entries = entries
+        .filter_map(|(key, value)| match value {
+            // Operands with no value below them in the whole table can be applied
+            Some(value) if value.merge => match merge_fn {
+                Some(merge_fn) if !older.iter().any(|s| s.holds(&key)) => {
+                    Some((key, Some(merge_fn.resolve(None, &value, now))))
+                }
+                _ => Some((key, Some(value))),
+            },
+            Some(value) if !value.is_expired(now) => Some((key, Some(value))),
+            // Expired values are dropped, or kept as tombstones if they shadow older data
+            Some(_) => older.iter().any(|s| s.holds(&key)).then_some((key, None)),
+            None => (!drop_tombstones || older.iter().any(|s| s.holds(&key))).then_some((key, None)),
+        })
+        .collect();
+    errors.check()?;
+    Ok(entries)
+}
 
-            serial = segment.serial;
-            segments.push(segment);
-        }
+// Consecutive key ranges covering all keys, split at keys sampled from the segments so they hold
+// a similar number of entries
+#[cfg(feature = "parallel")]
+fn shard_bounds(segments: &[Arc<SSTableSegment>], shards: usize) -> Vec<(Bound<String>, Bound<String>)> {
+    let mut keys: Vec<String> = segments.iter().flat_map(|s| s.data.sample_keys()).collect();
+    keys.sort_unstable();
+    keys.dedup();
+    let mut splits: Vec<String> = (1..shards).filter_map(|i| keys.get(i * keys.len() / shards).cloned()).collect();
+    splits.dedup();
 
-        Ok(segments)
+    let mut bounds = Vec::with_capacity(splits.len() + 1);
+    let mut start = Bound::Unbounded;
+    for split in splits {
+        bounds.push((start, Bound::Excluded(split.clone())));
+        start = Bound::Included(split);
     }
+    bounds.push((start, Bound::Unbounded));
+    bounds
+}
 
-    fn write(&self, path: &Path) -> io::Result<()> {
-        if !path.is_dir() {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Path {:?} is not a directory", path)));
-        }
+// Newest entry (value or tombstone) per key across segments ordered from oldest to newest. A
+// segment file that cannot be read ends its entries early and records the error.
+fn merge_all<'a>(segments: &'a [Arc<SSTableSegment>], merge_fn: Option<&MergeFn>, errors: &ReadErrors) -> MergeIterator<'a> {
+    MergeIterator::new(segments.iter().map(|segment| segment.data.iter(errors)).collect(), merge_fn)
+}
 
-        for s in &self.segments[..self.segments.len()-1] {
-            let filename = format!("{}.sst", s.serial);
-            let file_path = path.join(&filename);
-            if file_path.exists() {
-                continue;
-            }
-            let mut file = std::fs::File::create(file_path)?;
-            SSTable::write_segment(&mut file, s)?;
-        }
+// Like merge_all, for the keys within the bounds
+fn merge_range<'a>(segments: &'a [Arc<SSTableSegment>], bounds: (Bound<String>, Bound<String>),
+    merge_fn: Option<&MergeFn>, errors: &ReadErrors) -> MergeIterator<'a> {
+    MergeIterator::new(segments.iter().map(|segment| segment.data.range(bounds.clone(), errors)).collect(), merge_fn)
+}
 
-        Ok(())
+// K-way merge over segments ordered from oldest to newest. Merge operands are combined with the
+// older entries of their key, they stay operands if none of the segments has a value below them.
+struct MergeIterator<'a> {
+    sources: Vec<Box<dyn Iterator<Item = Entry> + 'a>>,
+    // Next entry of each source
+    heads: Vec<Option<Entry>>,
+    merge_fn: Option<MergeFn>,
+    now: u64,
+}
+
+impl<'a> MergeIterator<'a> {
+    fn new(mut sources: Vec<Box<dyn Iterator<Item = Entry> + 'a>>, merge_fn: Option<&MergeFn>) -> Self {
+        let heads = sources.iter_mut().map(|source| source.next()).collect();
+        MergeIterator { sources, heads, merge_fn: merge_fn.cloned(), now: now_millis() }
     }
+}
 
-    fn write_segment<W: Write>(writer: &mut W, segment: &SSTableSegment) -> io::Result<()> {
-        for (key, value) in &segment.data {
-            // Write key as UTF-8 followed by null terminator
-            writer.write_all(key.as_bytes())?;
-            writer.write_all(&[0])?;
+impl Iterator for MergeIterator<'_> {
+    type Item = Entry;
 
-            match value {
-                Some(v) => {
-                    // Write value length as u32 (4 bytes)
-                    writer.write_all(&(v.len() as u32).to_le_bytes())?;
-                    // Write value bytes
-                    writer.write_all(v)?;
-                }
-                None => {
-                    // For deleted entries, write length as 0
-                    writer.write_all(&[0, 0, 0, 0])?;
+    fn next(&mut self) -> Option<Self::Item> {
+        // Find the smallest key, newer segments win ties
+        let mut newest: Option<usize> = None;
+        for (index, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                if newest.is_none_or(|n| key <= &self.heads[n].as_ref().unwrap().0) {
+                    newest = Some(index);
                 }
             }
         }
-        writer.flush()?;
-        Ok(())
-    }
+        let newest_index = newest?;
+        let (key, mut value) = self.heads[newest_index].take()?;
+        self.heads[newest_index] = self.sources[newest_index].next();
 
-    fn read_segment<R: Read>(reader: &mut R, initial_serial: u64) -> io::Result<SSTableSegment> {
-        let mut segment = SSTableSegment::new(initial_serial);
-        let mut buffer = Vec::new();
-        
-        loop {
-            // Read key until null terminator
-            buffer.clear();
-            let mut byte = [0u8];
-            
-            loop {
-                match reader.read_exact(&mut byte) {
-                    Ok(_) if byte[0] == 0 => break,
-                    Ok(_) => buffer.push(byte[0]),
-                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                        return if buffer.is_empty() {
-                            Ok(segment)
-                        } else {
-                            Err(e)
-                        }
-                    }
-                    Err(e) => return Err(e),
-                }
+        // Skip shadowed entries of the same key in older segments, newest first so operands
+        // meet the value below them
+        for index in (0..newest_index).rev() {
+            if self.heads[index].as_ref().is_none_or(|(k, _)| *k != key) {
+                continue;
             }
-            
-            let key = String::from_utf8(buffer.clone())
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            
-            // Read value length
-            let mut len_bytes = [0u8; 4];
-            reader.read_exact(&mut len_bytes)?;
-            let value_len = u32::from_le_bytes(len_bytes) as usize;
-            
-            if value_len == 0 {
-                segment.insert(key, None);
-            } else {
-                // Read value
-                if buffer.len() < value_len {
-                    buffer.resize(value_len, 0);
-                }
-                reader.read_exact(&mut buffer[..value_len])?;
-                segment.insert(key, Some(buffer[..value_len].to_vec()));
+            let (_, older) = std::mem::replace(&mut self.heads[index], self.sources[index].next()).unwrap();
+            if let (Some(operands), Some(merge_fn)) = (value.as_ref().filter(|v| v.merge), &self.merge_fn) {
+                value = Some(match older {
+                    Some(older) if older.merge => older.followed_by(operands),
+                    older => merge_fn.resolve(older.as_ref(), operands, self.now),
+                });
             }
         }
-    }
 
-    pub fn latest_serial(&self) -> u64 {
-        self.segments.last()
-            .map(|s| s.serial)
-            .unwrap_or(0)
+        Some((key, value))
     }
 }
 
@@ -280,12 +2431,24 @@ mod tests {
     use io::Cursor;
     use tempfile::tempdir;
 
-    const SEGMENT_SIZE_LIMIT: usize = 1024 * 1024;
+    const SEGMENT_SIZE_LIMIT: usize = DEFAULT_MAX_SEGMENT_SIZE;
 
     fn filler() -> Vec<u8> {
         vec![0u8; SEGMENT_SIZE_LIMIT]
     }
 
+    fn default_naming() -> FileNaming {
+        FileNaming::unpadded(SEGMENT_EXTENSION)
+    }
+
+    fn sst_files(path: &Path) -> Vec<String> {
+        fs::read_dir(path).unwrap()
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".sst"))
+            .collect()
+    }
+
     #[test]
     fn test_insert_and_get() {
         let mut table = SSTable::try_new(tempdir().unwrap().path(), SEGMENT_SIZE_LIMIT).unwrap();
@@ -347,13 +2510,211 @@ mod tests {
         assert_eq!(table.segments[0].size, (4 + 9) + (4 + 6)); // ("key1" + "new_value") + ("key2" + "value2") lengths
     }
 
+    fn sum_merge() -> MergeFn {
+        MergeFn::new(|existing, operands| {
+            let decode = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+            let sum = existing.map_or(0, decode) + operands.iter().map(|o| decode(o)).sum::<u64>();
+            sum.to_le_bytes().to_vec()
+        })
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert!(table.merge("count", &1u64.to_le_bytes()).is_err());
+
+        let mut table = table.with_merge_fn(sum_merge());
+        for _ in 0..3 {
+            table.merge("count", &1u64.to_le_bytes()).unwrap();
+            table.flush().unwrap();
+        }
+        table.insert("base", &10u64.to_le_bytes()).unwrap();
+        table.flush().unwrap();
+        table.merge("base", &5u64.to_le_bytes()).unwrap();
+        table.merge("base", &5u64.to_le_bytes()).unwrap();
+
+        let operands = |table: &SSTable, key: &str| table.segments.iter()
+            .filter(|s| matches!(s.data.get(key), Ok(Some(Some(v))) if v.merge))
+            .count();
+        assert_eq!(operands(&table, "count"), 3);
+        assert_eq!(operands(&table, "base"), 1);
+        assert_eq!(&*table.try_get("count").unwrap().unwrap(), &3u64.to_le_bytes());
+        assert_eq!(&*table.try_get("base").unwrap().unwrap(), &20u64.to_le_bytes());
+        assert_eq!(table.iter().collect::<Vec<_>>(), vec![
+            ("base".to_string(), Box::from(20u64.to_le_bytes())),
+            ("count".to_string(), Box::from(3u64.to_le_bytes())),
+        ]);
+
+        // Merging into a value of the active segment applies the operand right away
+        table.merge("base", &1u64.to_le_bytes()).unwrap();
+        table.insert("active", &1u64.to_le_bytes()).unwrap();
+        table.merge("active", &1u64.to_le_bytes()).unwrap();
+        assert_eq!(operands(&table, "active"), 0);
+        assert_eq!(table.get_many(&["active", "base"]).unwrap(), vec![
+            Some(Box::from(2u64.to_le_bytes())),
+            Some(Box::from(21u64.to_le_bytes())),
+        ]);
+
+        // Operands are written to segment files as such
+        table.flush().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap().with_merge_fn(sum_merge());
+        assert_eq!(operands(&table, "count"), 3);
+        assert_eq!(&*table.try_get("count").unwrap().unwrap(), &3u64.to_le_bytes());
+
+        table.compact().unwrap();
+        assert_eq!(operands(&table, "count") + operands(&table, "base"), 0);
+        assert_eq!(&*table.try_get("count").unwrap().unwrap(), &3u64.to_le_bytes());
+        assert_eq!(&*table.try_get("base").unwrap().unwrap(), &21u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_latest_serial() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert_eq!(table.latest_serial(), 0);
+
+        table.insert("key1", b"value1").unwrap();
+        assert_eq!(table.latest_serial(), 1);
+        table.insert("key1", b"value2").unwrap();
+        assert_eq!(table.latest_serial(), 2);
+        table.delete("key1").unwrap();
+        assert_eq!(table.latest_serial(), 3);
+
+        table.insert("filler", &filler()).unwrap();
+        assert_eq!(table.latest_serial(), 4);
+
+        table.compact().unwrap();
+        assert_eq!(table.latest_serial(), table.segments.last().unwrap().serial);
+
+        table.advance_serial(10);
+        table.advance_serial(5);
+        table.insert("key3", b"value3").unwrap();
+        assert_eq!(table.latest_serial(), 11);
+    }
+
+    #[test]
+    fn test_segments_meta() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        table.insert("b", b"1").unwrap();
+        table.insert("a", b"22").unwrap();
+        table.insert("b", b"333").unwrap();
+        table.flush().unwrap();
+        table.insert("c", b"4").unwrap();
+        table.delete("a").unwrap();
+
+        let flushed = SegmentMeta {
+            serial: 3,
+            key_count: 2,
+            size_bytes: 7,
+            min_key: Some("a".to_string()),
+            max_key: Some("b".to_string()),
+            level: 0,
+            is_active: false,
+        };
+        let active = SegmentMeta {
+            serial: 5,
+            key_count: 2,
+            size_bytes: 3,
+            min_key: Some("a".to_string()),
+            max_key: Some("c".to_string()),
+            level: 0,
+            is_active: true,
+        };
+        assert_eq!(table.segments_meta(), vec![flushed.clone(), active]);
+
+        // Left in its file, a segment reports the file size
+        table.flush().unwrap();
+        let lazy = SSTable::try_new_lazy(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        let meta = lazy.segments_meta();
+        assert_eq!(meta.iter().map(|m| (m.serial, m.key_count, m.is_active)).collect::<Vec<_>>(),
+            vec![(3, 2, false), (5, 2, false)]);
+        assert_eq!(meta[0].size_bytes as u64, fs::metadata(dir.path().join("3.sst")).unwrap().len());
+        assert_eq!(meta[1].min_key, Some("a".to_string()));
+    }
+
+    #[test]
+    fn test_bloom_no_false_negatives() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), 64 * 1024).unwrap().with_bloom(true);
+
+        // xorshift keeps the keys pseudo-random but reproducible
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut keys = Vec::new();
+        for _ in 0..10_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            keys.push(format!("key{}", state));
+        }
+
+        for key in &keys {
+            table.insert(key, key.as_bytes()).unwrap();
+        }
+        assert!(table.segments.len() > 1);
+
+        for key in &keys {
+            assert_eq!(&*table.get(key).unwrap(), key.as_bytes());
+        }
+        for segment in &table.segments {
+            for (key, _) in segment.data.iter(&ReadErrors::default()) {
+                assert!(segment.may_contain(&key));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bloom_tombstone_shadowing() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap().with_bloom(true);
+
+        table.insert("key1", b"value1").unwrap();
+        table.insert("filler", &filler()).unwrap();  // Force new segment
+        table.delete("key1").unwrap();
+
+        assert!(table.get("key1").is_none());
+    }
+
+    #[test]
+    fn test_get_skips_segments_out_of_bounds() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), 16).unwrap();
+
+        table.insert("a1", &[1; 16]).unwrap();  // Rolls to a new segment
+        table.insert("m1", b"value").unwrap();
+        assert_eq!(table.segments.len(), 2);
+        assert_eq!(table.segments[0].min_key.as_deref(), Some("a1"));
+        assert_eq!(table.segments[1].max_key.as_deref(), Some("m1"));
+
+        // Smuggle an entry outside the bounds, lookups must never see it
+        Arc::make_mut(&mut table.segments[0]).data.memory_mut().insert("z1".to_string(), Some(Value::new(b"hidden".to_vec(), 0)));
+        assert!(table.get("z1").is_none());
+        assert!(table.get("a1").is_some());
+    }
+
+    #[test]
+    fn test_read_segment_restores_bounds() {
+        let mut segment = SSTableSegment::new(0);
+        segment.insert("b".to_string(), Some(Value::new(b"1".to_vec(), 0)));
+        segment.delete("a".to_string());
+        segment.insert("c".to_string(), Some(Value::new(b"2".to_vec(), 0)));
+
+        let mut buffer = Vec::new();
+        SSTable::write_segment(&mut buffer, &segment, &SegmentWriteOptions::default()).unwrap();
+        let read = SSTable::read_segment(&mut Cursor::new(&buffer)).unwrap();
+
+        assert_eq!(read.min_key.as_deref(), Some("a"));
+        assert_eq!(read.max_key.as_deref(), Some("c"));
+    }
+
     #[test]
     fn test_delete() {
         let mut table = SSTable::try_new(tempdir().unwrap().path(), SEGMENT_SIZE_LIMIT).unwrap();
         table.insert("key1", b"value1").unwrap();
         assert_eq!(&*table.get("key1").unwrap(), b"value1");
         
-        table.delete("key1");
+        table.delete("key1").unwrap();
         assert!(table.get("key1").is_none());
     }
 
@@ -361,7 +2722,7 @@ mod tests {
     fn test_delete_and_reinsert() {
         let mut table = SSTable::try_new(tempdir().unwrap().path(), SEGMENT_SIZE_LIMIT).unwrap();
         table.insert("key1", b"value1").unwrap();
-        table.delete("key1");
+        table.delete("key1").unwrap();
         table.insert("key1", b"value2").unwrap();
         assert_eq!(&*table.get("key1").unwrap(), b"value2");
     }
@@ -382,6 +2743,20 @@ mod tests {
         assert_eq!(&*table.get("key3").unwrap(), b"value3");
     }
 
+    #[test]
+    fn test_custom_segment_size() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), 10).unwrap();
+
+        table.insert("k1", b"1234").unwrap();
+        assert_eq!(table.segments.len(), 1);
+
+        // 6 + 7 bytes crosses the 10 byte limit
+        table.insert("k2", b"12345").unwrap();
+        assert_eq!(table.segments.len(), 2);
+        assert_eq!(sst_files(dir.path()).len(), 1);
+    }
+
     #[test]
     fn test_segment_value_shadowing() {
         let dir = tempdir().unwrap();
@@ -401,11 +2776,228 @@ mod tests {
         
         table.insert("key1", b"value1").unwrap();
         table.insert("filler", &filler()).unwrap();  // Force new segment
-        table.delete("key1");
+        table.delete("key1").unwrap();
         
         assert!(table.get("key1").is_none());
     }
 
+    #[test]
+    fn test_contains_key() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+
+        table.insert("key1", b"value1").unwrap();
+        table.insert("key2", b"value2").unwrap();
+        table.flush().unwrap();
+        table.delete("key1").unwrap();
+        table.insert_with_expiry("key3", b"value3", 1).unwrap();
+
+        assert!(!table.contains_key("key1").unwrap());
+        assert!(table.contains_key("key2").unwrap());
+        assert!(!table.contains_key("key3").unwrap());
+        assert!(!table.contains_key("missing").unwrap());
+    }
+
+    #[test]
+    fn test_key_index() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), 64).unwrap()
+            .with_merge_fn(sum_merge());
+        for i in 0..50 {
+            table.insert(&format!("key{:02}", i), b"old").unwrap();
+        }
+        table.insert("key10", b"new").unwrap();
+        table.delete("key20").unwrap();
+        table.merge("count", &1u64.to_le_bytes()).unwrap();
+        table.flush().unwrap();
+        table.merge("count", &2u64.to_le_bytes()).unwrap();
+
+        // Enabling the index on existing segments, and keeping it up to date afterwards
+        let mut table = table.with_key_index(true);
+        table.insert("key30", b"newer").unwrap();
+        table.delete("key31").unwrap();
+        table.bulk_load([("key40".to_string(), b"loaded".to_vec())]).unwrap();
+        assert_eq!(table.get("key00").unwrap().as_ref(), b"old");
+        assert_eq!(table.get("key10").unwrap().as_ref(), b"new");
+        assert_eq!(table.get("key20"), None);
+        assert_eq!(table.get("key30").unwrap().as_ref(), b"newer");
+        assert_eq!(table.get("key31"), None);
+        assert_eq!(table.get("key40").unwrap().as_ref(), b"loaded");
+        assert_eq!(table.get("count").unwrap().as_ref(), 3u64.to_le_bytes());
+        assert_eq!(table.get("missing"), None);
+        assert!(table.contains_key("key00").unwrap());
+        assert!(!table.contains_key("key20").unwrap());
+        assert!(!table.contains_key("missing").unwrap());
+
+        table.compact().unwrap();
+        table.insert("key50", b"after").unwrap();
+        assert_eq!(table.get("key10").unwrap().as_ref(), b"new");
+        assert_eq!(table.get("key20"), None);
+        assert_eq!(table.get("key50").unwrap().as_ref(), b"after");
+        assert_eq!(table.get("count").unwrap().as_ref(), 3u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_get_shared() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        table.insert("key", &[7; 1024]).unwrap();
+        let first = table.get_shared("key").unwrap().unwrap();
+        let second = table.get_shared("key").unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(&table.try_get("key").unwrap().unwrap()[..], &first[..]);
+        assert_eq!(table.get_shared("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let dir = tempdir().unwrap();
+        {
+            let mut table = SSTable::try_new(dir.path(), 32).unwrap();
+            for i in 0..10 {
+                table.insert(&format!("key{}", i), format!("value{}", i).as_bytes()).unwrap();
+            }
+            table.delete("key3").unwrap();
+            table.insert("key5", b"updated").unwrap();
+            table.flush().unwrap();
+            table.delete("key7").unwrap();
+        }
+
+        let keys = ["key9", "missing", "key3", "key5", "key0", "key7", "key5", "a"];
+        for table in [
+            SSTable::try_new(dir.path(), 32).unwrap(),
+            SSTable::try_new_lazy(dir.path(), 32).unwrap(),
+        ] {
+            let expected: Vec<_> = keys.iter().map(|key| table.get(key)).collect();
+            assert_eq!(table.get_many(&keys).unwrap(), expected);
+            assert_eq!(table.get_many(&[]).unwrap(), Vec::<Option<Box<[u8]>>>::new());
+        }
+    }
+
+    #[test]
+    fn test_iter_shadowing() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+
+        table.insert("key1", b"value1").unwrap();
+        table.insert("key2", b"value2").unwrap();
+        table.insert("filler", &filler()).unwrap();  // Force new segment
+        table.insert("key1", b"value3").unwrap();
+        table.delete("key2").unwrap();
+        table.insert("key0", b"value0").unwrap();
+
+        let entries: Vec<_> = table.iter().collect();
+        let keys: Vec<_> = entries.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["filler", "key0", "key1"]);
+        assert_eq!(&*entries[1].1, b"value0");
+        assert_eq!(&*entries[2].1, b"value3");
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+
+        table.insert("a", b"1").unwrap();
+        table.insert("b", b"2").unwrap();
+        table.insert("c", b"3").unwrap();
+        table.insert("filler", &filler()).unwrap();  // Force new segment
+        table.insert("b", b"4").unwrap();
+        table.delete("c").unwrap();
+        table.insert("d", b"5").unwrap();
+
+        let entries: Vec<_> = table.scan("b", "e").collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].0.as_str(), &*entries[0].1), ("b", &b"4"[..]));
+        assert_eq!((entries[1].0.as_str(), &*entries[1].1), ("d", &b"5"[..]));
+
+        assert_eq!(table.scan("a", "a").count(), 0);
+        assert_eq!(table.scan("d", "a").count(), 0);
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+
+        table.insert("user:1", b"root").unwrap();
+        table.insert("user:1:posts:1", b"a").unwrap();
+        table.insert("user:1:posts:2", b"b").unwrap();
+        table.insert("user:2", b"other").unwrap();
+        table.insert("filler", &filler()).unwrap();  // Force new segment
+        table.insert("user:1:posts:1", b"c").unwrap();
+        table.delete("user:1:posts:2").unwrap();
+
+        let entries: Vec<_> = table.scan_prefix("user:1").collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].0.as_str(), &*entries[0].1), ("user:1", &b"root"[..]));
+        assert_eq!((entries[1].0.as_str(), &*entries[1].1), ("user:1:posts:1", &b"c"[..]));
+
+        assert_eq!(table.scan_prefix("").count(), 4);
+        assert_eq!(table.scan_prefix("missing").count(), 0);
+    }
+
+    #[test]
+    fn test_merge_iter() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        table.insert("a", b"old").unwrap();
+        table.insert("b", b"b").unwrap();
+        table.insert("c", b"c").unwrap();
+        table.flush().unwrap();
+        table.insert("a", b"new").unwrap();
+        table.delete("b").unwrap();
+        table.insert("d", b"d").unwrap();
+
+        let entries: Vec<_> = table.merge_iter(..).map(|(key, value)| (key, value.map(|v| v.to_vec()))).collect();
+        assert_eq!(entries, vec![
+            ("a".to_string(), Some(b"new".to_vec())),
+            ("b".to_string(), None),
+            ("c".to_string(), Some(b"c".to_vec())),
+            ("d".to_string(), Some(b"d".to_vec())),
+        ]);
+
+        let keys: Vec<_> = table.merge_iter("b".to_string().."d".to_string()).map(|(key, _)| key).collect();
+        assert_eq!(keys, ["b", "c"]);
+        let keys: Vec<_> = table.merge_iter((Bound::Excluded("b".to_string()), Bound::Unbounded)).map(|(key, _)| key).collect();
+        assert_eq!(keys, ["c", "d"]);
+        assert_eq!(table.merge_iter("x".to_string()..).count(), 0);
+    }
+
+    #[test]
+    fn test_read_view_across_threads() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        for i in 0..100 {
+            table.insert(&format!("key{:03}", i), format!("value{}", i).as_bytes()).unwrap();
+        }
+        table.flush().unwrap();
+        table.delete("key050").unwrap();
+        table.flush().unwrap();
+        table.insert("active", b"value").unwrap();
+
+        let view = table.read_view();
+        // Neither the active segment nor later writes are seen
+        table.insert("key000", b"changed").unwrap();
+        assert_eq!(view.get("active").unwrap(), None);
+
+        let handles: Vec<_> = (0..4).map(|_| {
+            let view = view.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10 {
+                    assert_eq!(&*view.get("key000").unwrap().unwrap(), b"value0");
+                    assert_eq!(view.get("key050").unwrap(), None);
+                    assert_eq!(view.iter().count(), 99);
+                    let keys: Vec<_> = view.scan("key048", "key053").map(|(key, _)| key).collect();
+                    assert_eq!(keys, ["key048", "key049", "key051", "key052"]);
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
     #[test]
     fn test_compact() {
         let dir = tempdir().unwrap();
@@ -418,11 +3010,11 @@ mod tests {
         table.insert("key2", b"value3").unwrap();
         
         assert_eq!(table.segments.len(), 2);
-        table.compact();
+        table.compact().unwrap();
         
         assert_eq!(&*table.get("key1").unwrap(), b"value2");
         assert_eq!(&*table.get("key2").unwrap(), b"value3");
-        assert!(table.segments.len() >= 1);
+        assert!(!table.segments.is_empty());
     }
 
     #[test]
@@ -434,14 +3026,214 @@ mod tests {
         table.insert("key2", b"value2").unwrap();
         table.insert("filler", &filler()).unwrap();
         
-        table.delete("key1");
+        table.delete("key1").unwrap();
         assert!(table.get("key1").is_none());
-        table.compact();
+        table.compact().unwrap();
         
         assert!(table.get("key1").is_none());
         assert_eq!(&*table.get("key2").unwrap(), b"value2");
     }
 
+    #[test]
+    fn test_deletes_roll_over_segment() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), 64).unwrap();
+        // Every tombstone takes 5 bytes, the 13th one goes past the limit
+        for i in 0..12 {
+            table.delete(&format!("key{:02}", i)).unwrap();
+        }
+        assert!(sst_files(dir.path()).is_empty());
+        table.delete("key12").unwrap();
+        assert_eq!(sst_files(dir.path()), ["13.sst"]);
+        assert_eq!(table.active_segment_size(), 0);
+
+        let reopened = SSTable::try_new(dir.path(), 64).unwrap();
+        assert_eq!(reopened.tombstone_count(), 13);
+        assert_eq!(reopened.latest_serial(), 13);
+    }
+
+    #[test]
+    fn test_compact_removes_obsolete_files() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        for i in 0..4 {
+            table.insert("key", format!("value{}", i).as_bytes()).unwrap();
+            table.insert(&format!("key{}", i), b"value").unwrap();
+            table.flush().unwrap();
+        }
+        table.delete("key0").unwrap();
+        table.flush().unwrap();
+
+        let old_files = sst_files(dir.path());
+        assert_eq!(old_files.len(), 5);
+        table.compact().unwrap();
+
+        // Only the files of the live segments are left, none of the ones compacted away
+        let mut files = sst_files(dir.path());
+        let mut live = table.segments.iter().map(|s| table.naming.file_name(s.serial)).collect::<Vec<_>>();
+        files.sort();
+        live.sort();
+        assert_eq!(files, live);
+        assert!(files.iter().all(|file| !old_files.contains(file)));
+
+        let reopened = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert_eq!(reopened.segments.len(), table.segments.len());
+        assert_eq!(&*reopened.get("key").unwrap(), b"value3");
+        assert!(reopened.get("key0").is_none());
+        assert_eq!(reopened.iter().map(|(key, _)| key).collect::<Vec<_>>(), vec!["key", "key1", "key2", "key3"]);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), 4096).unwrap();
+        table.insert("key00000", b"old").unwrap();
+        table.insert("pending", b"value").unwrap();
+
+        let entries = (0..5000).map(|i| (format!("key{:05}", i), format!("value{}", i).into_bytes()));
+        table.bulk_load(entries).unwrap();
+        assert!(sst_files(dir.path()).len() > 2);
+        assert_eq!(table.get("key00000").unwrap().as_ref(), b"value0");
+        assert_eq!(table.get("key04999").unwrap().as_ref(), b"value4999");
+        assert_eq!(table.get("pending").unwrap().as_ref(), b"value");
+
+        let keys: Vec<String> = table.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys.len(), 5001);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Writes after the load shadow loaded values, also after reopening
+        table.insert("key00001", b"new").unwrap();
+        table.flush().unwrap();
+        let table = SSTable::try_new(dir.path(), 4096).unwrap();
+        assert_eq!(table.get("key00001").unwrap().as_ref(), b"new");
+        assert_eq!(table.get("key00002").unwrap().as_ref(), b"value2");
+
+        let mut table = table;
+        let unsorted = [("b".to_string(), vec![1]), ("a".to_string(), vec![2])];
+        assert!(table.bulk_load(unsorted).is_err());
+    }
+
+    #[test]
+    fn test_compact_dropping_tombstones() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+
+        table.insert("key1", b"value1").unwrap();
+        table.insert("key2", b"value2").unwrap();
+        table.insert("filler", &filler()).unwrap();
+        table.delete("key1").unwrap();
+        table.delete("missing").unwrap();
+
+        table.compact().unwrap();
+        assert!(table.segments.iter().any(|s| s.holds("key1")));
+
+        table.compact_dropping_tombstones().unwrap();
+        assert!(table.get("key1").is_none());
+        assert_eq!(&*table.get("key2").unwrap(), b"value2");
+        for segment in &table.segments {
+            assert_eq!(segment.data.get("key1").unwrap(), None);
+            assert_eq!(segment.data.get("missing").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_merge_segments_keeps_shadowing_tombstones() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+
+        table.insert("key1", b"value1").unwrap();
+        table.insert("key2", b"value2").unwrap();
+        table.insert("filler", &filler()).unwrap();
+        table.delete("key1").unwrap();
+        table.delete("key3").unwrap();
+        table.insert("filler", &filler()).unwrap();
+        assert_eq!(table.segments.len(), 3);
+
+        // The oldest segment still holds key1, so only the tombstone for key3 can go
+        table.merge_segments(1..3, true).unwrap();
+        assert!(table.get("key1").is_none());
+        assert!(table.segments[1..].iter().any(|s| s.data.get("key1").unwrap() == Some(None)));
+        assert!(!table.segments.iter().any(|s| s.holds("key3")));
+    }
+
+    #[test]
+    fn test_leveled_compaction() {
+        let dir = tempdir().unwrap();
+        let strategy = CompactionStrategy::Leveled {
+            level0_segments: 2,
+            base_level_size: 256,
+            level_size_multiplier: 2,
+        };
+        let mut table = SSTable::try_new(dir.path(), 64).unwrap().with_compaction(strategy);
+        let mut expected = BTreeMap::new();
+
+        for round in 0..12 {
+            for i in 0..8 {
+                let key = format!("key{:02}", (round * 5 + i * 3) % 40);
+                let value = format!("value{}-{}", round, i);
+                table.insert(&key, value.as_bytes()).unwrap();
+                expected.insert(key, value.into_bytes().into_boxed_slice());
+            }
+            let deleted = format!("key{:02}", (round * 7) % 40);
+            table.delete(&deleted).unwrap();
+            expected.remove(&deleted);
+
+            table.flush().unwrap();
+            table.compact().unwrap();
+        }
+
+        let max_level = table.segments.iter().map(|s| s.level).max().unwrap();
+        assert!(max_level >= 2);
+        for level in 1..=max_level {
+            let mut bounds: Vec<_> = table.segments.iter()
+                .filter(|s| s.level == level)
+                .map(|s| (s.min_key.clone().unwrap(), s.max_key.clone().unwrap()))
+                .collect();
+            bounds.sort();
+            for pair in bounds.windows(2) {
+                assert!(pair[0].1 < pair[1].0, "level {} overlaps: {:?}", level, pair);
+            }
+        }
+
+        // Levels are ordered from the deepest one so newer data still shadows older data
+        assert!(table.segments.windows(2).all(|pair| pair[0].level >= pair[1].level));
+        assert_eq!(table.iter().collect::<BTreeMap<_, _>>(), expected);
+        for key in (0..40).map(|i| format!("key{:02}", i)) {
+            assert_eq!(table.get(&key), expected.get(&key).cloned());
+        }
+
+        // The stored levels restore the same shadowing order
+        let table = SSTable::try_new(dir.path(), 64).unwrap();
+        assert_eq!(table.iter().collect::<BTreeMap<_, _>>(), expected);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        let later = now_millis() + 3_600_000;
+
+        table.insert("expired", b"old").unwrap();
+        table.insert_with_expiry("expired", b"value", 1).unwrap();
+        table.insert_with_expiry("live", b"value", later).unwrap();
+
+        assert!(table.get("expired").is_none());
+        assert_eq!(&*table.get("live").unwrap(), b"value");
+        assert_eq!(table.get_many(&["expired", "live"]).unwrap()[0], None);
+        assert_eq!(table.iter().map(|(k, _)| k).collect::<Vec<_>>(), vec!["live"]);
+
+        // Expiry times survive writing and reading a segment
+        let mut buffer = Vec::new();
+        SSTable::write_segment(&mut buffer, &table.segments[0], &SegmentWriteOptions::default()).unwrap();
+        let segment = SSTable::read_segment(&mut Cursor::new(&buffer)).unwrap();
+        assert_eq!(segment.data.get("expired").unwrap().unwrap().unwrap().expires_at, 1);
+        assert_eq!(segment.data.get("live").unwrap().unwrap().unwrap().expires_at, later);
+
+        table.compact().unwrap();
+        assert!(table.segments.iter().all(|s| s.data.get("expired").unwrap().is_none()));
+        assert_eq!(&*table.get("live").unwrap(), b"value");
+    }
+
     #[test]
     fn test_write_segment() {
         let dir = tempdir().unwrap();
@@ -450,32 +3242,75 @@ mod tests {
         table.insert("key2", b"value2").unwrap();
         
         let mut cursor = Cursor::new(Vec::new());
-        SSTable::write_segment(&mut cursor, &table.segments[0]).unwrap();
+        SSTable::write_segment(&mut cursor, &table.segments[0], &SegmentWriteOptions::default()).unwrap();
         
         let data = cursor.into_inner();
         
+        // Verify the header
+        assert_eq!(&data[..4], b"KSST");
+        assert_eq!(data[4], 5);
+
         // Verify that "key1" was written correctly
-        let mut pos = 0;
+        let mut pos = 5;
+        assert_eq!(&data[pos..pos+4], &4u32.to_le_bytes());  // key length
+        pos += 4;
         assert_eq!(&data[pos..pos+4], b"key1");  // key
         pos += 4;
-        assert_eq!(data[pos], 0);                // null terminator
+        assert_eq!(&data[pos..pos+8], &6u64.to_le_bytes());  // value length
+        pos += 8;
+        assert_eq!(&data[pos..pos+8], &0u64.to_le_bytes());  // expiry
+        pos += 8;
+        assert_eq!(data[pos], 0);  // codec
         pos += 1;
-        assert_eq!(&data[pos..pos+4], &6u32.to_le_bytes());  // value length
-        pos += 4;
         assert_eq!(&data[pos..pos+6], b"value1"); // value
         pos += 6;
         
         // Verify that "key2" was written correctly
+        assert_eq!(&data[pos..pos+4], &4u32.to_le_bytes());  // key length
+        pos += 4;
         assert_eq!(&data[pos..pos+4], b"key2");  // key
         pos += 4;
-        assert_eq!(data[pos], 0);                // null terminator
+        assert_eq!(&data[pos..pos+8], &6u64.to_le_bytes());  // value length
+        pos += 8;
+        assert_eq!(&data[pos..pos+8], &0u64.to_le_bytes());  // expiry
+        pos += 8;
+        assert_eq!(data[pos], 0);  // codec
         pos += 1;
-        assert_eq!(&data[pos..pos+4], &6u32.to_le_bytes());  // value length
-        pos += 4;
         assert_eq!(&data[pos..pos+6], b"value2"); // value
         
+        // Verify the footer with the key bounds
+        pos += 6;
+        let footer_offset = pos as u64;
+        assert_eq!(&data[pos..pos+4], &u32::MAX.to_le_bytes());  // footer marker
+        pos += 4;
+        assert_eq!(&data[pos..pos+8], &2u64.to_le_bytes());  // serial
+        pos += 8;
+        assert_eq!(&data[pos..pos+4], &0u32.to_le_bytes());  // level
+        pos += 4;
+        assert_eq!(&data[pos..pos+8], &2u64.to_le_bytes());  // entry count
+        pos += 8;
+        assert_eq!(&data[pos..pos+4], &4u32.to_le_bytes());  // min key length
+        pos += 4;
+        assert_eq!(&data[pos..pos+4], b"key1");  // min key
+        pos += 4;
+        assert_eq!(&data[pos..pos+4], &4u32.to_le_bytes());  // max key length
+        pos += 4;
+        assert_eq!(&data[pos..pos+4], b"key2");  // max key
+        pos += 4;
+        assert_eq!(&data[pos..pos+4], &1u32.to_le_bytes());  // index length
+        pos += 4;
+        assert_eq!(&data[pos..pos+4], &4u32.to_le_bytes());  // indexed key length
+        pos += 4;
+        assert_eq!(&data[pos..pos+4], b"key1");  // indexed key
+        pos += 4;
+        assert_eq!(&data[pos..pos+8], &5u64.to_le_bytes());  // indexed key offset
+        pos += 8;
+        assert_eq!(&data[pos..pos+8], &footer_offset.to_le_bytes());  // footer offset
+        pos += 8;
+        assert_eq!(&data[pos..pos+4], &crc32fast::hash(&data[5..pos]).to_le_bytes());  // checksum
+
         // Verify total length is correct
-        assert_eq!(data.len(), 30);
+        assert_eq!(data.len(), 5 + 62 + 68 + 4);
     }
 
     #[test]
@@ -484,22 +3319,22 @@ mod tests {
         let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
         table.insert("key1", b"value1").unwrap();
         table.insert("key2", b"value2").unwrap();
-        table.delete("key3");
+        table.delete("key3").unwrap();
         
         let mut buffer = Vec::new();
         {
             let mut cursor = Cursor::new(&mut buffer);
-            SSTable::write_segment(&mut cursor, &table.segments[0]).unwrap();
+            SSTable::write_segment(&mut cursor, &table.segments[0], &SegmentWriteOptions::default()).unwrap();
         }
         
         let mut cursor = Cursor::new(&buffer);
-        let segment = SSTable::read_segment(&mut cursor, 0).unwrap();
+        let segment = SSTable::read_segment(&mut cursor).unwrap();
         
         // Verify segment contents
         assert_eq!(segment.data.len(), 3);
-        assert_eq!(segment.data.get("key1").unwrap().as_ref().unwrap(), b"value1");
-        assert_eq!(segment.data.get("key2").unwrap().as_ref().unwrap(), b"value2");
-        assert!(segment.data.get("key3").unwrap().is_none());
+        assert_eq!(&*segment.data.get("key1").unwrap().unwrap().unwrap().data, b"value1");
+        assert_eq!(&*segment.data.get("key2").unwrap().unwrap().unwrap().data, b"value2");
+        assert!(segment.data.get("key3").unwrap().unwrap().is_none());
         
         // Verify segment size tracking
         assert_eq!(segment.size, "key1".len() + "value1".len() + 
@@ -510,16 +3345,340 @@ mod tests {
     #[test]
     fn test_read_segment_empty() {
         let mut cursor = Cursor::new(Vec::new());
-        let segment = SSTable::read_segment(&mut cursor, 0).unwrap();
+        let segment = SSTable::read_segment(&mut cursor).unwrap();
         assert_eq!(segment.data.len(), 0);
         assert_eq!(segment.size, 0);
     }
 
+    #[test]
+    fn test_read_segment_key_with_null_byte() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        table.insert("a\0b", b"value1").unwrap();
+
+        let mut buffer = Vec::new();
+        SSTable::write_segment(&mut buffer, &table.segments[0], &SegmentWriteOptions::default()).unwrap();
+        let segment = SSTable::read_segment(&mut Cursor::new(&buffer)).unwrap();
+
+        assert_eq!(segment.data.len(), 1);
+        assert_eq!(&*segment.data.get("a\0b").unwrap().unwrap().unwrap().data, b"value1");
+    }
+
     #[test]
     fn test_read_segment_invalid_utf8() {
-        let invalid_data = vec![0xFF, 0xFF, 0x00];  // Invalid UTF-8 sequence
+        let mut invalid_data = b"KSST\x02".to_vec();
+        invalid_data.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0xFF, 0xFF]);  // Invalid UTF-8 sequence
         let mut cursor = Cursor::new(&invalid_data);
-        assert!(SSTable::read_segment(&mut cursor, 0).is_err());
+        assert!(SSTable::read_segment(&mut cursor).is_err());
+    }
+
+    #[test]
+    fn test_read_segment_checksum_mismatch() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        table.insert("key1", b"value1").unwrap();
+        table.flush().unwrap();
+
+        let file_path = dir.path().join("1.sst");
+        let mut data = fs::read(&file_path).unwrap();
+        data[5 + 4 + 4 + 8 + 8 + 1] ^= 0xFF;  // First byte of the value
+        fs::write(&file_path, data).unwrap();
+
+        let err = SSTable::read_segments(dir.path(), false, &default_naming()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(&err, Error::CorruptSegment { path, reason }
+            if *path == file_path && reason.contains("checksum")));
+    }
+
+    #[test]
+    fn test_read_segment_version_2() {
+        // Version 2 stores value lengths as u32
+        let mut payload = Vec::new();
+        write_string(&mut payload, "key1").unwrap();
+        payload.extend_from_slice(&6u32.to_le_bytes());
+        payload.extend_from_slice(&0u64.to_le_bytes());
+        payload.extend_from_slice(b"value1");
+        let footer_offset = SEGMENT_HEADER_LEN + payload.len() as u64;
+        payload.extend_from_slice(&FOOTER_MARKER.to_le_bytes());
+        payload.extend_from_slice(&1u64.to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        payload.extend_from_slice(&1u64.to_le_bytes());
+        write_string(&mut payload, "key1").unwrap();
+        write_string(&mut payload, "key1").unwrap();
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        write_string(&mut payload, "key1").unwrap();
+        payload.extend_from_slice(&SEGMENT_HEADER_LEN.to_le_bytes());
+        payload.extend_from_slice(&footer_offset.to_le_bytes());
+
+        let dir = tempdir().unwrap();
+        let mut data = b"KSST\x02".to_vec();
+        data.extend_from_slice(&payload);
+        data.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        fs::write(dir.path().join("1.sst"), data).unwrap();
+
+        let table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert_eq!(&*table.get("key1").unwrap(), b"value1");
+        let table = SSTable::try_new_lazy(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert_eq!(&*table.get("key1").unwrap(), b"value1");
+    }
+
+    #[test]
+    #[ignore = "allocates more than 8 GiB"]
+    fn test_value_larger_than_u32() {
+        let dir = tempdir().unwrap();
+        let len = u32::MAX as usize + 1;
+        {
+            let mut table = SSTable::try_new(dir.path(), usize::MAX).unwrap();
+            let mut value = vec![0; len];
+            value[len - 1] = 1;
+            table.insert("key1", &value).unwrap();
+            table.insert("key2", b"value2").unwrap();
+            table.flush().unwrap();
+        }
+
+        let table = SSTable::try_new_lazy(dir.path(), usize::MAX).unwrap();
+        let value = table.get("key1").unwrap();
+        assert_eq!(value.len(), len);
+        assert_eq!(value[len - 1], 1);
+        assert_eq!(&*table.get("key2").unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_read_segment_truncated() {
+        let mut segment = SSTableSegment::new(0);
+        segment.insert("key1".to_string(), Some(Value::new(b"value1".to_vec(), 0)));
+        let mut buffer = Vec::new();
+        SSTable::write_segment(&mut buffer, &segment, &SegmentWriteOptions::default()).unwrap();
+
+        buffer.truncate(buffer.len() - 10);
+        assert!(SSTable::read_segment(&mut Cursor::new(&buffer)).is_err());
+    }
+
+    #[test]
+    fn test_read_segment_length_past_end() {
+        let mut huge_value = b"KSST\x05".to_vec();
+        write_string(&mut huge_value, "key1").unwrap();
+        huge_value.extend_from_slice(&(u64::MAX / 2).to_le_bytes());
+        huge_value.extend_from_slice(&0u64.to_le_bytes());
+        huge_value.push(0);
+        huge_value.extend_from_slice(b"value1");
+
+        let mut huge_key = b"KSST\x05".to_vec();
+        huge_key.extend_from_slice(&(FOOTER_MARKER - 1).to_le_bytes());
+        huge_key.extend_from_slice(b"key1");
+
+        for data in [huge_value, huge_key] {
+            let error = SSTable::read_segment(&mut Cursor::new(&data)).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_read_block_length_past_end() {
+        let mut segment = SSTableSegment::new(1);
+        segment.insert("key1".to_string(), Some(Value::new(b"value1".to_vec(), 0)));
+        let options = SegmentWriteOptions { layout: SegmentLayout::Block, ..Default::default() };
+        let dir = tempdir().unwrap();
+        SSTable::write_segment_file(dir.path(), &segment, &options, &default_naming()).unwrap();
+        let path = dir.path().join("1.sst");
+        let (file_segment, _) = FileSegment::open(&path).unwrap();
+
+        let mut data = fs::read(&path).unwrap();
+        let start = SEGMENT_HEADER_LEN as usize;
+        data[start..start + 4].copy_from_slice(&(FOOTER_MARKER - 1).to_le_bytes());
+        let error = SSTable::read_segment(&mut Cursor::new(&data)).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        fs::write(&path, data).unwrap();
+        assert_eq!(file_segment.get("key1").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_lazy_segments() {
+        let dir = tempdir().unwrap();
+        {
+            let mut table = SSTable::try_new(dir.path(), 64).unwrap();
+            for i in 0..20 {
+                table.insert(&format!("key{:02}", i), format!("value{}", i).as_bytes()).unwrap();
+            }
+            table.delete("key05").unwrap();
+            table.insert("key07", b"updated").unwrap();
+            table.flush().unwrap();
+        }
+
+        let table = SSTable::try_new_lazy(dir.path(), 64).unwrap();
+        assert!(table.segments.len() > 1);
+        assert!(table.segments.iter().all(|s| matches!(s.data, SegmentStore::File(_))));
+        assert_eq!(table.latest_serial(), 22);
+
+        assert_eq!(table.try_get("key00").unwrap().as_deref(), Some(&b"value0"[..]));
+        assert_eq!(table.try_get("key19").unwrap().as_deref(), Some(&b"value19"[..]));
+        assert_eq!(table.try_get("key07").unwrap().as_deref(), Some(&b"updated"[..]));
+        assert_eq!(table.try_get("key05").unwrap(), None);
+        assert_eq!(table.try_get("missing").unwrap(), None);
+
+        let eager = SSTable::try_new(dir.path(), 64).unwrap();
+        assert_eq!(table.iter().collect::<Vec<_>>(), eager.iter().collect::<Vec<_>>());
+        assert_eq!(table.scan("key10", "key15").count(), 5);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mapped_segments() {
+        let dir = tempdir().unwrap();
+        {
+            let mut table = SSTable::try_new(dir.path(), 64).unwrap().with_layout(SegmentLayout::Block);
+            for i in 0..20 {
+                table.insert(&format!("key{:02}", i), format!("value{}", i).as_bytes()).unwrap();
+            }
+            table.delete("key05").unwrap();
+            table.flush().unwrap();
+        }
+
+        let mut table = SSTable::try_new_mapped(dir.path(), 64).unwrap();
+        assert!(table.segments.iter().all(|s| matches!(&s.data, SegmentStore::File(f) if f.map.is_some())));
+        assert_eq!(table.try_get("key00").unwrap().as_deref(), Some(&b"value0"[..]));
+        assert_eq!(table.try_get("key19").unwrap().as_deref(), Some(&b"value19"[..]));
+        assert_eq!(table.try_get("key05").unwrap(), None);
+        assert_eq!(table.get_many(&["key01", "missing"]).unwrap(), vec![Some(Box::from(&b"value1"[..])), None]);
+        let eager = SSTable::try_new(dir.path(), 64).unwrap();
+        assert_eq!(table.iter().collect::<Vec<_>>(), eager.iter().collect::<Vec<_>>());
+
+        // Compaction removes the files, a snapshot keeps reading their mappings
+        let files = sst_files(dir.path());
+        let snapshot = table.snapshot();
+        table.compact().unwrap();
+        assert!(sst_files(dir.path()).iter().all(|file| !files.contains(file)));
+        assert_eq!(snapshot.get("key02").unwrap().as_deref(), Some(&b"value2"[..]));
+        assert_eq!(snapshot.iter().count(), 19);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel_compaction() {
+        // Overlapping keys across segments, with tombstones, expired values and merge operands
+        let fill = |path: &Path| {
+            let mut table = SSTable::try_new(path, 512).unwrap().with_merge_fn(sum_merge());
+            for round in 0..5u64 {
+                for i in (round..1000).step_by(1 + round as usize) {
+                    table.insert(&format!("key{:04}", i), &round.to_le_bytes()).unwrap();
+                }
+                for i in (0..1000).step_by(7 + round as usize) {
+                    table.delete(&format!("key{:04}", i)).unwrap();
+                }
+                table.insert_with_expiry(&format!("expired{}", round), b"gone", 1).unwrap();
+                table.merge(&format!("count{}", round % 2), &round.to_le_bytes()).unwrap();
+            }
+            table.flush().unwrap();
+        };
+        let serial_dir = tempdir().unwrap();
+        let parallel_dir = tempdir().unwrap();
+        fill(serial_dir.path());
+        fill(parallel_dir.path());
+
+        let mut serial = SSTable::try_new(serial_dir.path(), 512).unwrap().with_merge_fn(sum_merge());
+        let mut parallel = SSTable::try_new_lazy(parallel_dir.path(), 512).unwrap()
+            .with_merge_fn(sum_merge())
+            .with_compaction_shards(8);
+        assert_eq!(serial.compact().unwrap(), parallel.compact().unwrap());
+        assert_eq!(serial.compact_dropping_tombstones().unwrap(), parallel.compact_dropping_tombstones().unwrap());
+
+        let mut files = sst_files(serial_dir.path());
+        files.sort();
+        assert!(files.len() > 8);
+        assert_eq!(files, { let mut f = sst_files(parallel_dir.path()); f.sort(); f });
+        for file in files {
+            assert_eq!(fs::read(serial_dir.path().join(&file)).unwrap(), fs::read(parallel_dir.path().join(&file)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_lazy_segment_checksum_mismatch() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        table.insert("key1", b"value1").unwrap();
+        table.flush().unwrap();
+
+        let path = dir.path().join("1.sst");
+        let mut data = fs::read(&path).unwrap();
+        data[5 + 4 + 4 + 4] ^= 0xFF;
+        fs::write(&path, data).unwrap();
+
+        assert!(SSTable::read_segments(dir.path(), true, &default_naming()).is_err());
+    }
+
+    // Counts the bytes read from the wrapped reader
+    struct CountingReader<R> {
+        inner: R,
+        read: usize,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.inner.read(buf)?;
+            self.read += read;
+            Ok(read)
+        }
+    }
+
+    impl<R: Seek> Seek for CountingReader<R> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_get_from_file_uses_index() {
+        let dir = tempdir().unwrap();
+        let mut segment = SSTableSegment::new(1000);
+        for i in 0..1000 {
+            segment.insert(format!("key{:04}", i), Some(Value::new(format!("value{:04}", i).into_bytes(), 0)));
+        }
+        let options = SegmentWriteOptions { index_interval: 10, ..Default::default() };
+        SSTable::write_segment_file(dir.path(), &segment, &options, &default_naming()).unwrap();
+        let path = dir.path().join("1000.sst");
+        let (file_segment, _) = FileSegment::open(&path).unwrap();
+        assert_eq!(file_segment.index.len(), 100);
+
+        // Every entry takes 4 + 7 + 8 + 8 + 1 + 9 bytes, so at most 10 entries are scanned
+        let entry_size = 37;
+        for key in ["key0000", "key0500", "key0509", "key0999"] {
+            let mut reader = CountingReader { inner: File::open(&path).unwrap(), read: 0 };
+            let value = get_from_file(&mut reader, file_segment.version, &file_segment.index, key).unwrap();
+            assert_eq!(value, Some(Some(Value::new(key.replace("key", "value").into_bytes(), 0))));
+            assert!(reader.read <= 10 * entry_size);
+        }
+
+        let mut reader = CountingReader { inner: File::open(&path).unwrap(), read: 0 };
+        assert_eq!(get_from_file(&mut reader, file_segment.version, &file_segment.index, "key0500a").unwrap(), None);
+        assert!(reader.read <= 10 * entry_size);
+        assert_eq!(get_from_file(&mut reader, file_segment.version, &file_segment.index, "a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_restores_stored_serials() {
+        let dir = tempdir().unwrap();
+
+        // Serials that can't be derived from the number of keys in each segment
+        let mut older = SSTableSegment::new(0);
+        older.insert("key1".to_string(), Some(Value::new(b"old".to_vec(), 0)));
+        older.serial = 7;
+        let mut newer = SSTableSegment::new(0);
+        newer.insert("a".to_string(), Some(Value::new(b"a".to_vec(), 0)));
+        newer.insert("b".to_string(), Some(Value::new(b"b".to_vec(), 0)));
+        newer.insert("key1".to_string(), Some(Value::new(b"new".to_vec(), 0)));
+        newer.serial = 10;
+        SSTable::write_segment_file(dir.path(), &newer, &SegmentWriteOptions::default(), &default_naming()).unwrap();
+        SSTable::write_segment_file(dir.path(), &older, &SegmentWriteOptions::default(), &default_naming()).unwrap();
+
+        for lazy in [false, true] {
+            let segments = SSTable::read_segments(dir.path(), lazy, &default_naming()).unwrap();
+            assert_eq!(segments.iter().map(|s| s.serial).collect::<Vec<_>>(), vec![7, 10]);
+        }
+
+        let table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert_eq!(&*table.get("key1").unwrap(), b"new");
+        assert_eq!(table.latest_serial(), 10);
     }
 
     #[test]
@@ -530,15 +3689,12 @@ mod tests {
         table.insert("key2", b"value2").unwrap();
         table.insert("filler", &filler()).unwrap();
         table.insert("key3", b"value3").unwrap();
-        table.delete("key2");
+        table.delete("key2").unwrap();
         
         table.write(dir.path()).unwrap();
 
         // Verify file names match segment serials
-        let mut files: Vec<_> = fs::read_dir(dir.path()).unwrap()
-            .filter_map(|e| e.ok())
-            .map(|e| e.file_name().to_string_lossy().into_owned())
-            .collect();
+        let mut files = sst_files(dir.path());
 
         // Parse and sort by numeric serial
         files.sort_by_key(|name| {
@@ -581,8 +3737,252 @@ mod tests {
 
     #[test]
     fn test_read_invalid_path() {
-        let result = SSTable::read(Path::new("/nonexistent/path"));
-        assert!(result.is_err());
+        let result = SSTable::read_segments(Path::new("/nonexistent/path"), false, &default_naming());
+        assert!(matches!(result, Err(Error::NotADirectory(_))));
+    }
+
+    #[test]
+    fn test_padded_file_naming() {
+        let dir = tempdir().unwrap();
+        let naming = FileNaming::new("seg", 6).unwrap();
+        {
+            let mut table = SSTable::try_new_with_naming(dir.path(), SEGMENT_SIZE_LIMIT, naming.clone()).unwrap();
+            // Serials 9 and 10 sort the other way around as unpadded names
+            for i in 1..=10 {
+                table.insert("key", format!("value{}", i).as_bytes()).unwrap();
+                table.insert(&format!("key{}", i), b"value").unwrap();
+                table.flush().unwrap();
+            }
+        }
+        let mut files: Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".seg"))
+            .collect();
+        files.sort();
+        let serials: Vec<_> = files.iter().map(|name| naming.parse(name).unwrap()).collect();
+        assert_eq!(files.len(), 10);
+        assert!(serials.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(files[0], "000002.seg");
+
+        let mut table = SSTable::try_new_with_naming(dir.path(), SEGMENT_SIZE_LIMIT, naming.clone()).unwrap();
+        assert_eq!(&*table.get("key").unwrap(), b"value10");
+        assert_eq!(table.iter().count(), 11);
+        assert!(table.segments.windows(2).all(|w| w[0].serial < w[1].serial));
+        assert!(table.file_size().unwrap() > 0);
+
+        table.compact().unwrap();
+        let files: Vec<_> = fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != MANIFEST_FILE)
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert!(naming.parse(&files[0]).is_some());
+        drop(table);
+
+        // Opened with another naming, the segments are unknown files
+        assert!(SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).is_err());
+    }
+
+    #[test]
+    fn test_manifest_ignores_stray_segments() {
+        let dir = tempdir().unwrap();
+        {
+            let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+            table.insert("key1", b"value1").unwrap();
+            table.flush().unwrap();
+        }
+        let manifest = fs::read_to_string(dir.path().join(MANIFEST_FILE)).unwrap();
+        assert_eq!(manifest, "version 1\nserial 1\nsegments 1\n");
+
+        // A valid segment file the manifest doesn't know about
+        let mut stray = SSTableSegment::new(5);
+        stray.insert("stray".to_string(), Some(Value::new(b"value".to_vec(), 0)));
+        SSTable::write_segment_file(dir.path(), &stray, &SegmentWriteOptions::default(), &default_naming()).unwrap();
+
+        for table in [
+            SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap(),
+            SSTable::try_new_lazy(dir.path(), SEGMENT_SIZE_LIMIT).unwrap(),
+        ] {
+            assert!(table.get("stray").is_none());
+            assert_eq!(&*table.get("key1").unwrap(), b"value1");
+            assert_eq!(table.latest_serial(), 1);
+        }
+
+        // Segments written at the stray serial replace it
+        {
+            let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+            for i in 2..=6 {
+                table.insert(&format!("key{}", i), b"value").unwrap();
+                table.flush().unwrap();
+            }
+            assert!(table.latest_serial() > 5);
+        }
+        let table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert!(table.get("stray").is_none());
+        assert_eq!(&*table.get("key5").unwrap(), b"value");
+        drop(table);
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        table.compact().unwrap();
+        drop(table);
+        let table = SSTable::try_new_lazy(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert!(table.get("stray").is_none());
+        for i in 1..=6 {
+            assert!(table.get(&format!("key{}", i)).is_some());
+        }
+
+        fs::write(dir.path().join(MANIFEST_FILE), "version 0\n").unwrap();
+        assert!(matches!(SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT), Err(Error::CorruptManifest { .. })));
+    }
+
+    #[test]
+    fn test_manifest_keeps_serial_without_segments() {
+        let dir = tempdir().unwrap();
+        {
+            let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+            table.insert("key1", b"value1").unwrap();
+            table.delete("key1").unwrap();
+            table.compact_dropping_tombstones().unwrap();
+            assert!(sst_files(dir.path()).is_empty());
+        }
+
+        let table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert_eq!(table.latest_serial(), 2);
+    }
+
+    #[test]
+    fn test_read_skips_temporary_segment_files() {
+        let dir = tempdir().unwrap();
+        let mut segment = SSTableSegment::new(1);
+        segment.insert("key1".to_string(), Some(Value::new(b"value1".to_vec(), 0)));
+        SSTable::write_segment_file(dir.path(), &segment, &SegmentWriteOptions::default(), &default_naming()).unwrap();
+        assert_eq!(sst_files(dir.path()), ["1.sst"]);
+
+        // A segment write interrupted halfway
+        let mut partial = Vec::new();
+        SSTable::write_segment(&mut partial, &SSTableSegment::new(2), &SegmentWriteOptions::default()).unwrap();
+        fs::write(dir.path().join("2.sst.tmp"), &partial[..partial.len() / 2]).unwrap();
+
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert_eq!(&*table.get("key1").unwrap(), b"value1");
+
+        table.compact().unwrap();
+        assert!(!dir.path().join("2.sst.tmp").exists());
+    }
+
+    #[test]
+    fn test_first_and_last_key() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        assert_eq!(table.first_key(), None);
+        assert_eq!(table.last_key(), None);
+
+        table.insert("b", b"1").unwrap();
+        table.insert("d", &filler()).unwrap();
+        table.insert("a", b"2").unwrap();
+        table.insert("c", b"3").unwrap();
+        assert!(table.segments.len() > 1);
+        assert_eq!(table.first_key().as_deref(), Some("a"));
+        assert_eq!(table.last_key().as_deref(), Some("d"));
+
+        table.delete("a").unwrap();
+        table.delete("d").unwrap();
+        assert_eq!(table.first_key().as_deref(), Some("b"));
+        assert_eq!(table.last_key().as_deref(), Some("c"));
+
+        table.delete("b").unwrap();
+        table.delete("c").unwrap();
+        assert_eq!(table.first_key(), None);
+        assert_eq!(table.last_key(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_zstd_compression_round_trip() {
+        let dir = tempdir().unwrap();
+        let value = br#"{"name": "khimera", "tags": ["a", "b"]}"#.repeat(100);
+        {
+            let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap()
+                .with_compression(Some(Codec::Zstd));
+            for i in 0..10 {
+                table.insert(&format!("key{}", i), &value).unwrap();
+            }
+            table.insert("short", b"x").unwrap();
+            table.flush().unwrap();
+        }
+
+        let file_size = fs::metadata(dir.path().join("11.sst")).unwrap().len();
+        assert!(file_size < (10 * value.len()) as u64 / 5);
+
+        for table in [
+            SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap(),
+            SSTable::try_new_lazy(dir.path(), SEGMENT_SIZE_LIMIT).unwrap(),
+        ] {
+            for i in 0..10 {
+                assert_eq!(&*table.get(&format!("key{}", i)).unwrap(), &value[..]);
+            }
+            assert_eq!(&*table.get("short").unwrap(), b"x");
+        }
+    }
+
+    #[test]
+    fn test_block_layout() {
+        let mut segment = SSTableSegment::new(0);
+        for i in 0..1000 {
+            segment.insert(format!("user:{:06}", i), Some(Value::new(format!("{}", i).into_bytes(), 0)));
+        }
+        segment.delete("user:000500".to_string());
+
+        let block_options = SegmentWriteOptions { layout: SegmentLayout::Block, ..Default::default() };
+        let mut flat = Vec::new();
+        SSTable::write_segment(&mut flat, &segment, &SegmentWriteOptions::default()).unwrap();
+        let mut block = Vec::new();
+        SSTable::write_segment(&mut block, &segment, &block_options).unwrap();
+        assert_eq!(&block[..4], b"KSSB");
+        assert!(block.len() < flat.len());
+
+        let read = SSTable::read_segment(&mut Cursor::new(&block)).unwrap();
+        assert_eq!(read.serial, segment.serial);
+        let errors = ReadErrors::default();
+        assert!(read.data.iter(&errors).eq(segment.data.iter(&errors)));
+
+        let dir = tempdir().unwrap();
+        SSTable::write_segment_file(dir.path(), &segment, &block_options, &default_naming()).unwrap();
+        let (file_segment, _) = FileSegment::open(&dir.path().join(format!("{}.sst", segment.serial))).unwrap();
+        assert!(file_segment.index.len() > 1);
+        for key in ["user:000000", "user:000015", "user:000016", "user:000999"] {
+            assert_eq!(file_segment.get(key).unwrap(), segment.data.get(key).unwrap());
+        }
+        assert_eq!(file_segment.get("user:000500").unwrap(), Some(None));
+        assert_eq!(file_segment.get("user:0005000").unwrap(), None);
+        assert_eq!(file_segment.get("a").unwrap(), None);
+        assert_eq!(file_segment.get("z").unwrap(), None);
+
+        let bounds = (Bound::Included("user:000100".to_string()), Bound::Excluded("user:000600".to_string()));
+        assert!(file_segment.range(bounds.clone(), &errors).eq(segment.data.range(bounds, &errors)));
+        errors.check().unwrap();
+    }
+
+    #[test]
+    fn test_table_with_block_layout() {
+        let dir = tempdir().unwrap();
+        {
+            let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap()
+                .with_layout(SegmentLayout::Block);
+            for i in 0..100 {
+                table.insert(&format!("key{:03}", i), b"value").unwrap();
+            }
+            table.delete("key050").unwrap();
+            table.flush().unwrap();
+        }
+
+        for table in [
+            SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap(),
+            SSTable::try_new_lazy(dir.path(), SEGMENT_SIZE_LIMIT).unwrap(),
+        ] {
+            assert_eq!(&*table.get("key042").unwrap(), b"value");
+            assert!(table.get("key050").is_none());
+            assert_eq!(table.iter().count(), 99);
+        }
     }
 
     #[test]
@@ -592,11 +3992,80 @@ mod tests {
         // Create corrupted segment file
         fs::write(
             dir.path().join("0.sst"),
-            &[0xFF, 0xFF, 0xFF] // Invalid data
+            [0xFF, 0xFF, 0xFF] // Invalid data
         ).unwrap();
         
-        let result = SSTable::read(dir.path());
+        let result = SSTable::read_segments(dir.path(), false, &default_naming());
         assert!(result.is_err());
+
+        for result in [
+            SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT),
+            SSTable::try_new_lazy(dir.path(), SEGMENT_SIZE_LIMIT),
+        ] {
+            match result {
+                Err(Error::CorruptSegment { path, .. }) => assert_eq!(path, dir.path().join("0.sst")),
+                _ => panic!("expected a corrupt segment error"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_fails_on_unreadable_segment() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        for i in 0..100 {
+            table.insert(&format!("key{:03}", i), b"value").unwrap();
+        }
+        table.flush().unwrap();
+        drop(table);
+
+        let mut table = SSTable::try_new_lazy(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        let mut files = sst_files(dir.path());
+        files.sort();
+
+        // Make the length of a key in the middle of the segment run past its end
+        let path = dir.path().join(&files[0]);
+        let mut data = fs::read(&path).unwrap();
+        let position = data.windows(6).position(|w| w == b"key050").unwrap();
+        data[position - 4..position].copy_from_slice(&[0x7F; 4]);
+        fs::write(&path, data).unwrap();
+
+        assert!(table.compact().is_err());
+        assert!(table.compact_dropping_tombstones().is_err());
+        let mut after = sst_files(dir.path());
+        after.sort();
+        assert_eq!(after, files);
+        assert_eq!(&*table.get("key010").unwrap(), b"value");
+        assert!(table.try_iter().last().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_newer_format_version_is_refused() {
+        let dir = tempdir().unwrap();
+        let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
+        table.insert("key1", b"value1").unwrap();
+        table.flush().unwrap();
+        drop(table);
+
+        let path = dir.path().join(&sst_files(dir.path())[0]);
+        let mut data = fs::read(&path).unwrap();
+        data[4] = SEGMENT_FORMAT_VERSION + 1;
+        fs::write(&path, data).unwrap();
+        let expected = (SEGMENT_FORMAT_VERSION as u64 + 1, SEGMENT_FORMAT_VERSION as u64);
+        for result in [
+            SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT),
+            SSTable::try_new_lazy(dir.path(), SEGMENT_SIZE_LIMIT),
+        ] {
+            match result {
+                Err(Error::UnsupportedVersion { found, supported }) => assert_eq!((found, supported), expected),
+                _ => panic!("expected an unsupported version error"),
+            }
+        }
+
+        let manifest = fs::read_to_string(dir.path().join(MANIFEST_FILE)).unwrap();
+        fs::write(dir.path().join(MANIFEST_FILE), manifest.replacen("version 1", "version 2", 1)).unwrap();
+        assert!(matches!(SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT),
+            Err(Error::UnsupportedVersion { found: 2, supported: 1 })));
     }
 
     #[test]
@@ -612,7 +4081,7 @@ mod tests {
         table.write(dir.path()).unwrap();
         
         // Verify only one file exists
-        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(sst_files(dir.path()).len(), 1);
     }
 
     #[test]
@@ -621,7 +4090,7 @@ mod tests {
         let mut table = SSTable::try_new(dir.path(), SEGMENT_SIZE_LIMIT).unwrap();
         
         // No files initially
-        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert!(sst_files(dir.path()).is_empty());
         
         // Fill first segment
         table.insert("key1", &filler()).unwrap();
@@ -630,10 +4099,7 @@ mod tests {
         table.insert("key2", b"value2").unwrap();
         
         // Verify file was written
-        let files: Vec<_> = fs::read_dir(dir.path()).unwrap()
-            .filter_map(|e| e.ok())
-            .map(|e| e.file_name().to_string_lossy().into_owned())
-            .collect();
+        let files = sst_files(dir.path());
         
         assert_eq!(files.len(), 1);
         assert_eq!(files[0], format!("{}.sst", table.segments[0].serial));
@@ -1,73 +1,929 @@
-use std::{cell::RefCell, io, path::Path};
+use std::{cell::RefCell, collections::VecDeque, io::{BufReader, BufWriter, Read, Write}, path::Path, sync::{Arc, Mutex, PoisonError}, time::{Duration, Instant}};
 
+pub mod bloom;
+mod column_family;
+mod error;
+mod json;
 pub mod kv;
 pub mod log;
+mod naming;
+mod shared;
 pub mod streams;
+#[cfg(feature = "serde")]
+pub mod typed;
+
+pub use column_family::ColumnFamily;
+use column_family::COLUMN_FAMILY_MARKER;
+pub use error::{Error, Result};
+pub use naming::FileNaming;
+pub use shared::{CompactionHandle, CompactionPolicy, SharedEngine};
+#[cfg(feature = "serde")]
+pub use typed::TypedEngine;
 
 pub struct SSTEngine {
     kv: kv::SSTable,
-    log: log::Log<streams::FileSegmentStream>,
+    // Locked by readers going through &self such as get_at, iter_after_serial and stats, paths
+    // with &mut self use get_mut
+    log: Mutex<log::Log<streams::FileSegmentStream>>,
+    log_sync: LogSync,
+    fsync: bool,
+    max_key_len: usize,
+    // Operations and bytes appended to the log since it was last synced
+    unsynced_operations: usize,
+    unsynced_bytes: usize,
+    last_sync: Instant,
+    // Log entries are framed here, so appending an operation doesn't allocate
+    log_buffer: Vec<u8>,
+    metrics: Option<Arc<dyn kv::Metrics>>,
+    read_only: bool,
+}
+
+// Capacity up to which the log entry buffer is kept after a large entry
+const LOG_BUFFER_RETAIN: usize = 64 * 1024;
+
+// Default limit of the key length in bytes
+pub const DEFAULT_MAX_KEY_LEN: usize = 64 * 1024;
+
+// Default size threshold in bytes at which a log segment file is rolled over
+pub const DEFAULT_LOG_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+// Settings for opening an SSTEngine
+#[derive(Debug, Clone)]
+pub struct SSTEngineOptions {
+    // Size threshold in bytes at which the active data segment is rolled over
+    pub data_segment_size: usize,
+    // Size threshold in bytes at which a log segment file is rolled over
+    pub log_segment_size: u64,
+    // Store a CRC32 with every log entry. Must match the setting the log was written with.
+    pub checksum: bool,
+    pub log_sync: LogSync,
+    // Have log syncs reach the disk. Without it they only reach the OS, which is faster but
+    // loses the operations to a power loss.
+    pub fsync: bool,
+    // Codec of values in written data segment files
+    pub compression: Option<kv::Codec>,
+    // Combines merged operands with the value of their key, required by merge
+    pub merge_fn: Option<kv::MergeFn>,
+    // Longest key in bytes that writes accept
+    pub max_key_len: usize,
+    // Called back by the engine and its table, see kv::Metrics
+    pub metrics: Option<Arc<dyn kv::Metrics>>,
+    // Open an existing store without ever writing to its directory, failing every write
+    pub read_only: bool,
+    // Names of the data segment files and of the log segment files. A store must always be
+    // opened with the names it was written with.
+    pub data_file_naming: FileNaming,
+    pub log_file_naming: FileNaming,
+}
+
+// When appended log entries are synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogSync {
+    // Sync after every operation before it is applied
+    EveryOperation,
+    // Sync once enough operations or bytes were appended or enough time passed since the last
+    // sync, checked on every append. Operations not synced yet can be lost if the machine crashes.
+    // usize::MAX leaves out the bytes threshold.
+    GroupCommit {
+        max_operations: usize,
+        max_bytes: usize,
+        max_delay: Duration,
+    },
+}
+
+impl Default for SSTEngineOptions {
+    fn default() -> Self {
+        SSTEngineOptions {
+            data_segment_size: kv::DEFAULT_MAX_SEGMENT_SIZE,
+            log_segment_size: DEFAULT_LOG_SEGMENT_SIZE,
+            checksum: false,
+            log_sync: LogSync::EveryOperation,
+            fsync: true,
+            compression: None,
+            merge_fn: None,
+            max_key_len: DEFAULT_MAX_KEY_LEN,
+            metrics: None,
+            read_only: false,
+            data_file_naming: FileNaming::unpadded(kv::SEGMENT_EXTENSION),
+            log_file_naming: FileNaming::unpadded(streams::SEGMENT_EXTENSION),
+        }
+    }
+}
+
+impl SSTEngineOptions {
+    pub fn open(self, path: &Path) -> Result<SSTEngine> {
+        let data_path = path.join("data");
+        if self.read_only && !data_path.is_dir() {
+            return Err(Error::NotADirectory(data_path));
+        }
+        // A read-only table never fills its active segment, replaying the log writes nothing
+        let data_segment_size = if self.read_only { usize::MAX } else { self.data_segment_size };
+        let mut kv = kv::SSTable::try_new_with_naming(&data_path, data_segment_size, self.data_file_naming)?
+            .with_compression(self.compression);
+        if let Some(merge_fn) = self.merge_fn {
+            kv = kv.with_merge_fn(merge_fn);
+        }
+        if let Some(metrics) = &self.metrics {
+            kv = kv.with_metrics(metrics.clone());
+        }
+        let file_segment_stream = streams::FileSegmentStream::open(path.join("log"), self.log_segment_size,
+            self.read_only, self.log_file_naming)?;
+        let log = if self.checksum {
+            log::Log::new_with_checksum(RefCell::new(file_segment_stream))
+        } else {
+            log::Log::new(RefCell::new(file_segment_stream))
+        };
+        let mut engine = SSTEngine {
+            kv,
+            log: Mutex::new(log),
+            log_sync: self.log_sync,
+            fsync: self.fsync,
+            max_key_len: self.max_key_len,
+            unsynced_operations: 0,
+            unsynced_bytes: 0,
+            log_buffer: Vec::new(),
+            metrics: self.metrics,
+            read_only: self.read_only,
+            last_sync: Instant::now(),
+        };
+        engine.replay_log()?;
+        Ok(engine)
+    }
+
+    // Create a store at path, which must not hold one yet, from an archive written by checkpoint
+    // and open it with these options
+    pub fn restore<R: Read>(self, path: &Path, reader: R) -> Result<SSTEngine> {
+        let serial = kv::SSTable::restore_archive(path.join("data").as_path(), reader,
+            &self.data_file_naming, self.compression)?;
+        let mut engine = self.open(path)?;
+        engine.kv.advance_serial(serial);
+        Ok(engine)
+    }
+}
+
+// Figures describing the state of an engine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EngineStats {
+    // Entries of all segments minus their tombstones. A key overwritten in a newer segment is
+    // counted for each of them, so this overestimates until compaction merges them.
+    pub live_keys: usize,
+    pub tombstones: usize,
+    // Segments holding entries, the active one included
+    pub segment_count: usize,
+    // Key and value bytes of the active segment
+    pub active_segment_bytes: usize,
+    // Size of the segment files
+    pub disk_bytes: u64,
+    // Size of the write-ahead log since its last checkpoint
+    pub wal_bytes: u64,
+    pub latest_serial: u64,
+}
+
+impl std::fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "live_keys~{} tombstones={} segments={} active_segment_bytes={} disk_bytes={} wal_bytes={} latest_serial={}",
+            self.live_keys, self.tombstones, self.segment_count, self.active_segment_bytes,
+            self.disk_bytes, self.wal_bytes, self.latest_serial)
+    }
 }
 
-#[derive(Debug)]
-enum LogOperation {
+// An operation as written to the write-ahead log. The operations of a batch are enclosed by
+// markers, which take the serials of its first and last operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogOperation {
     Insert(String, Vec<u8>),
     Delete(String),
+    BatchBegin,
+    BatchCommit,
+    // Key, value and expiry time in milliseconds since the Unix epoch
+    InsertWithExpiry(String, Vec<u8>, u64),
+    // Key and merge operand
+    Merge(String, Vec<u8>),
 }
+
+// Byte following the serial of a log entry that says which operation it holds. Codes are
+// stored in logs, so they never change and new operations take unused ones.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OperationCode {
+    // Ends the key and the value, never the code of an entry
     Terminator = 0,
+    // Key, terminator, value, terminator
+    Insert = 1,
+    // Key, terminator
+    Delete = 2,
+    // Nothing else, for both markers
+    BatchBegin = 3,
+    BatchCommit = 4,
+    // Key, terminator, expiry time, value, terminator
+    InsertWithExpiry = 5,
+    // Key, terminator, operand, terminator
+    Merge = 6,
+}
+
+impl TryFrom<u8> for OperationCode {
+    type Error = Error;
+
+    fn try_from(code: u8) -> Result<OperationCode> {
+        Ok(match code {
+            0 => OperationCode::Terminator,
+            1 => OperationCode::Insert,
+            2 => OperationCode::Delete,
+            3 => OperationCode::BatchBegin,
+            4 => OperationCode::BatchCommit,
+            5 => OperationCode::InsertWithExpiry,
+            6 => OperationCode::Merge,
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("Unknown operation code {}", code)).into()),
+        })
+    }
+}
+
+// Whether a single operation waits for the log to be synced, regardless of the log sync mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    // Sync the log before the operation is applied
+    Durable,
+    // Leave the operation unsynced until the next sync, it can be lost if the machine crashes
+    Relaxed,
+}
+
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    Put(String, Vec<u8>),
+    Delete(String),
+}
 
 impl SSTEngine {
-    pub fn try_new(path: &Path) -> io::Result<Self> {
-        let kv = kv::SSTable::try_new(path.join("data").as_path(), 1024*1024)?;
-        let file_segment_stream = streams::FileSegmentStream::new(path.join("log"), 1024*1024);
-        let log = log::Log::new(RefCell::new(file_segment_stream));
-        Ok(SSTEngine { kv, log })
+    // Open with the default options
+    pub fn try_new(path: &Path) -> Result<Self> {
+        SSTEngineOptions::default().open(path)
     }
 
-    pub fn get(&self, key: &str) -> io::Result<Option<Box<[u8]>>> {
-        Ok(self.kv.get(key))
+    // Open an existing store for reading, see SSTEngineOptions::read_only
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        SSTEngineOptions { read_only: true, ..Default::default() }.open(path)
     }
 
-    pub fn insert(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
-        self.append_log(LogOperation::Insert(key.to_string(), value.to_vec()), self.kv.latest_serial() + 1)?;
-        self.kv.insert(key, value)
+    pub fn get(&self, key: &str) -> Result<Option<Box<[u8]>>> {
+        self.kv.try_get(key)
     }
 
-    pub fn delete(&mut self, key: &str) -> io::Result<()> {
-        self.append_log(LogOperation::Delete(key.to_string()), self.kv.latest_serial() + 1)?;
-        self.kv.delete(key);
-        Ok(())
+    // Like get, without copying values held in memory
+    pub fn get_shared(&self, key: &str) -> Result<Option<Arc<[u8]>>> {
+        self.kv.get_shared(key)
+    }
+
+    // Value of a key as of a serial. Segments only keep the latest value of every key, so the
+    // write-ahead log is the source of history: the operations it holds are replayed up to the
+    // serial. Once a checkpoint truncated the log, reading a serial before its first operation
+    // fails, as does reading a key written since as of a serial before that write. Serials from
+    // the latest one on read the current value.
+    pub fn get_at(&self, key: &str, serial: u64) -> Result<Option<Box<[u8]>>> {
+        if serial >= self.kv.latest_serial() {
+            return self.get(key);
+        }
+        let log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        let little_endian = log.is_little_endian()?;
+        // An untruncated log holds every operation, so history starts out empty
+        let complete = log.len_bytes()? == log.end()?;
+
+        let mut first_serial = None;
+        // Set by the last write of the key at or before the serial
+        let mut value: Option<Option<Box<[u8]>>> = None;
+        let mut written_later = false;
+        let mut batch: Option<Vec<(u64, LogOperation)>> = None;
+        for entry in &*log {
+            let (op_serial, op) = decode_log_entry(&entry?, little_endian)?;
+            first_serial.get_or_insert(op_serial);
+            let ops = match op {
+                LogOperation::BatchBegin => {
+                    batch = Some(Vec::new());
+                    continue;
+                }
+                LogOperation::BatchCommit => batch.take().unwrap_or_default(),
+                op => match &mut batch {
+                    Some(ops) => {
+                        ops.push((op_serial, op));
+                        continue;
+                    }
+                    None => vec![(op_serial, op)],
+                },
+            };
+            for (op_serial, op) in ops {
+                if operation_key(&op) != Some(key) {
+                    continue;
+                }
+                if op_serial > serial {
+                    written_later = true;
+                    continue;
+                }
+                value = match op {
+                    LogOperation::Merge(_, operand) => match value.take() {
+                        Some(base) => Some(Some(self.merge_operand(base.as_deref(), &operand)?)),
+                        None if complete => Some(Some(self.merge_operand(None, &operand)?)),
+                        // Merged into a value from before the log, known only if it is the latest
+                        None => None,
+                    },
+                    op => Some(written_value(op)),
+                };
+            }
+        }
+
+        if !complete && serial + 1 < first_serial.unwrap_or(self.kv.latest_serial() + 1) {
+            return Err(history_lost(serial));
+        }
+        match value {
+            Some(value) => Ok(value),
+            None if !written_later => self.get(key),
+            None if complete => Ok(None),
+            // The value from before the log starts was overwritten since
+            None => Err(history_lost(serial)),
+        }
     }
 
-    fn append_log(&mut self, op: LogOperation, serial: u64) -> io::Result<()> {
-        let serial_bytes = serial.to_be_bytes();
-        match op {
-            LogOperation::Insert(key, value) => {
-                let key_bytes = key.as_bytes();
-                let mut entry = Vec::with_capacity(serial_bytes.len() + 3 + key_bytes.len() + value.len());
-                entry.extend_from_slice(&serial_bytes);
-                entry.push(OperationCode::Insert as u8);
-                entry.extend_from_slice(key.as_bytes());
-                entry.push(OperationCode::Terminator as u8);
-                entry.extend_from_slice(&value);
-                entry.push(OperationCode::Terminator as u8);
-                self.log.append(&entry)?;
+    // Operations logged with a serial above the given one in log order, for a follower to catch
+    // up from the last serial it applied. Batches come whole once their commit marker is read,
+    // without the markers.
+    // Only operations synced to the log are read, the iterator picks up ones synced later.
+    // Fails if a checkpoint truncated operations after the serial from the log.
+    pub fn iter_after_serial(&self, serial: u64) -> Result<impl Iterator<Item = Result<(u64, LogOperation)>>> {
+        let log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        let little_endian = log.is_little_endian()?;
+        let complete = log.len_bytes()? == log.end()?;
+        let first_serial = match log.into_iter().next() {
+            Some(entry) => Some(decode_log_entry(&entry?, little_endian)?.0),
+            None => None,
+        };
+        if !complete && serial + 1 < first_serial.unwrap_or(self.kv.latest_serial() + 1) {
+            return Err(history_lost(serial));
+        }
+        let mut entries = log.reader()?;
+        drop(log);
+
+        let mut batch: Option<Vec<(u64, LogOperation)>> = None;
+        let mut ready = VecDeque::new();
+        Ok(std::iter::from_fn(move || loop {
+            if let Some(op) = ready.pop_front() {
+                return Some(Ok(op));
             }
-            LogOperation::Delete(key) => {
-                let key_bytes = key.as_bytes();
-                let mut entry = Vec::with_capacity(serial_bytes.len() + 2 + key_bytes.len());
-                entry.extend_from_slice(&serial_bytes);
-                entry.push(OperationCode::Delete as u8);
-                entry.extend_from_slice(key_bytes);
-                entry.push(OperationCode::Terminator as u8);
-                self.log.append(&entry)?;
+            let entry = match entries.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            let (op_serial, op) = match decode_log_entry(&entry, little_endian) {
+                Ok(decoded) => decoded,
+                Err(e) => return Some(Err(e)),
+            };
+            match op {
+                LogOperation::BatchBegin => batch = Some(Vec::new()),
+                LogOperation::BatchCommit => ready.extend(batch.take().unwrap_or_default()),
+                _ if op_serial <= serial => {}
+                op => match &mut batch {
+                    Some(ops) => ops.push((op_serial, op)),
+                    None => ready.push_back((op_serial, op)),
+                },
+            }
+        }))
+    }
+
+    // Parse an entry of the write-ahead log into its serial and operation. Integers follow the
+    // byte order of the log, see log::Log::is_little_endian.
+    pub fn decode_log_entry(entry: &[u8], little_endian: bool) -> Result<(u64, LogOperation)> {
+        decode_log_entry(entry, little_endian)
+    }
+
+    fn merge_operand(&self, existing: Option<&[u8]>, operand: &[u8]) -> Result<Box<[u8]>> {
+        let merge_fn = self.kv.merge_fn()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "No merge function set"))?;
+        Ok(merge_fn.apply(existing, &[operand]).into())
+    }
+
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        self.kv.contains_key(key)
+    }
+
+    // Results follow the order of the keys
+    pub fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<Box<[u8]>>>> {
+        self.kv.get_many(keys)
+    }
+
+    // Live entries in ascending key order, from flushed segments and the active one alike.
+    // Entries of column families are left out here and in the scans.
+    pub fn iter(&self) -> impl Iterator<Item = (String, Box<[u8]>)> + '_ {
+        self.kv.iter().filter(|(key, _)| !key.starts_with(COLUMN_FAMILY_MARKER))
+    }
+
+    // Separate key space stored along with the default one, named by a non-empty name
+    pub fn cf(&mut self, name: &str) -> Result<ColumnFamily<'_>> {
+        ColumnFamily::new(self, name)
+    }
+
+    // Stream the live entries in key order as a JSON array of {"key": ..., "value": ...} objects
+    // with base64 encoded values
+    pub fn export_json<W: Write>(&self, writer: W) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(b"[")?;
+        for (i, (key, value)) in self.iter().enumerate() {
+            writer.write_all(if i == 0 { b"\n" } else { b",\n" })?;
+            json::write_entry(&mut writer, &key, &value)?;
+        }
+        writer.write_all(b"\n]\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Insert the entries of a JSON array as written by export_json
+    pub fn import_json<R: Read>(&mut self, reader: R) -> Result<()> {
+        json::read_objects(BufReader::new(reader), |fields| {
+            let field = |name: &str| fields.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+            let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid exported entry");
+            let key = field("key").ok_or_else(invalid)?;
+            let value = field("value").and_then(json::base64_decode).ok_or_else(invalid)?;
+            self.insert(key, &value)?;
+            Ok(())
+        })
+    }
+
+    // Write a consistent snapshot of the whole store, column families included, as one archive
+    // that restore turns back into a store
+    pub fn checkpoint<W: Write>(&self, writer: W) -> Result<()> {
+        self.snapshot().write_archive(writer)
+    }
+
+    // Create a store at path, which must not hold one yet, from an archive written by checkpoint.
+    // Stores using other than the default options are restored through SSTEngineOptions::restore.
+    pub fn restore<R: Read>(path: &Path, reader: R) -> Result<SSTEngine> {
+        SSTEngineOptions::default().restore(path, reader)
+    }
+
+    // Counts and sizes for monitoring. Tombstones are counted by reading every entry, the rest
+    // comes from segment metadata and file sizes.
+    pub fn stats(&self) -> Result<EngineStats> {
+        let tombstones = self.kv.tombstone_count();
+        let wal_bytes = self.log.lock().unwrap_or_else(PoisonError::into_inner).len_bytes()?;
+        Ok(EngineStats {
+            live_keys: self.kv.entry_count().saturating_sub(tombstones),
+            tombstones,
+            segment_count: self.kv.segment_count(),
+            active_segment_bytes: self.kv.active_segment_size(),
+            disk_bytes: self.kv.file_size()?,
+            wal_bytes,
+            latest_serial: self.kv.latest_serial(),
+        })
+    }
+
+    // Consistent view of the current data that later writes don't affect
+    pub fn snapshot(&self) -> kv::Snapshot {
+        self.kv.snapshot()
+    }
+
+    pub fn scan(&self, start: &str, end: &str) -> Result<impl Iterator<Item = (String, Box<[u8]>)> + '_> {
+        Ok(self.kv.scan(start, end).filter(|(key, _)| !key.starts_with(COLUMN_FAMILY_MARKER)))
+    }
+
+    // Live entries whose key starts with the prefix in ascending key order
+    pub fn scan_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (String, Box<[u8]>)> + 'a {
+        self.kv.scan_prefix(prefix).filter(|(key, _)| !key.starts_with(COLUMN_FAMILY_MARKER))
+    }
+
+    // Returns the value the key had before, if any
+    pub fn insert(&mut self, key: &str, value: &[u8]) -> Result<Option<Box<[u8]>>> {
+        let previous = self.get(key)?;
+        self.log_and_apply(LogOperation::Insert(key.to_string(), value.to_vec()))?;
+        Ok(previous)
+    }
+
+    // Insert unless the key holds the same value already, in which case neither the log nor the
+    // table is written. Returns whether the value was written. A value with an expiry time is
+    // always overwritten.
+    pub fn insert_if_changed(&mut self, key: &str, value: &[u8]) -> Result<bool> {
+        self.check_writable()?;
+        if self.kv.holds_value(key, value)? {
+            return Ok(false);
+        }
+        self.log_and_apply(LogOperation::Insert(key.to_string(), value.to_vec()))?;
+        Ok(true)
+    }
+
+    // Insert with a durability of its own, for writes that need to be synced right away or
+    // can skip it even though the log sync mode says otherwise
+    pub fn insert_with_durability(&mut self, key: &str, value: &[u8], durability: Durability) -> Result<Option<Box<[u8]>>> {
+        let previous = self.get(key)?;
+        let op = LogOperation::Insert(key.to_string(), value.to_vec());
+        let serial = self.kv.next_serial();
+        self.append_operation(&op, serial)?;
+        match durability {
+            Durability::Durable => self.sync()?,
+            Durability::Relaxed => self.unsynced_operations += 1,
+        }
+        apply_operation(&mut self.kv, op, serial)?;
+        Ok(previous)
+    }
+
+    // Insert with the serial an upstream source gave the operation, so operations replayed from
+    // it twice are applied once. Returns false without doing anything for a serial the table
+    // already reached. The table is at the serial afterwards.
+    pub fn insert_with_serial(&mut self, key: &str, value: &[u8], serial: u64) -> Result<bool> {
+        if serial <= self.kv.latest_serial() {
+            return Ok(false);
+        }
+        self.log_and_apply_at(LogOperation::Insert(key.to_string(), value.to_vec()), serial)?;
+        Ok(true)
+    }
+
+    // Insert a value that reads as absent once the ttl has passed
+    pub fn insert_with_ttl(&mut self, key: &str, value: &[u8], ttl: Duration) -> Result<Option<Box<[u8]>>> {
+        let previous = self.get(key)?;
+        let expires_at = kv::now_millis().saturating_add(ttl.as_millis() as u64);
+        self.log_and_apply(LogOperation::InsertWithExpiry(key.to_string(), value.to_vec(), expires_at))?;
+        Ok(previous)
+    }
+
+    // Returns the removed value, None if the key had no live value
+    pub fn delete(&mut self, key: &str) -> Result<Option<Box<[u8]>>> {
+        let previous = self.get(key)?;
+        self.log_and_apply(LogOperation::Delete(key.to_string()))?;
+        Ok(previous)
+    }
+
+    // Combine an operand with the value of the key through the merge function of the options,
+    // without reading the value first. The operands are applied once the key is read.
+    pub fn merge(&mut self, key: &str, operand: &[u8]) -> Result<()> {
+        if self.kv.merge_fn().is_none() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "No merge function set").into());
+        }
+        self.log_and_apply(LogOperation::Merge(key.to_string(), operand.to_vec()))
+    }
+
+    // Set the key to new, or delete it if new is None, only if its current value equals
+    // expected. An expected None means the key must be absent. A failed swap logs nothing.
+    pub fn compare_and_swap(&mut self, key: &str, expected: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool> {
+        if self.get(key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        let op = match new {
+            Some(value) => LogOperation::Insert(key.to_string(), value.to_vec()),
+            None => LogOperation::Delete(key.to_string()),
+        };
+        self.log_and_apply(op)?;
+        Ok(true)
+    }
+
+    // Apply all operations or none of them, even across a crash
+    pub fn write_batch(&mut self, ops: Vec<BatchOp>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        let ops: Vec<_> = ops.into_iter()
+            .map(|op| match op {
+                BatchOp::Put(key, value) => LogOperation::Insert(key, value),
+                BatchOp::Delete(key) => LogOperation::Delete(key),
+            })
+            .collect();
+        for op in &ops {
+            check_key(op, self.max_key_len)?;
+        }
+        self.check_writable()?;
+
+        // Every operation gets the serial the table reaches by applying it, the markers take
+        // those of the first and last one
+        let first_serial = self.kv.next_serial();
+        let last_serial = first_serial + ops.len() as u64 - 1;
+        let little_endian = self.log().is_little_endian()?;
+        let entries: Vec<_> = std::iter::once((&LogOperation::BatchBegin, first_serial))
+            .chain(ops.iter().zip(first_serial..))
+            .chain(std::iter::once((&LogOperation::BatchCommit, last_serial)))
+            .map(|(op, serial)| encode_log_entry(op, serial, little_endian))
+            .collect();
+        self.log().append_batch(&entries.iter().map(|e| &e[..]).collect::<Vec<_>>())?;
+        self.record_append(entries.iter().map(Vec::len).sum());
+        self.commit_log()?;
+
+        for (op, serial) in ops.into_iter().zip(first_serial..) {
+            apply_operation(&mut self.kv, op, serial)?;
+        }
+
+        Ok(())
+    }
+
+    // Persist the active segment and checkpoint the write-ahead log, whose entries all made it
+    // into written segments by then
+    pub fn flush(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.kv.flush()?;
+        self.sync()?;
+        let checkpoint = self.log().end()?;
+        self.log().truncate_to(checkpoint)
+    }
+
+    // Sync the operations appended to the write-ahead log so far, to the disk or only to the OS
+    // depending on the fsync option
+    pub fn sync(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.fsync {
+            self.log().sync_all()?;
+        } else {
+            self.log().flush()?;
+        }
+        self.unsynced_operations = 0;
+        self.unsynced_bytes = 0;
+        self.last_sync = Instant::now();
+        if let Some(metrics) = &self.metrics {
+            metrics.on_wal_sync();
+        }
+        Ok(())
+    }
+
+    // Merge the data segments into one sorted run and rewrite their files, deleting the ones
+    // merged away. Tombstones have nothing left to shadow afterwards and are dropped.
+    pub fn compact(&mut self) -> Result<kv::CompactionStats> {
+        self.check_writable()?;
+        self.sync()?;
+        self.kv.compact_dropping_tombstones()
+    }
+
+    // A read-only engine has nothing to flush
+    pub fn close(mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.flush()
+    }
+
+    fn log(&mut self) -> &mut log::Log<streams::FileSegmentStream> {
+        self.log.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Re-apply logged operations that did not make it into a persisted segment
+    fn replay_log(&mut self) -> Result<()> {
+        let latest_serial = self.kv.latest_serial();
+        let log = self.log.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let little_endian = log.is_little_endian()?;
+
+        // Operations of a batch are held back until its commit marker is seen,
+        // a batch without one was interrupted and is discarded
+        let mut batch: Option<Vec<(u64, LogOperation)>> = None;
+        for entry in &*log {
+            // Failing to read the log must not pass for its end
+            let (serial, op) = decode_log_entry(&entry?, little_endian)?;
+            match op {
+                LogOperation::BatchBegin => batch = Some(Vec::new()),
+                LogOperation::BatchCommit => {
+                    for (serial, op) in batch.take().unwrap_or_default() {
+                        replay_operation(&mut self.kv, op, serial, latest_serial)?;
+                    }
+                }
+                op => match &mut batch {
+                    Some(ops) => ops.push((serial, op)),
+                    None => replay_operation(&mut self.kv, op, serial, latest_serial)?,
+                },
             }
         }
 
-        self.log.flush()?;
+        Ok(())
+    }
+
+    fn log_and_apply(&mut self, op: LogOperation) -> Result<()> {
+        let serial = self.kv.next_serial();
+        self.log_and_apply_at(op, serial)
+    }
+
+    // Log and apply an operation with a serial above the latest one, which the table is at
+    // afterwards
+    fn log_and_apply_at(&mut self, op: LogOperation, serial: u64) -> Result<()> {
+        self.append_operation(&op, serial)?;
+        self.commit_log()?;
+
+        apply_operation(&mut self.kv, op, serial)
+    }
+
+    fn append_operation(&mut self, op: &LogOperation, serial: u64) -> Result<()> {
+        check_key(op, self.max_key_len)?;
+        self.check_writable()?;
+        let log = self.log.get_mut().unwrap_or_else(PoisonError::into_inner);
+        let little_endian = log.is_little_endian()?;
+        if self.log_buffer.capacity() > LOG_BUFFER_RETAIN {
+            self.log_buffer = Vec::new();
+        }
+        self.log_buffer.clear();
+        write_log_entry(&mut self.log_buffer, op, serial, little_endian);
+        log.append(&self.log_buffer)?;
+        self.record_append(self.log_buffer.len());
+        Ok(())
+    }
+
+    fn record_append(&mut self, bytes: usize) {
+        self.unsynced_bytes += bytes;
+        if let Some(metrics) = &self.metrics {
+            metrics.on_wal_append(bytes);
+        }
+    }
 
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         Ok(())
     }
+
+    // Sync the log after an operation was appended, as often as the log sync mode asks for
+    fn commit_log(&mut self) -> Result<()> {
+        self.unsynced_operations += 1;
+        match self.log_sync {
+            LogSync::EveryOperation => self.sync(),
+            LogSync::GroupCommit { max_operations, max_bytes, max_delay } => {
+                if self.unsynced_operations >= max_operations
+                    || self.unsynced_bytes >= max_bytes
+                    || self.last_sync.elapsed() >= max_delay {
+                    self.sync()
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+// Best-effort flush so a dropped engine keeps its writes. Errors can't be reported from here,
+// call close to handle them.
+impl Drop for SSTEngine {
+    fn drop(&mut self) {
+        if !self.read_only {
+            let _ = self.flush();
+        }
+    }
+}
+
+// Keys are NUL-terminated in the log, so they can't contain NUL bytes themselves
+// Empty keys and keys longer than max_key_len bytes are rejected as well.
+fn check_key(op: &LogOperation, max_key_len: usize) -> Result<()> {
+    match operation_key(op) {
+        Some(key) if key.is_empty() || key.len() > max_key_len || key.contains('\0') => {
+            Err(Error::InvalidKey(key.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Apply a logged operation unless its serial shows a persisted segment has it already. Each
+// operation is logged with the serial the table reaches by applying it, so this holds even for
+// batches a segment rolled over in the middle of.
+fn replay_operation(kv: &mut kv::SSTable, op: LogOperation, serial: u64, latest_serial: u64) -> Result<()> {
+    if serial <= latest_serial {
+        return Ok(());
+    }
+    apply_operation(kv, op, serial)
+}
+
+// The engine gives every operation one serial, above the latest serial of the table, logs it
+// with that serial and stamps the table with it by applying it, so the latest serial of the
+// table is that of the last operation logged. Batch markers share the serials of the
+// operations they enclose and are not applied.
+fn apply_operation(kv: &mut kv::SSTable, op: LogOperation, serial: u64) -> Result<()> {
+    kv.advance_serial(serial - 1);
+    match op {
+        LogOperation::Insert(key, value) => kv.insert(&key, &value)?,
+        LogOperation::Delete(key) => kv.delete(&key)?,
+        LogOperation::InsertWithExpiry(key, value, expires_at) => kv.insert_with_expiry(&key, &value, expires_at)?,
+        LogOperation::Merge(key, operand) => kv.merge(&key, &operand)?,
+        LogOperation::BatchBegin | LogOperation::BatchCommit => {}
+    }
+    Ok(())
+}
+
+fn history_lost(serial: u64) -> Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput,
+        format!("Serial {} is older than the history kept in the log", serial)).into()
+}
+
+fn operation_key(op: &LogOperation) -> Option<&str> {
+    match op {
+        LogOperation::Insert(key, _) | LogOperation::InsertWithExpiry(key, _, _)
+            | LogOperation::Delete(key) | LogOperation::Merge(key, _) => Some(key),
+        LogOperation::BatchBegin | LogOperation::BatchCommit => None,
+    }
+}
+
+// The value an operation other than a merge leaves its key with
+fn written_value(op: LogOperation) -> Option<Box<[u8]>> {
+    match op {
+        LogOperation::Insert(_, value) => Some(value.into()),
+        LogOperation::InsertWithExpiry(_, value, expires_at) => (expires_at > kv::now_millis()).then(|| value.into()),
+        _ => None,
+    }
+}
+
+// Serials and expiry times follow the byte order of the log
+fn encode_u64(value: u64, little_endian: bool) -> [u8; 8] {
+    if little_endian {
+        value.to_le_bytes()
+    } else {
+        value.to_be_bytes()
+    }
+}
+
+fn decode_u64(bytes: &[u8], little_endian: bool) -> Option<u64> {
+    let bytes = bytes.try_into().ok()?;
+    Some(if little_endian { u64::from_le_bytes(bytes) } else { u64::from_be_bytes(bytes) })
+}
+
+fn encode_log_entry(op: &LogOperation, serial: u64, little_endian: bool) -> Vec<u8> {
+    let mut entry = Vec::new();
+    write_log_entry(&mut entry, op, serial, little_endian);
+    entry
+}
+
+// Append the log entry of an operation to a buffer
+fn write_log_entry(entry: &mut Vec<u8>, op: &LogOperation, serial: u64, little_endian: bool) {
+    entry.extend_from_slice(&encode_u64(serial, little_endian));
+    match op {
+        LogOperation::Insert(key, value) | LogOperation::Merge(key, value) => {
+            let code = match op {
+                LogOperation::Merge(..) => OperationCode::Merge,
+                _ => OperationCode::Insert,
+            };
+            entry.reserve(3 + key.len() + value.len());
+            entry.push(code as u8);
+            entry.extend_from_slice(key.as_bytes());
+            entry.push(OperationCode::Terminator as u8);
+            entry.extend_from_slice(value);
+            entry.push(OperationCode::Terminator as u8);
+        }
+        LogOperation::InsertWithExpiry(key, value, expires_at) => {
+            entry.reserve(11 + key.len() + value.len());
+            entry.push(OperationCode::InsertWithExpiry as u8);
+            entry.extend_from_slice(key.as_bytes());
+            entry.push(OperationCode::Terminator as u8);
+            entry.extend_from_slice(&encode_u64(*expires_at, little_endian));
+            entry.extend_from_slice(value);
+            entry.push(OperationCode::Terminator as u8);
+        }
+        LogOperation::Delete(key) => {
+            entry.reserve(2 + key.len());
+            entry.push(OperationCode::Delete as u8);
+            entry.extend_from_slice(key.as_bytes());
+            entry.push(OperationCode::Terminator as u8);
+        }
+        LogOperation::BatchBegin => entry.push(OperationCode::BatchBegin as u8),
+        LogOperation::BatchCommit => entry.push(OperationCode::BatchCommit as u8),
+    }
+}
+
+// Parse an entry written by write_log_entry. Entries that can't be parsed are corrupt.
+fn decode_log_entry(entry: &[u8], little_endian: bool) -> Result<(u64, LogOperation)> {
+    let corrupt = |reason: &str| -> Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Corrupt log entry: {}", reason)).into()
+    };
+    if entry.len() < 9 {
+        return Err(corrupt("too short"));
+    }
+    let (serial_bytes, rest) = entry.split_at(8);
+    let serial = decode_u64(serial_bytes, little_endian).unwrap();
+
+    let (&code, rest) = rest.split_first().unwrap();
+    let code = match OperationCode::try_from(code)? {
+        OperationCode::Terminator => return Err(corrupt("no operation code")),
+        code @ (OperationCode::BatchBegin | OperationCode::BatchCommit) => {
+            if !rest.is_empty() {
+                return Err(corrupt("trailing bytes after a batch marker"));
+            }
+            let marker = if code == OperationCode::BatchBegin { LogOperation::BatchBegin } else { LogOperation::BatchCommit };
+            return Ok((serial, marker));
+        }
+        code => code,
+    };
+
+    let key_end = rest.iter().position(|&b| b == OperationCode::Terminator as u8)
+        .ok_or_else(|| corrupt("unterminated key"))?;
+    let key = String::from_utf8(rest[..key_end].to_vec()).map_err(|_| corrupt("key is not UTF-8"))?;
+    let rest = &rest[key_end + 1..];
+
+    if code == OperationCode::Delete {
+        if !rest.is_empty() {
+            return Err(corrupt("trailing bytes after a deleted key"));
+        }
+        return Ok((serial, LogOperation::Delete(key)));
+    }
+    let (expires_at, rest) = if code == OperationCode::InsertWithExpiry {
+        if rest.len() < 8 {
+            return Err(corrupt("missing expiry time"));
+        }
+        let (expiry_bytes, rest) = rest.split_at(8);
+        (decode_u64(expiry_bytes, little_endian), rest)
+    } else {
+        (None, rest)
+    };
+    let value = match rest.split_last() {
+        Some((&terminator, value)) if terminator == OperationCode::Terminator as u8 => value.to_vec(),
+        _ => return Err(corrupt("unterminated value")),
+    };
+    Ok((serial, match expires_at {
+        Some(expires_at) => LogOperation::InsertWithExpiry(key, value, expires_at),
+        None if code == OperationCode::Merge => LogOperation::Merge(key, value),
+        None => LogOperation::Insert(key, value),
+    }))
 }
 
 #[cfg(test)]
@@ -88,6 +944,14 @@ mod tests {
         assert!(log_dir.is_dir());
     }
 
+    #[test]
+    fn test_engine_invalid_log_path() {
+        let root = tempdir().unwrap();
+        fs::write(root.path().join("log"), b"not a directory").unwrap();
+
+        assert!(SSTEngine::try_new(root.path()).is_err());
+    }
+
     #[test]
     fn test_engine_insert() {
         let root = tempdir().unwrap();
@@ -123,6 +987,45 @@ mod tests {
         assert_eq!(log_files.len(), 1);
     }
 
+    #[test]
+    fn test_engine_contains_key() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+
+        engine.insert("key1", b"value1").unwrap();
+        engine.insert("key2", b"value2").unwrap();
+        engine.delete("key1").unwrap();
+
+        assert!(!engine.contains_key("key1").unwrap());
+        assert!(engine.contains_key("key2").unwrap());
+    }
+
+    #[test]
+    fn test_engine_invalid_key() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+
+        assert!(matches!(engine.insert("a\0b", b"value"), Err(Error::InvalidKey(key)) if key == "a\0b"));
+        assert!(matches!(engine.delete("a\0b"), Err(Error::InvalidKey(_))));
+        let batch = vec![BatchOp::Put("key1".to_string(), b"value1".to_vec()), BatchOp::Delete("\0".to_string())];
+        assert!(matches!(engine.write_batch(batch), Err(Error::InvalidKey(_))));
+        assert!(engine.get("key1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_engine_key_length() {
+        let root = tempdir().unwrap();
+        let options = SSTEngineOptions { max_key_len: 16, ..Default::default() };
+        let mut engine = options.open(root.path()).unwrap();
+
+        assert!(matches!(engine.insert("", b"value"), Err(Error::InvalidKey(key)) if key.is_empty()));
+        engine.insert(&"k".repeat(16), b"value").unwrap();
+        assert!(matches!(engine.insert(&"k".repeat(17), b"value"), Err(Error::InvalidKey(_))));
+        assert!(matches!(engine.cf("cf").unwrap().insert("", b"value"), Err(Error::InvalidKey(_))));
+        assert_eq!(engine.iter().count(), 1);
+        assert_eq!(SSTEngineOptions::default().max_key_len, 64 * 1024);
+    }
+
     #[test]
     fn test_engine_delete_nonexistent() {
         let root = tempdir().unwrap();
@@ -146,7 +1049,7 @@ mod tests {
 
         engine.insert("key1", b"value1").unwrap();
         engine.delete("key1").unwrap();
-        engine.insert("key1", b"value2").unwrap();
+        assert_eq!(engine.insert("key1", b"value2").unwrap(), None);
         
         assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value2");
 
@@ -158,31 +1061,1047 @@ mod tests {
     }
 
     #[test]
-    fn test_engine_segment_overflow() {
+    fn test_engine_returns_previous_value() {
         let root = tempdir().unwrap();
         let mut engine = SSTEngine::try_new(root.path()).unwrap();
 
-        // Create enough data to force segment overflow
-        let large_value = vec![0u8; 1024*1024];
-        engine.insert("key1", &large_value).unwrap();
-        engine.insert("key2", b"value2").unwrap();
+        assert_eq!(engine.insert("key1", b"value1").unwrap(), None);
+        assert_eq!(engine.insert("key1", b"value2").unwrap().as_deref(), Some(&b"value1"[..]));
 
-        // Verify data directory contains segment file
-        let data_files: Vec<_> = fs::read_dir(root.path().join("data")).unwrap()
-            .filter_map(|e| e.ok())
-            .map(|e| e.file_name().to_string_lossy().into_owned())
-            .collect();
-        
-        assert!(!data_files.is_empty());
-        assert!(data_files.iter().any(|f| f.ends_with(".sst")));
+        assert_eq!(engine.delete("key1").unwrap().as_deref(), Some(&b"value2"[..]));
+        assert_eq!(engine.delete("key1").unwrap(), None);
+        assert_eq!(engine.delete("missing").unwrap(), None);
+    }
 
-        // Verify log directory reflects the changes
-        let log_files: Vec<_> = fs::read_dir(root.path().join("log")).unwrap()
-            .filter_map(|e| e.ok())
-            .collect();
-        assert!(log_files.len() >= 1); // Should have at least one log file
-        
-        // Verify data is still accessible
+    fn log_size(root: &Path) -> u64 {
+        fs::read_dir(root.join("log")).unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().len())
+            .sum()
+    }
+
+    #[test]
+    fn test_engine_insert_durability() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        engine.insert_with_durability("key1", b"value1", Durability::Durable).unwrap();
+        let synced = log_size(root.path());
+        assert!(synced > 0);
+
+        engine.insert_with_durability("key2", b"value2", Durability::Relaxed).unwrap();
         assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+        assert_eq!(log_size(root.path()), synced);
+
+        engine.sync().unwrap();
+        assert!(log_size(root.path()) > synced);
+
+        // Syncs without fsync still hand the operations to the OS
+        let root = tempdir().unwrap();
+        let options = SSTEngineOptions { fsync: false, ..Default::default() };
+        let mut engine = options.open(root.path()).unwrap();
+        engine.insert("key1", b"value1").unwrap();
+        assert!(log_size(root.path()) > 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_engine_compare_and_swap() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+
+        // Insert only if absent
+        assert!(engine.compare_and_swap("key1", None, Some(b"value1")).unwrap());
+        assert!(!engine.compare_and_swap("key1", None, Some(b"other")).unwrap());
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value1");
+
+        assert!(engine.compare_and_swap("key1", Some(b"value1"), Some(b"value2")).unwrap());
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value2");
+
+        let size = log_size(root.path());
+        assert!(!engine.compare_and_swap("key1", Some(b"value1"), Some(b"value3")).unwrap());
+        assert!(!engine.compare_and_swap("missing", Some(b"value1"), None).unwrap());
+        assert_eq!(log_size(root.path()), size);
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value2");
+
+        assert!(engine.compare_and_swap("key1", Some(b"value2"), None).unwrap());
+        assert!(engine.get("key1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_engine_insert_with_ttl() {
+        let root = tempdir().unwrap();
+        let crashed = tempdir().unwrap();
+        {
+            let mut engine = SSTEngine::try_new(root.path()).unwrap();
+            engine.insert("key1", b"value1").unwrap();
+            engine.insert_with_ttl("key1", b"expired", Duration::ZERO).unwrap();
+            engine.insert_with_ttl("key2", b"value2", Duration::from_secs(3600)).unwrap();
+
+            assert!(engine.get("key1").unwrap().is_none());
+            assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+
+            // Copied as a crash would leave it, before dropping the engine flushes
+            copy_dir(root.path(), crashed.path());
+        }
+
+        // Expiry times survive a replay of the log
+        let engine = SSTEngine::try_new(crashed.path()).unwrap();
+        assert!(engine.get("key1").unwrap().is_none());
+        assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_engine_segment_overflow() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+
+        // Create enough data to force segment overflow
+        let large_value = vec![0u8; 1024*1024];
+        engine.insert("key1", &large_value).unwrap();
+        engine.insert("key2", b"value2").unwrap();
+
+        // Verify data directory contains segment file
+        let data_files: Vec<_> = fs::read_dir(root.path().join("data")).unwrap()
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        
+        assert!(!data_files.is_empty());
+        assert!(data_files.iter().any(|f| f.ends_with(".sst")));
+
+        // Verify log directory reflects the changes
+        let log_files: Vec<_> = fs::read_dir(root.path().join("log")).unwrap()
+            .filter_map(|e| e.ok())
+            .collect();
+        assert!(!log_files.is_empty()); // Should have at least one log file
+        
+        // Verify data is still accessible
+        assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_engine_options() {
+        let root = tempdir().unwrap();
+        let crashed = tempdir().unwrap();
+        let options = SSTEngineOptions {
+            data_segment_size: 64,
+            log_segment_size: 128,
+            checksum: true,
+            ..Default::default()
+        };
+        {
+            let mut engine = options.clone().open(root.path()).unwrap();
+            engine.insert("key1", &[1; 40]).unwrap();
+            assert_eq!(sst_files(root.path()), 0);
+            engine.insert("key2", &[2; 40]).unwrap();
+            assert_eq!(sst_files(root.path()), 1);
+            engine.insert("key3", b"value3").unwrap();
+
+            // The log entries of the two large values alone overflow a log segment
+            assert!(fs::read_dir(root.path().join("log")).unwrap().count() > 1);
+
+            // Copied as a crash would leave it, before dropping the engine flushes
+            copy_dir(root.path(), crashed.path());
+        }
+
+        let engine = options.open(crashed.path()).unwrap();
+        assert_eq!(&*engine.get("key2").unwrap().unwrap(), &[2; 40]);
+        assert_eq!(&*engine.get("key3").unwrap().unwrap(), b"value3");
+    }
+
+    fn sst_files(root: &Path) -> usize {
+        fs::read_dir(root.join("data")).unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "sst"))
+            .count()
+    }
+
+    #[test]
+    fn test_engine_compact() {
+        let root = tempdir().unwrap();
+        let options = SSTEngineOptions { data_segment_size: 64, ..Default::default() };
+        {
+            let mut engine = options.clone().open(root.path()).unwrap();
+            for round in 0..5u8 {
+                for i in 0..4 {
+                    engine.insert(&format!("key{}", i), &[round; 20]).unwrap();
+                }
+            }
+            engine.delete("key0").unwrap();
+            let files = sst_files(root.path());
+
+            let stats = engine.compact().unwrap();
+            assert!(stats.segments_before > stats.segments_after);
+            assert!(stats.bytes_reclaimed > 0);
+            assert_eq!(stats.tombstones_dropped, 1);
+            assert!(sst_files(root.path()) < files);
+            assert!(engine.get("key0").unwrap().is_none());
+            assert_eq!(&*engine.get("key3").unwrap().unwrap(), &[4; 20]);
+            engine.insert("key4", b"value4").unwrap();
+        }
+
+        let engine = options.open(root.path()).unwrap();
+        assert!(engine.get("key0").unwrap().is_none());
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), &[4; 20]);
+        assert_eq!(&*engine.get("key4").unwrap().unwrap(), b"value4");
+    }
+
+    #[test]
+    fn test_engine_group_commit() {
+        let root = tempdir().unwrap();
+        let crashed = tempdir().unwrap();
+        let options = SSTEngineOptions {
+            log_sync: LogSync::GroupCommit { max_operations: 3, max_bytes: usize::MAX, max_delay: Duration::from_secs(3600) },
+            ..Default::default()
+        };
+        {
+            let mut engine = options.clone().open(root.path()).unwrap();
+            engine.insert("key1", b"value1").unwrap();
+            engine.delete("key1").unwrap();
+            assert_eq!(engine.unsynced_operations, 2);
+            engine.insert("key2", b"value2").unwrap();
+            assert_eq!(engine.unsynced_operations, 0);
+
+            engine.insert("key3", b"value3").unwrap();
+            assert_eq!(engine.unsynced_operations, 1);
+            engine.sync().unwrap();
+            assert_eq!(engine.unsynced_operations, 0);
+
+            // An elapsed time window syncs on the next append
+            engine.log_sync = LogSync::GroupCommit { max_operations: 100, max_bytes: usize::MAX, max_delay: Duration::ZERO };
+            engine.insert("key4", b"value4").unwrap();
+            assert_eq!(engine.unsynced_operations, 0);
+
+            // Copied as a crash would leave it, before dropping the engine flushes
+            copy_dir(root.path(), crashed.path());
+        }
+
+        let engine = options.open(crashed.path()).unwrap();
+        assert!(engine.get("key1").unwrap().is_none());
+        assert_eq!(&*engine.get("key3").unwrap().unwrap(), b"value3");
+        assert_eq!(&*engine.get("key4").unwrap().unwrap(), b"value4");
+    }
+
+    #[test]
+    fn test_engine_flush_truncates_log() {
+        let root = tempdir().unwrap();
+        let options = SSTEngineOptions { log_segment_size: 64, ..Default::default() };
+        let log_files = || fs::read_dir(root.path().join("log")).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "log"))
+            .collect::<std::collections::BTreeSet<_>>();
+        {
+            let mut engine = options.clone().open(root.path()).unwrap();
+            for i in 0..10 {
+                engine.insert(&format!("key{}", i), b"value").unwrap();
+            }
+            let before = log_files();
+            assert!(before.len() > 1);
+
+            engine.flush().unwrap();
+            let after = log_files();
+            assert_eq!(after.len(), 1);
+            assert!(after.is_subset(&before));
+
+            engine.insert("key10", b"value10").unwrap();
+        }
+
+        let engine = options.open(root.path()).unwrap();
+        assert_eq!(&*engine.get("key0").unwrap().unwrap(), b"value");
+        assert_eq!(&*engine.get("key10").unwrap().unwrap(), b"value10");
+    }
+
+    #[test]
+    fn test_log_entry_byte_order() {
+        let op = LogOperation::InsertWithExpiry("key1".to_string(), b"value1".to_vec(), 1000);
+        for little_endian in [true, false] {
+            let entry = encode_log_entry(&op, 42, little_endian);
+            assert_eq!(&entry[..8], &encode_u64(42, little_endian));
+            assert_eq!(&entry[14..22], &encode_u64(1000, little_endian));
+            assert!(matches!(decode_log_entry(&entry, little_endian),
+                Ok((42, LogOperation::InsertWithExpiry(key, value, 1000))) if key == "key1" && value == b"value1"));
+        }
+        assert_eq!(encode_u64(42, true), 42u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_operation_code_try_from() {
+        let codes = [
+            OperationCode::Terminator,
+            OperationCode::Insert,
+            OperationCode::Delete,
+            OperationCode::BatchBegin,
+            OperationCode::BatchCommit,
+            OperationCode::InsertWithExpiry,
+            OperationCode::Merge,
+        ];
+        for code in codes {
+            assert_eq!(OperationCode::try_from(code as u8).unwrap(), code);
+        }
+        assert_eq!(OperationCode::try_from(7).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert!(OperationCode::try_from(u8::MAX).is_err());
+    }
+
+    #[test]
+    fn test_log_entry_round_trip() {
+        let ops = [
+            LogOperation::Insert("key1".to_string(), b"value1".to_vec()),
+            LogOperation::Insert("key2".to_string(), Vec::new()),
+            LogOperation::Delete("key3".to_string()),
+            LogOperation::InsertWithExpiry("key4".to_string(), b"value4".to_vec(), 1000),
+            LogOperation::Merge("key5".to_string(), 7u64.to_le_bytes().to_vec()),
+            LogOperation::BatchBegin,
+            LogOperation::BatchCommit,
+        ];
+        for little_endian in [true, false] {
+            for (serial, op) in (1..).zip(&ops) {
+                let entry = encode_log_entry(op, serial, little_endian);
+                assert_eq!(SSTEngine::decode_log_entry(&entry, little_endian).unwrap(), (serial, op.clone()));
+            }
+        }
+
+        let entry = |code: u8, rest: &[u8]| [&7u64.to_le_bytes()[..], &[code], rest].concat();
+        let malformed = [
+            7u64.to_le_bytes().to_vec(),
+            entry(OperationCode::Terminator as u8, b"key\0value\0"),
+            entry(42, b"key\0value\0"),
+            entry(OperationCode::Insert as u8, b"key"),
+            entry(OperationCode::Insert as u8, b"key\0value"),
+            entry(OperationCode::Insert as u8, b"\xff\0value\0"),
+            entry(OperationCode::Delete as u8, b"key\0value"),
+            entry(OperationCode::InsertWithExpiry as u8, b"key\0\x01\x02"),
+            entry(OperationCode::BatchCommit as u8, b"\0"),
+        ];
+        for entry in malformed {
+            let error = SSTEngine::decode_log_entry(&entry, true).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn test_engine_get_at() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        engine.insert("key1", b"value1").unwrap();
+        let first = engine.kv.latest_serial();
+        engine.insert("key2", b"value").unwrap();
+        engine.insert("key1", b"value2").unwrap();
+        let second = engine.kv.latest_serial();
+        engine.delete("key1").unwrap();
+
+        assert_eq!(engine.get_at("key1", 0).unwrap(), None);
+        assert_eq!(&*engine.get_at("key1", first).unwrap().unwrap(), b"value1");
+        assert_eq!(&*engine.get_at("key1", second - 1).unwrap().unwrap(), b"value1");
+        assert_eq!(&*engine.get_at("key1", second).unwrap().unwrap(), b"value2");
+        assert_eq!(engine.get_at("key1", engine.kv.latest_serial()).unwrap(), None);
+        assert_eq!(engine.get_at("key2", first).unwrap(), None);
+
+        // A checkpoint drops the history before it
+        engine.flush().unwrap();
+        let checkpoint = engine.kv.latest_serial();
+        engine.insert("key2", b"value2").unwrap();
+        assert!(engine.get_at("key1", first).is_err());
+        assert_eq!(engine.get_at("key1", checkpoint).unwrap(), None);
+        // Overwritten since, and segments only have the new value
+        assert!(engine.get_at("key2", checkpoint).is_err());
+        assert_eq!(&*engine.get_at("key2", checkpoint + 1).unwrap().unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_engine_merge() {
+        let root = tempdir().unwrap();
+        let counter = kv::MergeFn::new(|existing, operands| {
+            let decode = |bytes: &[u8]| String::from_utf8_lossy(bytes).parse::<i64>().unwrap();
+            let sum = existing.map_or(0, decode) + operands.iter().map(|o| decode(o)).sum::<i64>();
+            sum.to_string().into_bytes()
+        });
+        let options = SSTEngineOptions { merge_fn: Some(counter), ..Default::default() };
+        assert!(SSTEngine::try_new(tempdir().unwrap().path()).unwrap().merge("count", b"+1").is_err());
+        {
+            let mut engine = options.clone().open(root.path()).unwrap();
+            for _ in 0..3 {
+                engine.merge("count", b"+1").unwrap();
+            }
+            assert_eq!(&*engine.get("count").unwrap().unwrap(), b"3");
+            assert_eq!(&*engine.get_at("count", 2).unwrap().unwrap(), b"2");
+
+            engine.flush().unwrap();
+            engine.merge("count", b"+1").unwrap();
+            engine.flush().unwrap();
+            engine.merge("count", b"-2").unwrap();
+            assert_eq!(&*engine.get("count").unwrap().unwrap(), b"2");
+        }
+
+        // Replayed from the log and read back from segment files
+        let mut engine = options.open(root.path()).unwrap();
+        assert_eq!(&*engine.get("count").unwrap().unwrap(), b"2");
+        engine.compact().unwrap();
+        assert_eq!(&*engine.get("count").unwrap().unwrap(), b"2");
+        assert_eq!(engine.iter().collect::<Vec<_>>(), vec![("count".to_string(), Box::from(&b"2"[..]))]);
+    }
+
+    #[test]
+    fn test_engine_stats() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        let stats = engine.stats().unwrap();
+        assert_eq!((stats.segment_count, stats.latest_serial, stats.live_keys), (0, 0, 0));
+
+        engine.insert("key1", b"value1").unwrap();
+        engine.insert("key2", b"value2").unwrap();
+        engine.flush().unwrap();
+        engine.delete("key1").unwrap();
+        engine.insert("key3", b"v3").unwrap();
+        let stats = engine.stats().unwrap();
+        assert_eq!(stats.segment_count, 2);
+        assert_eq!(stats.latest_serial, 4);
+        assert_eq!(stats.tombstones, 1);
+        // key1 is still counted for the segment written before its deletion
+        assert_eq!(stats.live_keys, 3);
+        // The tombstone takes up the bytes of its key
+        assert_eq!(stats.active_segment_bytes, 10);
+        assert!(stats.disk_bytes > 0);
+        assert!(stats.wal_bytes > 0);
+        assert!(stats.to_string().contains("segments=2 "));
+
+        engine.compact().unwrap();
+        let stats = engine.stats().unwrap();
+        assert_eq!((stats.segment_count, stats.live_keys), (1, 2));
+    }
+
+    #[test]
+    fn test_engine_drop_flushes() {
+        let root = tempdir().unwrap();
+        {
+            let mut engine = SSTEngine::try_new(root.path()).unwrap();
+            engine.insert("key1", b"value1").unwrap();
+        }
+        assert_eq!(sst_files(root.path()), 1);
+
+        let engine = SSTEngine::try_new(root.path()).unwrap();
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value1");
+    }
+
+    #[test]
+    fn test_engine_replays_log_on_reopen() {
+        let root = tempdir().unwrap();
+        let crashed = tempdir().unwrap();
+        {
+            let mut engine = SSTEngine::try_new(root.path()).unwrap();
+            engine.insert("key1", b"value1").unwrap();
+            engine.insert("key2", b"value2").unwrap();
+            engine.delete("key1").unwrap();
+            engine.insert("key3", b"value3").unwrap();
+
+            // Copied as a crash would leave it, before dropping the engine flushes
+            copy_dir(root.path(), crashed.path());
+        }
+
+        let engine = SSTEngine::try_new(crashed.path()).unwrap();
+        assert!(engine.get("key1").unwrap().is_none());
+        assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+        assert_eq!(&*engine.get("key3").unwrap().unwrap(), b"value3");
+    }
+
+    #[test]
+    fn test_engine_replay_ignores_torn_entry() {
+        let root = tempdir().unwrap();
+        let crashed = tempdir().unwrap();
+        {
+            let mut engine = SSTEngine::try_new(root.path()).unwrap();
+            engine.insert("key1", b"value1").unwrap();
+            copy_dir(root.path(), crashed.path());
+        }
+
+        // Simulate a crash in the middle of appending the next entry
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(crashed.path().join("log").join("0.log"))
+            .unwrap();
+        std::io::Write::write_all(&mut file, &[0, 0, 0, 32, 0, 0]).unwrap();
+
+        let mut engine = SSTEngine::try_new(crashed.path()).unwrap();
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value1");
+        engine.insert("key2", b"value2").unwrap();
+        assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_engine_json_round_trip() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        engine.insert("plain", b"value").unwrap();
+        engine.insert("binary", &[0, 255, 10, 34, 92]).unwrap();
+        engine.insert("quote\"d\n", b"").unwrap();
+        engine.insert("deleted", b"value").unwrap();
+        engine.delete("deleted").unwrap();
+
+        let mut exported = Vec::new();
+        engine.export_json(&mut exported).unwrap();
+        let text = String::from_utf8(exported.clone()).unwrap();
+        assert!(text.contains(r#"{"key": "plain", "value": "dmFsdWU="}"#));
+        assert!(!text.contains("deleted"));
+
+        let other_root = tempdir().unwrap();
+        let mut other = SSTEngine::try_new(other_root.path()).unwrap();
+        other.import_json(&exported[..]).unwrap();
+        assert!(other.iter().eq(engine.iter()));
+
+        assert!(other.import_json(&br#"[{"key": "k"}]"#[..]).is_err());
+        assert!(other.import_json(&br#"[{"key": "k", "value": "!"}]"#[..]).is_err());
+    }
+
+    // Path, length and contents of every file below the directory, in path order
+    fn directory_contents(path: &Path) -> Vec<(std::path::PathBuf, Vec<u8>)> {
+        let mut contents = Vec::new();
+        for entry in fs::read_dir(path).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                contents.extend(directory_contents(&path));
+            } else {
+                contents.push((path.clone(), fs::read(&path).unwrap()));
+            }
+        }
+        contents.sort();
+        contents
+    }
+
+    #[test]
+    fn test_engine_file_naming() {
+        let root = tempdir().unwrap();
+        let options = SSTEngineOptions {
+            data_file_naming: FileNaming::new("data", 8).unwrap(),
+            log_file_naming: FileNaming::new("wal", 8).unwrap(),
+            ..Default::default()
+        };
+        {
+            let mut engine = options.clone().open(root.path()).unwrap();
+            engine.insert("flushed", b"1").unwrap();
+            engine.flush().unwrap();
+            engine.insert("logged", b"2").unwrap();
+        }
+        let names = |dir: &str| -> Vec<String> {
+            fs::read_dir(root.path().join(dir)).unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.contains('.'))
+                .collect()
+        };
+        assert!(names("data").iter().all(|name| name.len() == "00000000.data".len() && name.ends_with(".data")));
+        assert_eq!(names("log"), ["00000000.wal"]);
+
+        let engine = options.open(root.path()).unwrap();
+        assert_eq!(engine.get("flushed").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(engine.get("logged").unwrap().as_deref(), Some(&b"2"[..]));
+    }
+
+    #[test]
+    fn test_engine_read_only() {
+        let written = tempdir().unwrap();
+        let root = tempdir().unwrap();
+        {
+            let mut engine = SSTEngine::try_new(written.path()).unwrap();
+            engine.insert("flushed", b"1").unwrap();
+            engine.flush().unwrap();
+            engine.insert("logged", b"2").unwrap();
+            // Copied before dropping the engine flushes, so the second insert is only logged
+            copy_dir(written.path(), root.path());
+        }
+        let before = directory_contents(root.path());
+
+        let mut engine = SSTEngine::open_read_only(root.path()).unwrap();
+        assert_eq!(engine.get("flushed").unwrap().as_deref(), Some(&b"1"[..]));
+        assert_eq!(engine.get("logged").unwrap().as_deref(), Some(&b"2"[..]));
+        assert_eq!(engine.iter().count(), 2);
+        assert!(matches!(engine.insert("key", b"value"), Err(Error::ReadOnly)));
+        assert!(matches!(engine.delete("flushed"), Err(Error::ReadOnly)));
+        assert!(matches!(engine.write_batch(vec![BatchOp::Delete("logged".to_string())]), Err(Error::ReadOnly)));
+        assert!(matches!(engine.compact(), Err(Error::ReadOnly)));
+        assert!(matches!(engine.flush(), Err(Error::ReadOnly)));
+        assert_eq!(engine.get("logged").unwrap().as_deref(), Some(&b"2"[..]));
+        drop(engine);
+        assert_eq!(directory_contents(root.path()), before);
+
+        let missing = root.path().join("missing");
+        assert!(SSTEngine::open_read_only(&missing).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        segment_reads: Mutex<Vec<u64>>,
+        gets: Mutex<(usize, usize)>,
+        compactions: Mutex<Vec<kv::CompactionStats>>,
+        wal_appends: Mutex<Vec<usize>>,
+        // Appends made when each sync happened
+        wal_syncs: Mutex<Vec<usize>>,
+    }
+
+    impl kv::Metrics for CountingMetrics {
+        fn on_segment_read(&self, serial: u64) {
+            self.segment_reads.lock().unwrap().push(serial);
+        }
+
+        fn on_get(&self, found: bool) {
+            let mut gets = self.gets.lock().unwrap();
+            if found {
+                gets.0 += 1;
+            } else {
+                gets.1 += 1;
+            }
+        }
+
+        fn on_compaction(&self, stats: &kv::CompactionStats) {
+            self.compactions.lock().unwrap().push(*stats);
+        }
+
+        fn on_wal_append(&self, bytes: usize) {
+            self.wal_appends.lock().unwrap().push(bytes);
+        }
+
+        fn on_wal_sync(&self) {
+            self.wal_syncs.lock().unwrap().push(self.wal_appends.lock().unwrap().len());
+        }
+    }
+
+    #[test]
+    fn test_engine_metrics() {
+        let root = tempdir().unwrap();
+        let metrics = Arc::new(CountingMetrics::default());
+        let mut engine = SSTEngineOptions { metrics: Some(metrics.clone()), ..Default::default() }
+            .open(root.path())
+            .unwrap();
+        engine.insert("a", b"1").unwrap();
+        engine.insert("c", b"3").unwrap();
+        engine.flush().unwrap();
+        engine.insert("b", b"2").unwrap();
+
+        // Inserts look up the previous value too. Only segments whose key range covers the key
+        // are read, the flushed one with serial 2 and the active one with serial 3.
+        assert!(engine.get("a").unwrap().is_some());
+        assert!(engine.get("b").unwrap().is_some());
+        assert!(engine.get("bb").unwrap().is_none());
+        assert!(engine.get("z").unwrap().is_none());
+        assert_eq!(*metrics.segment_reads.lock().unwrap(), vec![2, 2, 3, 2]);
+        assert_eq!(*metrics.gets.lock().unwrap(), (2, 5));
+
+        engine.write_batch(vec![BatchOp::Put("d".to_string(), b"4".to_vec()), BatchOp::Delete("a".to_string())]).unwrap();
+        let wal_appends = metrics.wal_appends.lock().unwrap().clone();
+        assert_eq!(wal_appends.len(), 4);
+        assert!(wal_appends.iter().all(|&bytes| bytes > 0));
+
+        let stats = engine.compact().unwrap();
+        assert_eq!(*metrics.compactions.lock().unwrap(), vec![stats]);
+    }
+
+    #[test]
+    fn test_engine_group_commit_thresholds() {
+        let root = tempdir().unwrap();
+        let metrics = Arc::new(CountingMetrics::default());
+        let mut engine = SSTEngineOptions {
+            log_sync: LogSync::GroupCommit { max_operations: 4, max_bytes: usize::MAX, max_delay: Duration::MAX },
+            metrics: Some(metrics.clone()),
+            ..Default::default()
+        }.open(root.path()).unwrap();
+        for i in 0..10 {
+            engine.insert(&format!("key{}", i), b"value").unwrap();
+        }
+        assert_eq!(*metrics.wal_syncs.lock().unwrap(), vec![4, 8]);
+
+        // Every entry here takes the same number of bytes, the threshold is reached by the third
+        engine.sync().unwrap();
+        metrics.wal_syncs.lock().unwrap().clear();
+        let entry_len = metrics.wal_appends.lock().unwrap()[0];
+        engine.log_sync = LogSync::GroupCommit { max_operations: usize::MAX, max_bytes: 3 * entry_len, max_delay: Duration::MAX };
+        for i in 0..7 {
+            engine.insert(&format!("key{}", i), b"other").unwrap();
+        }
+        assert!(metrics.wal_appends.lock().unwrap().iter().all(|&bytes| bytes == entry_len));
+        assert_eq!(*metrics.wal_syncs.lock().unwrap(), vec![13, 16]);
+        assert_eq!(engine.unsynced_operations, 1);
+        assert_eq!(engine.unsynced_bytes, entry_len);
+    }
+
+    #[test]
+    fn test_engine_reuses_log_buffer() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        engine.insert("key1", b"value1").unwrap();
+        let buffer = engine.log_buffer.as_ptr();
+        engine.insert("key2", b"value2").unwrap();
+        engine.delete("key1").unwrap();
+        assert_eq!(engine.log_buffer.as_ptr(), buffer);
+
+        // A buffer grown past the retained capacity is dropped by the next operation
+        engine.insert("large", &vec![0; 2 * LOG_BUFFER_RETAIN]).unwrap();
+        engine.insert("key3", b"value3").unwrap();
+        assert!(engine.log_buffer.capacity() <= LOG_BUFFER_RETAIN);
+        drop(engine);
+
+        let engine = SSTEngine::try_new(root.path()).unwrap();
+        assert_eq!(engine.get("key1").unwrap(), None);
+        assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+        assert_eq!(&*engine.get("key3").unwrap().unwrap(), b"value3");
+        assert_eq!(engine.get("large").unwrap().unwrap().len(), 2 * LOG_BUFFER_RETAIN);
+    }
+
+    #[test]
+    fn test_engine_checkpoint_restore() {
+        let root = tempdir().unwrap();
+        let options = SSTEngineOptions { data_segment_size: 256, ..Default::default() };
+        let mut engine = options.open(root.path()).unwrap();
+        for i in 0..100 {
+            engine.insert(&format!("key{:03}", i), format!("value{}", i).as_bytes()).unwrap();
+        }
+        for i in (0..100).step_by(3) {
+            engine.delete(&format!("key{:03}", i)).unwrap();
+        }
+        engine.insert("key001", b"updated").unwrap();
+        engine.cf("users").unwrap().insert("alice", b"1").unwrap();
+
+        let mut archive = Vec::new();
+        engine.checkpoint(&mut archive).unwrap();
+        // Later writes are not part of the checkpoint
+        engine.insert("later", b"value").unwrap();
+
+        let other_root = tempdir().unwrap();
+        let mut restored = SSTEngine::restore(other_root.path(), &archive[..]).unwrap();
+        assert!(restored.iter().eq(engine.iter().filter(|(key, _)| key != "later")));
+        assert_eq!(&*restored.get("key001").unwrap().unwrap(), b"updated");
+        assert_eq!(restored.get("key003").unwrap(), None);
+        assert_eq!(&*restored.cf("users").unwrap().get("alice").unwrap().unwrap(), b"1");
+        assert_eq!(restored.kv.latest_serial(), engine.kv.latest_serial() - 1);
+        drop(restored);
+
+        let reopened = SSTEngine::try_new(other_root.path()).unwrap();
+        assert_eq!(&*reopened.get("key098").unwrap().unwrap(), b"value98");
+
+        // A store is never restored over existing data, nor from a damaged archive
+        assert!(SSTEngine::restore(other_root.path(), &archive[..]).is_err());
+        let damaged_root = tempdir().unwrap();
+        assert!(SSTEngine::restore(damaged_root.path(), &archive[..archive.len() - 1]).is_err());
+        assert!(SSTEngine::restore(damaged_root.path(), &b"KSST"[..]).is_err());
+        // A segment length past the end of the archive is not allocated up front
+        let mut huge = archive[..17].to_vec();
+        huge.extend_from_slice(&(u64::MAX / 2).to_le_bytes());
+        huge.extend_from_slice(&archive[25..]);
+        assert!(SSTEngine::restore(damaged_root.path(), &huge[..]).is_err());
+
+        // Segment files are written as the options name them
+        let options = SSTEngineOptions { data_file_naming: FileNaming::new("data", 8).unwrap(), ..Default::default() };
+        let padded_root = tempdir().unwrap();
+        drop(options.clone().restore(padded_root.path(), &archive[..]).unwrap());
+        assert!(fs::read_dir(padded_root.path().join("data")).unwrap()
+            .any(|e| e.unwrap().file_name().to_string_lossy().ends_with(".data")));
+        let mut reopened = options.open(padded_root.path()).unwrap();
+        assert_eq!(&*reopened.get("key001").unwrap().unwrap(), b"updated");
+        assert_eq!(&*reopened.cf("users").unwrap().get("alice").unwrap().unwrap(), b"1");
+    }
+
+    #[test]
+    fn test_engine_flush() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        engine.insert("key1", b"value1").unwrap();
+        engine.flush().unwrap();
+        engine.flush().unwrap();
+
+        assert_eq!(sst_files(root.path()), 1);
+
+        let engine = SSTEngine::try_new(root.path()).unwrap();
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value1");
+    }
+
+    #[test]
+    fn test_engine_close_then_insert() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        engine.insert("key1", b"value1").unwrap();
+        engine.close().unwrap();
+
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        engine.insert("key2", b"value2").unwrap();
+        engine.close().unwrap();
+
+        let engine = SSTEngine::try_new(root.path()).unwrap();
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value1");
+        assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_engine_scan() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+
+        engine.insert("ts:001", b"a").unwrap();
+        engine.insert("ts:002", b"b").unwrap();
+        engine.insert("ts:003", b"c").unwrap();
+        engine.delete("ts:002").unwrap();
+
+        let keys: Vec<_> = engine.scan("ts:001", "ts:003").unwrap().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["ts:001"]);
+    }
+
+    #[test]
+    fn test_engine_iter() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        let mut expected = std::collections::BTreeMap::new();
+
+        for i in 0..50 {
+            let key = format!("key{:02}", (i * 7) % 50);
+            engine.insert(&key, key.as_bytes()).unwrap();
+            expected.insert(key.clone(), key.into_bytes().into_boxed_slice());
+            if i % 10 == 9 {
+                engine.flush().unwrap();
+            }
+        }
+        for i in (0..50).step_by(5) {
+            let key = format!("key{:02}", i);
+            engine.delete(&key).unwrap();
+            expected.remove(&key);
+        }
+        engine.insert("key01", b"updated").unwrap();
+        expected.insert("key01".to_string(), b"updated".to_vec().into_boxed_slice());
+
+        assert_eq!(engine.iter().collect::<Vec<_>>(), expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_engine_snapshot() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+
+        engine.insert("key1", b"value1").unwrap();
+        engine.insert("key2", b"value2").unwrap();
+        let snapshot = engine.snapshot();
+
+        engine.insert("key1", b"updated").unwrap();
+        engine.delete("key2").unwrap();
+        engine.insert("key3", b"value3").unwrap();
+        engine.flush().unwrap();
+        engine.insert("key4", b"value4").unwrap();
+
+        assert_eq!(snapshot.serial(), 2);
+        assert_eq!(&*snapshot.get("key1").unwrap().unwrap(), b"value1");
+        assert_eq!(&*snapshot.get("key2").unwrap().unwrap(), b"value2");
+        assert!(snapshot.get("key3").unwrap().is_none());
+        let keys: Vec<_> = snapshot.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["key1", "key2"]);
+
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"updated");
+        assert!(engine.get("key2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_engine_write_batch() {
+        let root = tempdir().unwrap();
+        let crashed = tempdir().unwrap();
+        {
+            let mut engine = SSTEngine::try_new(root.path()).unwrap();
+            engine.insert("key1", b"value1").unwrap();
+            engine.write_batch(vec![
+                BatchOp::Put("key2".to_string(), b"value2".to_vec()),
+                BatchOp::Delete("key1".to_string()),
+            ]).unwrap();
+            assert!(engine.get("key1").unwrap().is_none());
+            assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+
+            // Copied as a crash would leave it, before dropping the engine flushes
+            copy_dir(root.path(), crashed.path());
+        }
+
+        let engine = SSTEngine::try_new(crashed.path()).unwrap();
+        assert!(engine.get("key1").unwrap().is_none());
+        assert_eq!(&*engine.get("key2").unwrap().unwrap(), b"value2");
+    }
+
+    #[test]
+    fn test_engine_insert_if_changed() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        assert!(engine.insert_if_changed("key1", b"value1").unwrap());
+        let entries = engine.log().count_entries().unwrap();
+        let serial = engine.kv.latest_serial();
+
+        assert!(!engine.insert_if_changed("key1", b"value1").unwrap());
+        assert_eq!(engine.log().count_entries().unwrap(), entries);
+        assert_eq!(engine.kv.latest_serial(), serial);
+
+        assert!(engine.insert_if_changed("key1", b"value2").unwrap());
+        assert_eq!(engine.log().count_entries().unwrap(), entries + 1);
+
+        // Also compared once flushed, unless the value could expire
+        engine.flush().unwrap();
+        assert!(!engine.insert_if_changed("key1", b"value2").unwrap());
+        assert_eq!(engine.log().count_entries().unwrap(), 0);
+        engine.insert_with_ttl("key2", b"value3", Duration::from_secs(3600)).unwrap();
+        assert!(engine.insert_if_changed("key2", b"value3").unwrap());
+        engine.delete("key1").unwrap();
+        assert!(engine.insert_if_changed("key1", b"value2").unwrap());
+        assert_eq!(engine.log().count_entries().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_engine_iter_after_serial() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        engine.insert("key1", b"value1").unwrap();
+        engine.insert("key2", b"value2").unwrap();
+        engine.delete("key1").unwrap();
+        engine.write_batch(vec![
+            BatchOp::Put("key3".to_string(), b"value3".to_vec()),
+            BatchOp::Delete("key2".to_string()),
+        ]).unwrap();
+        engine.insert("key1", b"value4").unwrap();
+
+        let after = |engine: &SSTEngine, serial| engine.iter_after_serial(serial).unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(after(&engine, 0).len(), 6);
+        assert_eq!(after(&engine, 3), vec![
+            (4, LogOperation::Insert("key3".to_string(), b"value3".to_vec())),
+            (5, LogOperation::Delete("key2".to_string())),
+            (6, LogOperation::Insert("key1".to_string(), b"value4".to_vec())),
+        ]);
+        assert!(after(&engine, 6).is_empty());
+
+        // A follower keeps reading operations synced since
+        let mut follower = engine.iter_after_serial(5).unwrap();
+        assert_eq!(follower.next().unwrap().unwrap().0, 6);
+        assert!(follower.next().is_none());
+        engine.delete("key3").unwrap();
+        assert_eq!(follower.next().unwrap().unwrap(), (7, LogOperation::Delete("key3".to_string())));
+
+        // Operations a checkpoint truncated from the log can't be caught up on
+        engine.flush().unwrap();
+        engine.insert("key4", b"value5").unwrap();
+        assert!(engine.iter_after_serial(6).is_err());
+        assert_eq!(after(&engine, 7), vec![(8, LogOperation::Insert("key4".to_string(), b"value5".to_vec()))]);
+    }
+
+    #[test]
+    fn test_engine_log_and_table_serials_agree() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        let logged_serials = |engine: &SSTEngine| {
+            let log = engine.log.lock().unwrap();
+            let little_endian = log.is_little_endian().unwrap();
+            (&*log).into_iter()
+                .map(|entry| decode_log_entry(&entry.unwrap(), little_endian).unwrap().0)
+                .collect::<Vec<_>>()
+        };
+
+        engine.insert("key1", b"value1").unwrap();
+        engine.insert("key1", b"value2").unwrap();
+        engine.delete("key1").unwrap();
+        engine.delete("missing").unwrap();
+        assert_eq!(logged_serials(&engine), vec![1, 2, 3, 4]);
+        assert_eq!(engine.kv.latest_serial(), 4);
+
+        // One serial per operation of a batch, whatever keys it touches more than once
+        engine.write_batch(vec![
+            BatchOp::Put("key2".to_string(), b"value".to_vec()),
+            BatchOp::Put("key2".to_string(), b"value3".to_vec()),
+            BatchOp::Delete("key1".to_string()),
+        ]).unwrap();
+        engine.insert("key3", b"value4").unwrap();
+        assert_eq!(logged_serials(&engine), vec![1, 2, 3, 4, 5, 5, 6, 7, 7, 8]);
+        assert_eq!(engine.kv.latest_serial(), 8);
+
+        // Replaying the log stamps the table with the same serials
+        let crashed = tempdir().unwrap();
+        copy_dir(root.path(), crashed.path());
+        let engine = SSTEngine::try_new(crashed.path()).unwrap();
+        assert_eq!(engine.kv.latest_serial(), 8);
+        assert_eq!(&*engine.get_at("key2", 6).unwrap().unwrap(), b"value3");
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap() {
+            let path = entry.unwrap().path();
+            let target = to.join(path.file_name().unwrap());
+            if path.is_dir() {
+                copy_dir(&path, &target);
+            } else {
+                fs::copy(&path, &target).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_engine_replay_is_idempotent() {
+        let root = tempdir().unwrap();
+        let crashed = tempdir().unwrap();
+        let options = SSTEngineOptions { data_segment_size: 32, ..Default::default() };
+        {
+            let mut engine = options.clone().open(root.path()).unwrap();
+            engine.insert("key1", b"value1").unwrap();
+            engine.delete("key1").unwrap();
+            // Segments roll over in the middle of the batch
+            engine.write_batch((0..3).map(|i| BatchOp::Put(format!("key{}", i + 2), vec![i; 20])).collect()).unwrap();
+            assert!(sst_files(root.path()) > 0);
+
+            // Copied as a crash would leave it, before dropping the engine flushes
+            copy_dir(root.path(), crashed.path());
+        }
+
+        let mut engine = options.open(crashed.path()).unwrap();
+        let state = |engine: &SSTEngine| engine.iter().collect::<Vec<_>>();
+        let replayed = state(&engine);
+        let serial = engine.kv.latest_serial();
+        assert_eq!(replayed.len(), 3);
+        assert_eq!(&*engine.get("key4").unwrap().unwrap(), &[2; 20]);
+
+        engine.replay_log().unwrap();
+        assert_eq!(state(&engine), replayed);
+        assert_eq!(engine.kv.latest_serial(), serial);
+
+        // Serials already reached are skipped
+        assert!(!engine.insert_with_serial("key1", b"value3", serial).unwrap());
+        assert!(engine.insert_with_serial("key1", b"value3", serial + 5).unwrap());
+        assert!(!engine.insert_with_serial("key1", b"value4", serial + 5).unwrap());
+        assert_eq!(engine.kv.latest_serial(), serial + 5);
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value3");
+    }
+
+    #[test]
+    fn test_engine_incomplete_batch_rolled_back() {
+        let root = tempdir().unwrap();
+        let crashed = tempdir().unwrap();
+        {
+            let mut engine = SSTEngine::try_new(root.path()).unwrap();
+            engine.insert("key1", b"value1").unwrap();
+
+            // Simulate a crash before the commit marker was written
+            let serial = engine.kv.latest_serial() + 1;
+            engine.log().append(&encode_log_entry(&LogOperation::BatchBegin, serial, true)).unwrap();
+            engine.log().append(&encode_log_entry(&LogOperation::Insert("key2".to_string(), b"value2".to_vec()), serial, true)).unwrap();
+            engine.log().append(&encode_log_entry(&LogOperation::Delete("key1".to_string()), serial, true)).unwrap();
+            engine.log().flush().unwrap();
+            copy_dir(root.path(), crashed.path());
+        }
+
+        let engine = SSTEngine::try_new(crashed.path()).unwrap();
+        assert_eq!(&*engine.get("key1").unwrap().unwrap(), b"value1");
+        assert!(engine.get("key2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_engine_malformed_log_entry_fails_replay() {
+        let root = tempdir().unwrap();
+        let mut engine = SSTEngine::try_new(root.path()).unwrap();
+        engine.insert("key1", b"value1").unwrap();
+        let serial = engine.kv.latest_serial() + 1;
+        let malformed = [&serial.to_le_bytes()[..], &[42], b"key1\0value2\0"].concat();
+        engine.log().append(&malformed).unwrap();
+        engine.insert("key2", b"value2").unwrap();
+        engine.insert("key1", b"value3").unwrap();
+        engine.log().flush().unwrap();
+
+        assert!(engine.get_at("key1", 1).is_err());
+        let ops: Vec<_> = engine.iter_after_serial(0).unwrap().collect();
+        assert!(matches!(&ops[..], [Ok((1, _)), Err(_), ..]));
+
+        let crashed = tempdir().unwrap();
+        copy_dir(root.path(), crashed.path());
+        assert!(SSTEngine::try_new(crashed.path()).is_err());
+    }
+}
@@ -1,9 +1,29 @@
-use std::{cell::RefCell, io::{Read, Seek, SeekFrom, Write}};
+use std::{cell::{Cell, RefCell}, collections::HashMap, io::{Read, Seek, SeekFrom, Write}};
+
+use crate::{streams::{FileSegmentStream, SegmentReader}, Error};
+
+const LOG_MAGIC: [u8; 4] = *b"KLOG";
+// Version 2 added the header and widened entry sizes from u32 to u64, version 3 switched from
+// big-endian to little-endian
+const LOG_FORMAT_VERSION: u8 = 3;
+// Logs without a header
+const LEGACY_LOG_VERSION: u8 = 1;
+const FIRST_LITTLE_ENDIAN_VERSION: u8 = 3;
+const LOG_HEADER_LEN: u64 = 5;
+
+// Default limit of the entry size, frames declaring a larger one are taken for corrupt instead
+// of being allocated
+pub const DEFAULT_MAX_ENTRY_SIZE: usize = 1024 * 1024 * 1024;
 
 pub struct Log<T>
     where T: Read + Write + Seek {
     // The log entries
     storage: RefCell<T>,
+    // Whether each entry carries a CRC32 of its length and payload
+    checksum: bool,
+    // Format version read from the storage, None until known or while the storage is empty
+    version: Cell<Option<u8>>,
+    max_entry_size: usize,
 }
 
 impl <T> Log<T>
@@ -11,83 +31,697 @@ impl <T> Log<T>
     // Create a new MemoryLog
     pub fn new(storage: RefCell<T>) -> Log<T> {
         Log {
-            storage
+            storage,
+            checksum: false,
+            version: Cell::new(None),
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+        }
+    }
+
+    // Create a log that stores a CRC32 with every entry and verifies it on read
+    pub fn new_with_checksum(storage: RefCell<T>) -> Log<T> {
+        Log {
+            storage,
+            checksum: true,
+            version: Cell::new(None),
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+        }
+    }
+
+    // Largest entry in bytes that can be appended, and read back. Reading a frame that declares a
+    // larger size fails without allocating it.
+    pub fn with_max_entry_size(mut self, max_entry_size: usize) -> Log<T> {
+        self.max_entry_size = max_entry_size;
+        self
+    }
+
+    // Append a new entry to the log and return the offset its frame starts at
+    pub fn append(&mut self, entry: &[u8]) -> crate::Result<u64> {
+        let version = self.prepare_append()?;
+        let mut frames = Vec::new();
+        self.encode_frame(&mut frames, entry, version)?;
+
+        let mut storage = self.storage.borrow_mut();
+        let offset = storage.seek(SeekFrom::End(0))?;
+        storage.write_all(&frames)?;
+        Ok(offset)
+    }
+
+    // Append several entries with a single write, framed just like append would
+    pub fn append_batch(&mut self, entries: &[&[u8]]) -> crate::Result<()> {
+        let version = self.prepare_append()?;
+        let mut frames = Vec::with_capacity(entries.iter().map(|e| e.len() + 12).sum());
+        for entry in entries {
+            self.encode_frame(&mut frames, entry, version)?;
         }
+
+        let mut storage = self.storage.borrow_mut();
+        storage.seek(SeekFrom::End(0))?;
+        storage.write_all(&frames)?;
+        Ok(())
     }
 
-    // Append a new entry to the log
-    pub fn append(&mut self, entry: &[u8]) -> std::io::Result<()> {
-        let size = entry.len() as u32;
-        let size_bytes = size.to_be_bytes();
-        self.storage.borrow_mut().seek(SeekFrom::End(0))?;
-        self.storage.borrow_mut().write_all(&size_bytes)?;
-        self.storage.borrow_mut().write_all(entry)?;
+    // Write the header of an empty log and return the format version to append with
+    fn prepare_append(&mut self) -> crate::Result<u8> {
+        Ok(match self.version()? {
+            Some(version) => version,
+            None => {
+                let mut storage = self.storage.borrow_mut();
+                storage.seek(SeekFrom::Start(0))?;
+                storage.write_all(&LOG_MAGIC)?;
+                storage.write_all(&[LOG_FORMAT_VERSION])?;
+                self.version.set(Some(LOG_FORMAT_VERSION));
+                LOG_FORMAT_VERSION
+            }
+        })
+    }
 
+    // Size, optional checksum and payload of an entry
+    fn encode_frame(&self, frames: &mut Vec<u8>, entry: &[u8], version: u8) -> crate::Result<()> {
+        if version == LEGACY_LOG_VERSION && u32::try_from(entry.len()).is_err() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "Entry too large for a version 1 log").into());
+        }
+        if entry.len() > self.max_entry_size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Entry of {} bytes exceeds the log entry size limit", entry.len())).into());
+        }
+
+        let size_bytes = encode_size(entry.len() as u64, version);
+        frames.extend_from_slice(&size_bytes);
+        if self.checksum {
+            frames.extend_from_slice(&encode_checksum(entry_checksum(&size_bytes, entry), version));
+        }
+        frames.extend_from_slice(entry);
+        Ok(())
+    }
+
+    // Read the entry whose frame starts at an offset returned by append
+    pub fn read_at(&self, offset: u64) -> crate::Result<Box<[u8]>> {
+        let Some(version) = self.version()? else {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        };
+        if offset < self.first_position(version)? {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Offset before the start of the log").into());
+        }
+        let mut buf = Vec::new();
+        match read_frame(&mut *self.storage.borrow_mut(), offset, version, self.checksum, self.max_entry_size, &mut buf)? {
+            Some(size) => Ok(Box::from(&buf[..size])),
+            None => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "No log entry at offset").into()),
+        }
+    }
+
+    // Iterate over the entries whose frames start at or after an offset returned by append
+    pub fn iter_from(&self, offset: u64) -> LogIterator<'_, T> {
+        self.iter_range(offset, u64::MAX)
+    }
+
+    // Iterate over the entries whose frames start within start..end
+    pub fn iter_range(&self, start: u64, end: u64) -> LogIterator<'_, T> {
+        let first = self.version().and_then(|version| match version {
+            Some(version) => Ok(Some((version, self.first_position(version)?))),
+            None => Ok(None),
+        });
+        let ((version, first), error) = match first {
+            Ok(Some((version, first))) => ((Some(version), first), None),
+            Ok(None) => ((None, 0), None),
+            Err(e) => ((None, 0), Some(e)),
+        };
+        LogIterator {
+            log: &self.storage,
+            checksum: self.checksum,
+            max_entry_size: self.max_entry_size,
+            version,
+            position: start.max(first),
+            end,
+            buf: Vec::new(),
+            error,
+        }
+    }
+
+    // Call f with every entry, each read into the same buffer instead of an allocation of its
+    // own. Ends at the end of the log like the iterator, and at the first error, which is returned.
+    pub fn for_each_entry(&self, mut f: impl FnMut(&[u8])) -> crate::Result<()> {
+        let mut entries = self.iter_range(0, u64::MAX);
+        if let Some(e) = entries.error.take() {
+            return Err(e);
+        }
+        while let Some(size) = entries.read_entry()? {
+            f(&entries.buf[..size]);
+        }
         Ok(())
     }
 
-    pub fn flush(&self) -> std::io::Result<()> {
-        self.storage.borrow_mut().flush()
+    // Iterate over the entries, skipping over frames that fail their checksum or run past the
+    // end of the log instead of stopping at them. After a bad frame every later offset is tried
+    // until a valid frame starts there, and the bytes in between are reported as skipped. This is
+    // best-effort: without checksums a bad frame can't be told apart from a good one, so the
+    // rest of the log is reported as skipped.
+    pub fn iter_recover(&self) -> RecoverIterator<'_, T> {
+        let first = self.version().and_then(|version| match version {
+            Some(version) => Ok(Some((version, self.first_position(version)?, self.end()?))),
+            None => Ok(None),
+        });
+        let ((version, position, end), error) = match first {
+            Ok(Some((version, position, end))) => ((Some(version), position, end), None),
+            Ok(None) => ((None, 0, 0), None),
+            Err(e) => ((None, 0, 0), Some(e)),
+        };
+        RecoverIterator {
+            log: self,
+            version,
+            position,
+            end,
+            buf: Vec::new(),
+            error,
+        }
+    }
+
+    // End of the last entry that can be read back whole, where a torn or corrupt tail left by an
+    // interrupted append starts. Entries are checked against their checksums when the log has
+    // them, otherwise only whether their declared size fits in the log. 0 for an empty log.
+    pub fn valid_len(&self) -> crate::Result<u64> {
+        let mut entries = self.iter_recover();
+        loop {
+            let position = entries.position;
+            match entries.next().transpose()? {
+                Some(Recovered::Entry(_)) => continue,
+                Some(Recovered::Skipped { .. }) => return Ok(position),
+                None => return Ok(entries.position),
+            }
+        }
+    }
+
+    // Hand appended entries to the storage. For files that means the OS, so they survive the
+    // process crashing but can still be lost to a power loss. See sync_all.
+    pub fn flush(&self) -> crate::Result<()> {
+        Ok(self.storage.borrow_mut().flush()?)
+    }
+
+    // Offset the next entry will be appended at, once the log has a header
+    pub fn end(&self) -> crate::Result<u64> {
+        Ok(self.storage.borrow_mut().seek(SeekFrom::End(0))?)
+    }
+
+    // Whether integers in the log are little-endian, as they are from version 3 on. Entries
+    // encoding integers of their own should follow it.
+    pub fn is_little_endian(&self) -> crate::Result<bool> {
+        Ok(self.version()?.is_none_or(|version| version >= FIRST_LITTLE_ENDIAN_VERSION))
+    }
+
+    // Bytes held by the log, including its header unless it was truncated away
+    pub fn len_bytes(&self) -> crate::Result<u64> {
+        let mut storage = self.storage.borrow_mut();
+        let start = storage.seek(SeekFrom::Start(0))?;
+        Ok(storage.seek(SeekFrom::End(0))? - start)
+    }
+
+    // Number of complete entries, counted from their frame headers without reading payloads
+    pub fn count_entries(&self) -> crate::Result<usize> {
+        let mut count = 0;
+        self.walk_frames(|_| count += 1)?;
+        Ok(count)
+    }
+
+    // Entries from newest to oldest. Frames can only be found going forward, so this first walks
+    // all frame headers to collect their offsets, one u64 per entry, before reading any payload.
+    pub fn iter_rev(&self) -> impl Iterator<Item = crate::Result<Box<[u8]>>> + '_ {
+        let mut offsets = Vec::new();
+        let error = self.walk_frames(|offset| offsets.push(offset)).err();
+        if error.is_some() {
+            offsets.clear();
+        }
+        error.into_iter()
+            .map(Err)
+            .chain(offsets.into_iter().rev().map(|offset| self.read_at(offset)))
+    }
+
+    // Offsets of all complete frames, found from their headers without reading payloads, so
+    // entries can be read by number with get_entry. Costs 8 bytes per entry.
+    pub fn build_index(&self) -> crate::Result<Vec<u64>> {
+        let mut index = Vec::new();
+        self.walk_frames(|offset| index.push(offset))?;
+        Ok(index)
+    }
+
+    // Read entry number n of an index built by build_index
+    pub fn get_entry(&self, index: &[u64], n: usize) -> crate::Result<Box<[u8]>> {
+        match index.get(n) {
+            Some(&offset) => self.read_at(offset),
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("No entry {} in an index of {} entries", n, index.len())).into()),
+        }
+    }
+
+    // Call f with the offset of every complete frame in order, skipping over the payloads
+    fn walk_frames(&self, mut f: impl FnMut(u64)) -> crate::Result<()> {
+        let Some(version) = self.version()? else {
+            return Ok(());
+        };
+        let mut position = self.first_position(version)?;
+        let end = self.end()?;
+        let header_len = frame_header_len(version, self.checksum) as u64;
+
+        let mut storage = self.storage.borrow_mut();
+        let mut size_bytes = [0; 8];
+        let size_bytes = &mut size_bytes[..size_len(version)];
+        while position + header_len <= end {
+            storage.seek(SeekFrom::Start(position))?;
+            storage.read_exact(size_bytes)?;
+            let frame_end = position + header_len + decode_size(size_bytes, version);
+            // A torn last frame ends the log
+            if frame_end > end {
+                break;
+            }
+            f(position);
+            position = frame_end;
+        }
+        Ok(())
+    }
+
+    // Offset of the first entry, past the header and whatever was truncated. Storage that lost
+    // its front reports the first position kept when asked to seek to 0.
+    fn first_position(&self, version: u8) -> crate::Result<u64> {
+        let start = self.storage.borrow_mut().seek(SeekFrom::Start(0))?;
+        let header_len = if version == LEGACY_LOG_VERSION { 0 } else { LOG_HEADER_LEN };
+        Ok(start.max(header_len))
+    }
+
+    // Format version of the stored log, None for an empty one. A log not starting with the
+    // header was written before it existed.
+    fn version(&self) -> crate::Result<Option<u8>> {
+        if let Some(version) = self.version.get() {
+            return Ok(Some(version));
+        }
+        let mut storage = self.storage.borrow_mut();
+        if storage.seek(SeekFrom::End(0))? == 0 {
+            return Ok(None);
+        }
+        // Only logs with a header are ever truncated, and they are in the current format
+        if storage.seek(SeekFrom::Start(0))? > 0 {
+            self.version.set(Some(LOG_FORMAT_VERSION));
+            return Ok(Some(LOG_FORMAT_VERSION));
+        }
+        let mut header = [0u8; LOG_HEADER_LEN as usize];
+        let version = match read_full(&mut *storage, &mut header)? {
+            true if header[..4] == LOG_MAGIC => header[4],
+            _ => LEGACY_LOG_VERSION,
+        };
+        if version > LOG_FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion { found: version.into(), supported: LOG_FORMAT_VERSION.into() });
+        }
+        self.version.set(Some(version));
+        Ok(Some(version))
+    }
+}
+
+// Entry sizes are u64 from version 2 on and u32 before
+fn encode_size(size: u64, version: u8) -> Vec<u8> {
+    match version {
+        LEGACY_LOG_VERSION => (size as u32).to_be_bytes().to_vec(),
+        v if v < FIRST_LITTLE_ENDIAN_VERSION => size.to_be_bytes().to_vec(),
+        _ => size.to_le_bytes().to_vec(),
+    }
+}
+
+fn decode_size(size_bytes: &[u8], version: u8) -> u64 {
+    match *size_bytes {
+        [a, b, c, d] => u32::from_be_bytes([a, b, c, d]) as u64,
+        _ if version < FIRST_LITTLE_ENDIAN_VERSION => u64::from_be_bytes(size_bytes.try_into().unwrap()),
+        _ => u64::from_le_bytes(size_bytes.try_into().unwrap()),
+    }
+}
+
+fn encode_checksum(checksum: u32, version: u8) -> [u8; 4] {
+    if version < FIRST_LITTLE_ENDIAN_VERSION {
+        checksum.to_be_bytes()
+    } else {
+        checksum.to_le_bytes()
     }
 }
 
+fn size_len(version: u8) -> usize {
+    if version == LEGACY_LOG_VERSION { 4 } else { 8 }
+}
+
 impl<'a, T> IntoIterator for &'a Log<T>
     where T: Read + Write + Seek {
-    type Item = Box<[u8]>;
+    type Item = crate::Result<Box<[u8]>>;
     type IntoIter = LogIterator<'a, T>;
 
     fn into_iter(self) -> Self::IntoIter {
-        LogIterator {
-            log: &self.storage,
-            position: 0,
+        self.iter_range(0, u64::MAX)
+    }
+}
+
+impl Log<FileSegmentStream> {
+    // Drop the entries before offset, which must be the offset of an entry or the end of the
+    // log. A truncated log loses its header and is taken to be in the current format, so logs
+    // in older formats are kept whole.
+    pub fn truncate_to(&mut self, offset: u64) -> crate::Result<()> {
+        match self.version()? {
+            Some(LOG_FORMAT_VERSION) => self.storage.borrow_mut().truncate_to(offset),
+            _ => Ok(()),
+        }
+    }
+
+    // Rewrite the log keeping only the newest entry of every key, as key_of extracts it from an
+    // entry, in the order of those entries. They are appended behind the others and synced
+    // before truncate_to drops the others, so a crash leaves either the old entries or the
+    // compacted ones. Offsets from before no longer point to entries. Logs in older formats
+    // can't be truncated and aren't compacted.
+    pub fn compact(&mut self, key_of: impl Fn(&[u8]) -> &[u8]) -> crate::Result<()> {
+        match self.version()? {
+            Some(LOG_FORMAT_VERSION) => {}
+            None => return Ok(()),
+            Some(_) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+                    "Logs in an older format can't be compacted").into());
+            }
+        }
+        let index = self.build_index()?;
+        let end = self.end()?;
+
+        // Number of the newest entry of every key
+        let mut newest = HashMap::new();
+        for (n, &offset) in index.iter().enumerate() {
+            newest.insert(key_of(&self.read_at(offset)?).to_vec(), n);
+        }
+        let mut kept: Vec<usize> = newest.into_values().collect();
+        kept.sort_unstable();
+
+        for n in kept {
+            let entry = self.read_at(index[n])?;
+            self.append(&entry)?;
+        }
+        self.sync_all()?;
+        self.truncate_to(end)
+    }
+
+    // Flush and have the appended entries reach the disk, so they survive a power loss
+    pub fn sync_all(&self) -> crate::Result<()> {
+        Ok(self.storage.borrow_mut().sync_all()?)
+    }
+
+    // A reader with its own handle on the segment files, iterating over the entries while the
+    // log keeps being appended to. Entries appended later are read once the log is flushed.
+    pub fn reader(&self) -> crate::Result<LogReader> {
+        let version = self.version()?;
+        let position = match version {
+            Some(version) => self.first_position(version)?,
+            None => 0,
+        };
+        Ok(LogReader {
+            storage: self.storage.borrow().reader(),
+            checksum: self.checksum,
+            max_entry_size: self.max_entry_size,
+            version,
+            position,
             buf: Vec::new(),
+            failed: false,
+        })
+    }
+}
+
+// Iterates over the entries of a log independently of its writer. Unlike LogIterator, reaching
+// the end only pauses the iteration: next returns None until more entries are flushed.
+pub struct LogReader {
+    storage: SegmentReader,
+    checksum: bool,
+    max_entry_size: usize,
+    // None until the log has a header
+    version: Option<u8>,
+    position: u64,
+    buf: Vec<u8>,
+    // Set once an error was yielded, after which the iteration ends
+    failed: bool,
+}
+
+impl LogReader {
+    // Offset of the next entry to read
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // The header is only looked for when the log was empty as the reader was created, in which
+    // case the writer starts it in the current format
+    fn read_version(&mut self) -> crate::Result<Option<u8>> {
+        if self.version.is_none() {
+            let mut header = [0u8; LOG_HEADER_LEN as usize];
+            self.storage.seek(SeekFrom::Start(0))?;
+            if read_full(&mut self.storage, &mut header)? {
+                if header[..4] == LOG_MAGIC && header[4] > LOG_FORMAT_VERSION {
+                    return Err(Error::UnsupportedVersion { found: header[4].into(), supported: LOG_FORMAT_VERSION.into() });
+                }
+                if header[..4] != LOG_MAGIC || header[4] != LOG_FORMAT_VERSION {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid log header").into());
+                }
+                self.version = Some(LOG_FORMAT_VERSION);
+                self.position = LOG_HEADER_LEN;
+            }
+        }
+        Ok(self.version)
+    }
+
+    fn read_entry(&mut self) -> crate::Result<Option<usize>> {
+        let Some(version) = self.read_version()? else {
+            return Ok(None);
+        };
+        let size = read_frame(&mut self.storage, self.position, version, self.checksum, self.max_entry_size, &mut self.buf)?;
+        if let Some(size) = size {
+            self.position += (frame_header_len(version, self.checksum) + size) as u64;
         }
+        Ok(size)
     }
 }
 
+// A frame that isn't fully flushed yet is read again by the next call
+impl Iterator for LogReader {
+    type Item = crate::Result<Box<[u8]>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.read_entry() {
+            Ok(Some(size)) => Some(Ok(Box::from(&self.buf[..size]))),
+            Ok(None) => None,
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn entry_checksum(size_bytes: &[u8], entry: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(size_bytes);
+    hasher.update(entry);
+    hasher.finalize()
+}
+
 pub struct LogIterator<'a, T>
     where T: Read + Write + Seek {
     log: &'a RefCell<T>,
+    checksum: bool,
+    max_entry_size: usize,
+    // None for an empty log
+    version: Option<u8>,
     position: u64,
+    // Position at which iteration stops
+    end: u64,
     buf: Vec<u8>,
+    // Error hit before the first entry, yielded by the first call to next
+    error: Option<Error>,
+}
+
+impl<'a, T> LogIterator<'a, T>
+    where T: Read + Write + Seek {
+    // Read the entry at the current position into the buffer and return its size.
+    // An entry torn by an interrupted append ends the log just like a clean EOF.
+    fn read_entry(&mut self) -> crate::Result<Option<usize>> {
+        let Some(version) = self.version else {
+            return Ok(None);
+        };
+        if self.position >= self.end {
+            return Ok(None);
+        }
+        let size = read_frame(&mut *self.log.borrow_mut(), self.position, version, self.checksum, self.max_entry_size,
+            &mut self.buf)?;
+        if let Some(size) = size {
+            self.position += (frame_header_len(version, self.checksum) + size) as u64;
+        }
+        Ok(size)
+    }
+}
+
+// Read the entry of the frame at the position into the buffer and return its size, or None
+// if the log ends before the frame does. A size above max_size fails before anything is allocated.
+fn read_frame<R: Read + Seek>(log: &mut R, position: u64, version: u8, checksum: bool, max_size: usize, buf: &mut Vec<u8>)
+    -> crate::Result<Option<usize>> {
+    log.seek(SeekFrom::Start(position))?;
+
+    let mut size_bytes = [0; 8];
+    let size_bytes = &mut size_bytes[..size_len(version)];
+    if !read_full(log, size_bytes)? {
+        return Ok(None);
+    }
+    let size = usize::try_from(decode_size(size_bytes, version))
+        .ok()
+        .filter(|&size| size <= max_size)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Log entry size exceeds the limit"))?;
+
+    let mut checksum_bytes = [0; 4];
+    if checksum && !read_full(log, &mut checksum_bytes)? {
+        return Ok(None);
+    }
+
+    if buf.len() < size {
+        buf.resize(size, 0);
+    }
+    if !read_full(log, &mut buf[..size])? {
+        return Ok(None);
+    }
+
+    if checksum && checksum_bytes != encode_checksum(entry_checksum(size_bytes, &buf[..size]), version) {
+        return Err(Error::ChecksumMismatch);
+    }
+    Ok(Some(size))
+}
+
+// Length of the size prefix and checksum in front of an entry
+fn frame_header_len(version: u8, checksum: bool) -> usize {
+    size_len(version) + if checksum { 4 } else { 0 }
 }
 
+// A clean or torn end of the log ends the iteration. Read errors and checksum mismatches are
+// yielded once, after which the iteration ends too.
 impl<'a, T> Iterator for LogIterator<'a, T>
     where T: Read + Write + Seek {
-    type Item = Box<[u8]>;
+    type Item = crate::Result<Box<[u8]>>;
     
     fn next(&mut self) -> Option<Self::Item> {
-        let mut log = self.log.borrow_mut();
-        if log.seek(SeekFrom::Start(self.position)).is_err() {
-            return None;
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        match self.read_entry() {
+            Ok(Some(size)) => Some(Ok(Box::from(&self.buf[..size]))),
+            Ok(None) => None,
+            Err(e) => {
+                self.version = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// Item of Log::iter_recover
+#[derive(Debug, PartialEq)]
+pub enum Recovered {
+    Entry(Box<[u8]>),
+    // Bytes from start to end that hold no valid frame
+    Skipped { start: u64, end: u64 },
+}
+
+pub struct RecoverIterator<'a, T>
+    where T: Read + Write + Seek {
+    log: &'a Log<T>,
+    // None for an empty log, or once an error was yielded
+    version: Option<u8>,
+    position: u64,
+    // End of the log when the iteration started
+    end: u64,
+    buf: Vec<u8>,
+    // Error hit before the first entry, yielded by the first call to next
+    error: Option<Error>,
+}
+
+impl<'a, T> RecoverIterator<'a, T>
+    where T: Read + Write + Seek {
+    // Read the frame at a position into the buffer and return its size, or None if it isn't a
+    // valid frame ending within the log
+    fn read_valid(&mut self, position: u64, version: u8) -> crate::Result<Option<usize>> {
+        let header_len = frame_header_len(version, self.log.checksum) as u64;
+        if position + header_len > self.end {
+            return Ok(None);
         }
+        let mut storage = self.log.storage.borrow_mut();
+        let mut size_bytes = [0; 8];
+        let size_bytes = &mut size_bytes[..size_len(version)];
+        storage.seek(SeekFrom::Start(position))?;
+        storage.read_exact(size_bytes)?;
+        // Checked before reading, a garbage size must not be allocated
+        let size = decode_size(size_bytes, version);
+        let frame_end = (position + header_len).checked_add(size);
+        if frame_end.is_none_or(|frame_end| frame_end > self.end) || size > self.log.max_entry_size as u64 {
+            return Ok(None);
+        }
+        match read_frame(&mut *storage, position, version, self.log.checksum, self.log.max_entry_size, &mut self.buf) {
+            Err(Error::ChecksumMismatch) => Ok(None),
+            result => result,
+        }
+    }
 
-        let mut size_bytes = [0; 4];
-        match log.read(&mut size_bytes) {
-            Ok(0) => return None,
-            Err(_) => return None,
-            _ => {}
+    // Offset of the first valid frame after a bad one, or the end of the log
+    fn resync(&mut self, from: u64, version: u8) -> crate::Result<u64> {
+        if !self.log.checksum {
+            return Ok(self.end);
         }
+        for position in from..self.end {
+            if self.read_valid(position, version)?.is_some() {
+                return Ok(position);
+            }
+        }
+        Ok(self.end)
+    }
 
-        let size = u32::from_be_bytes(size_bytes) as usize;
-        if self.buf.len() < size {
-            self.buf.resize(size, 0);
+    fn next_item(&mut self, version: u8) -> crate::Result<Recovered> {
+        if let Some(size) = self.read_valid(self.position, version)? {
+            self.position += (frame_header_len(version, self.log.checksum) + size) as u64;
+            return Ok(Recovered::Entry(Box::from(&self.buf[..size])));
         }
+        let start = self.position;
+        self.position = self.resync(start + 1, version)?;
+        Ok(Recovered::Skipped { start, end: self.position })
+    }
+}
 
-        match log.read(&mut self.buf[..size]) {
-            Ok(0) => return None,
-            Err(_) => return None,
-            _ => {}
+// Read errors are yielded once, after which the iteration ends
+impl<'a, T> Iterator for RecoverIterator<'a, T>
+    where T: Read + Write + Seek {
+    type Item = crate::Result<Recovered>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
         }
+        let version = self.version?;
+        if self.position >= self.end {
+            return None;
+        }
+        let item = self.next_item(version);
+        if item.is_err() {
+            self.version = None;
+        }
+        Some(item)
+    }
+}
 
-        self.position += 4 + size as u64;
-        Some(Box::from(&self.buf[..size]))
+// Fill the buffer completely, returning false if the stream ends first
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::log::Log;
+    use crate::log::{encode_size, Log, Recovered, LEGACY_LOG_VERSION, LOG_FORMAT_VERSION};
+    use crate::Error;
+    use crate::streams::FileSegmentStream;
     use std::cell::RefCell;
 
     #[test]
@@ -106,4 +740,457 @@ mod tests {
         }
         assert_eq!(count, count);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_log_checksum_detects_corruption() {
+        let storage:Vec<u8> = Vec::new();
+        let cursor = RefCell::new(std::io::Cursor::new(storage));
+        let mut log = Log::new_with_checksum(cursor);
+        for i in 0..3u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+        assert_eq!(log.into_iter().count(), 3);
+
+        // Flip a payload byte of the second entry
+        log.storage.borrow_mut().get_mut()[5 + 22 + 12 + 2] ^= 0xFF;
+
+        let mut iter = log.into_iter();
+        assert_eq!(&*iter.next().unwrap().unwrap(), &[0; 10]);
+        assert!(matches!(iter.next(), Some(Err(Error::ChecksumMismatch))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_log_read_at() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSegmentStream::new(dir.path().to_path_buf(), 16);
+        let mut log = Log::new_with_checksum(RefCell::new(storage));
+        let entries: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 10 + i as usize]).collect();
+        let offsets: Vec<u64> = entries.iter().map(|entry| log.append(entry).unwrap()).collect();
+        assert_eq!(offsets[0], 5);
+
+        for (offset, entry) in offsets.iter().zip(&entries).rev() {
+            assert_eq!(&*log.read_at(*offset).unwrap(), &entry[..]);
+        }
+        assert!(log.read_at(1000).is_err());
+        assert_eq!(log.into_iter().count(), entries.len());
+    }
+
+    #[test]
+    fn test_log_iter_from() {
+        let storage: Vec<u8> = Vec::new();
+        let mut log = Log::new(RefCell::new(std::io::Cursor::new(storage)));
+        let offsets: Vec<u64> = (0..10u8).map(|i| log.append(&[i; 4]).unwrap()).collect();
+
+        let tail: Vec<_> = log.iter_from(offsets[5]).map(Result::unwrap).collect();
+        assert_eq!(tail, (5..10u8).map(|i| Box::from([i; 4])).collect::<Vec<_>>());
+
+        let range: Vec<_> = log.iter_range(offsets[2], offsets[4]).map(Result::unwrap).collect();
+        assert_eq!(range, vec![Box::from([2; 4]), Box::from([3; 4])]);
+        assert_eq!(log.iter_from(0).count(), 10);
+    }
+
+    #[test]
+    fn test_log_truncate_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSegmentStream::new(dir.path().to_path_buf(), 32);
+        let mut log = Log::new(RefCell::new(storage));
+        let offsets: Vec<u64> = (0..10u8).map(|i| log.append(&[i; 10]).unwrap()).collect();
+        let log_files = || dir.path().read_dir().unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "log"))
+            .count();
+        let files = log_files();
+
+        log.truncate_to(offsets[6]).unwrap();
+        assert!(log_files() < files);
+        assert_eq!(log.into_iter().count(), 4);
+        assert_eq!(&*log.read_at(offsets[6]).unwrap(), &[6; 10]);
+        assert!(log.read_at(offsets[0]).is_err());
+        log.flush().unwrap();
+
+        // Offsets survive reopening
+        let storage = FileSegmentStream::new(dir.path().to_path_buf(), 32);
+        let mut log = Log::new(RefCell::new(storage));
+        let entries: Vec<_> = log.into_iter().map(Result::unwrap).collect();
+        assert_eq!(entries, (6..10u8).map(|i| Box::from([i; 10])).collect::<Vec<_>>());
+        let offset = log.append(&[10; 10]).unwrap();
+        assert_eq!(&*log.read_at(offset).unwrap(), &[10; 10]);
+        assert_eq!(&*log.read_at(offsets[9]).unwrap(), &[9; 10]);
+
+        log.truncate_to(log.end().unwrap()).unwrap();
+        assert_eq!(log.into_iter().count(), 0);
+        assert_eq!(log_files(), 1);
+
+        log.append(&[11; 10]).unwrap();
+        log.sync_all().unwrap();
+        let storage = FileSegmentStream::new(dir.path().to_path_buf(), 32);
+        let log = Log::new(RefCell::new(storage));
+        assert_eq!(log.into_iter().map(Result::unwrap).collect::<Vec<_>>(), [Box::from([11; 10])]);
+    }
+
+    #[test]
+    fn test_log_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSegmentStream::new(dir.path().to_path_buf(), 64);
+        let mut log = Log::new_with_checksum(RefCell::new(storage));
+        // Entries are "key=value", keyed by what comes before the '='
+        fn key_of(entry: &[u8]) -> &[u8] {
+            let end = entry.iter().position(|&b| b == b'=').unwrap_or(entry.len());
+            &entry[..end]
+        }
+        for version in 0..5 {
+            for key in ["a", "b", "c"] {
+                log.append(format!("{}={}", key, version).as_bytes()).unwrap();
+            }
+        }
+        log.append(b"b=6").unwrap();
+        log.append(b"d=0").unwrap();
+        let len = log.len_bytes().unwrap();
+
+        log.compact(key_of).unwrap();
+        let expected: Vec<Box<[u8]>> = ["a=4", "c=4", "b=6", "d=0"].iter().map(|e| Box::from(e.as_bytes())).collect();
+        assert_eq!(log.into_iter().map(Result::unwrap).collect::<Vec<_>>(), expected);
+        assert!(log.len_bytes().unwrap() < len);
+
+        // The compacted log is what a reopened one reads, and can be appended to
+        let storage = FileSegmentStream::new(dir.path().to_path_buf(), 64);
+        let mut log = Log::new_with_checksum(RefCell::new(storage));
+        assert_eq!(log.into_iter().map(Result::unwrap).collect::<Vec<_>>(), expected);
+        log.append(b"a=5").unwrap();
+        log.compact(key_of).unwrap();
+        assert_eq!(log.into_iter().map(Result::unwrap).collect::<Vec<_>>(), [&expected[1..], &[Box::from(&b"a=5"[..])]].concat());
+    }
+
+    #[test]
+    fn test_log_reader_while_appending() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSegmentStream::new(dir.path().to_path_buf(), 64);
+        let mut log = Log::new_with_checksum(RefCell::new(storage));
+        let mut empty_reader = log.reader().unwrap();
+        assert!(empty_reader.next().is_none());
+
+        for i in 0..5u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+        log.flush().unwrap();
+        let mut reader = log.reader().unwrap();
+        assert_eq!(&*reader.next().unwrap().unwrap(), &[0; 10]);
+
+        for i in 5..10u8 {
+            log.append(&[i; 10]).unwrap();
+        }
+        log.flush().unwrap();
+        let rest: Vec<_> = reader.by_ref().map(Result::unwrap).collect();
+        assert_eq!(rest, (1..10u8).map(|i| Box::from([i; 10])).collect::<Vec<_>>());
+        assert_eq!(reader.position(), log.end().unwrap());
+        assert_eq!(empty_reader.count(), 10);
+
+        // Iteration picks up again after reaching the end
+        log.append(&[10; 10]).unwrap();
+        log.flush().unwrap();
+        assert_eq!(&*reader.next().unwrap().unwrap(), &[10; 10]);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_log_iter_recover() {
+        let mut log = Log::new_with_checksum(RefCell::new(std::io::Cursor::new(Vec::new())));
+        let offsets: Vec<u64> = (1..=3u8).map(|i| log.append(&[i; 10]).unwrap()).collect();
+        log.storage.borrow_mut().get_mut()[offsets[1] as usize + 15] ^= 0xff;
+        assert!(matches!(log.into_iter().nth(1), Some(Err(Error::ChecksumMismatch))));
+
+        let recovered: Vec<_> = log.iter_recover().map(Result::unwrap).collect();
+        assert_eq!(recovered, vec![
+            Recovered::Entry(Box::from([1; 10])),
+            Recovered::Skipped { start: offsets[1], end: offsets[2] },
+            Recovered::Entry(Box::from([3; 10])),
+        ]);
+
+        // A torn last frame is skipped to the end
+        log.storage.borrow_mut().get_mut().truncate(offsets[2] as usize + 14);
+        let recovered: Vec<_> = log.iter_recover().map(Result::unwrap).collect();
+        assert_eq!(recovered[1..], [Recovered::Skipped { start: offsets[1], end: offsets[2] + 14 }]);
+    }
+
+    #[test]
+    fn test_log_valid_len() {
+        let mut log = Log::new(RefCell::new(std::io::Cursor::new(Vec::new())));
+        assert_eq!(log.valid_len().unwrap(), 0);
+        log.append(&[1; 10]).unwrap();
+        log.append(&[2; 10]).unwrap();
+        let end = log.end().unwrap();
+        assert_eq!(log.valid_len().unwrap(), end);
+
+        // A length prefix whose payload never made it
+        let mut storage = log.storage.borrow_mut();
+        storage.get_mut().extend_from_slice(&encode_size(100, LOG_FORMAT_VERSION));
+        storage.get_mut().extend_from_slice(&[3; 10]);
+        drop(storage);
+        assert_eq!(log.valid_len().unwrap(), end);
+
+        let mut log = Log::new_with_checksum(RefCell::new(std::io::Cursor::new(Vec::new())));
+        log.append(&[1; 10]).unwrap();
+        let offset = log.append(&[2; 10]).unwrap();
+        log.storage.borrow_mut().get_mut()[offset as usize + 15] ^= 0xff;
+        assert_eq!(log.valid_len().unwrap(), offset);
+    }
+
+    #[test]
+    fn test_log_index() {
+        let mut log = Log::new_with_checksum(RefCell::new(std::io::Cursor::new(Vec::new())));
+        for i in 0..100u32 {
+            log.append(&i.to_le_bytes().repeat(i as usize % 7)).unwrap();
+        }
+        let index = log.build_index().unwrap();
+        assert_eq!(index.len(), 100);
+
+        let entries: Vec<_> = log.into_iter().map(Result::unwrap).collect();
+        let mut n = 0usize;
+        for _ in 0..200 {
+            n = (n * 37 + 11) % 100;
+            assert_eq!(log.get_entry(&index, n).unwrap(), entries[n]);
+        }
+        assert!(log.get_entry(&index, 100).is_err());
+    }
+
+    // Storage that fails reads past a position
+    struct FailingStorage {
+        inner: std::io::Cursor<Vec<u8>>,
+        fail_after: u64,
+    }
+
+    impl std::io::Read for FailingStorage {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.inner.position() >= self.fail_after {
+                return Err(std::io::Error::other("disk failure"));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl std::io::Write for FailingStorage {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for FailingStorage {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_log_iterator_yields_read_errors() {
+        let storage = FailingStorage { inner: std::io::Cursor::new(Vec::new()), fail_after: u64::MAX };
+        let mut log = Log::new(RefCell::new(storage));
+        let offsets: Vec<u64> = (0..3u8).map(|i| log.append(&[i; 10]).unwrap()).collect();
+        log.storage.borrow_mut().fail_after = offsets[2];
+
+        let mut iter = log.into_iter();
+        assert_eq!(&*iter.next().unwrap().unwrap(), &[0; 10]);
+        assert_eq!(&*iter.next().unwrap().unwrap(), &[1; 10]);
+        assert!(matches!(iter.next(), Some(Err(Error::Io(e))) if e.to_string() == "disk failure"));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_log_for_each_entry() {
+        let mut log = Log::new_with_checksum(RefCell::new(std::io::Cursor::new(Vec::new())));
+        let mut count = 0;
+        log.for_each_entry(|_| count += 1).unwrap();
+        assert_eq!(count, 0);
+
+        for i in 0..10u8 {
+            log.append(&vec![i; 10 - i as usize]).unwrap();
+        }
+        let mut entries = Vec::new();
+        log.for_each_entry(|entry| entries.push(Box::from(entry))).unwrap();
+        assert_eq!(entries, log.into_iter().map(Result::unwrap).collect::<Vec<_>>());
+
+        let storage = FailingStorage { inner: std::io::Cursor::new(Vec::new()), fail_after: u64::MAX };
+        let mut log = Log::new(RefCell::new(storage));
+        let offsets: Vec<u64> = (0..3u8).map(|i| log.append(&[i; 10]).unwrap()).collect();
+        log.storage.borrow_mut().fail_after = offsets[2];
+        let mut count = 0;
+        assert!(log.for_each_entry(|_| count += 1).is_err());
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_log_append_batch() {
+        let entries: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 3 + i as usize]).collect();
+        let entries: Vec<&[u8]> = entries.iter().map(|e| &e[..]).collect();
+
+        let mut single = Log::new_with_checksum(RefCell::new(std::io::Cursor::new(Vec::new())));
+        for entry in &entries {
+            single.append(entry).unwrap();
+        }
+        let mut batched = Log::new_with_checksum(RefCell::new(std::io::Cursor::new(Vec::new())));
+        batched.append_batch(&entries[..2]).unwrap();
+        batched.append_batch(&entries[2..]).unwrap();
+        batched.append_batch(&[]).unwrap();
+
+        assert_eq!(single.storage.borrow().get_ref(), batched.storage.borrow().get_ref());
+    }
+
+    #[test]
+    fn test_log_count_entries() {
+        let mut log = Log::new_with_checksum(RefCell::new(std::io::Cursor::new(Vec::new())));
+        assert_eq!(log.count_entries().unwrap(), 0);
+        assert_eq!(log.len_bytes().unwrap(), 0);
+
+        for i in 0..20u8 {
+            log.append(&vec![i; 100 * i as usize]).unwrap();
+        }
+        assert_eq!(log.count_entries().unwrap(), 20);
+        assert_eq!(log.len_bytes().unwrap(), 5 + 20 * 12 + (0..20).map(|i| 100 * i).sum::<u64>());
+
+        // A torn last entry isn't counted
+        let len = log.storage.borrow().get_ref().len();
+        log.storage.borrow_mut().get_mut().truncate(len - 1);
+        assert_eq!(log.count_entries().unwrap(), 19);
+    }
+
+    #[test]
+    fn test_log_iter_rev() {
+        let mut log = Log::new(RefCell::new(std::io::Cursor::new(Vec::new())));
+        assert_eq!(log.iter_rev().count(), 0);
+        for i in 0..10u8 {
+            log.append(&[i; 3]).unwrap();
+        }
+
+        let entries: Vec<_> = log.iter_rev().map(Result::unwrap).collect();
+        assert_eq!(entries, (0..10u8).rev().map(|i| Box::from([i; 3])).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_log_byte_order() {
+        let mut log = Log::new_with_checksum(RefCell::new(std::io::Cursor::new(Vec::new())));
+        log.append(b"entry").unwrap();
+        assert!(log.is_little_endian().unwrap());
+
+        let data = log.storage.borrow().get_ref().clone();
+        assert_eq!(&data[..5], b"KLOG\x03");
+        assert_eq!(&data[5..13], &5u64.to_le_bytes());
+        let checksum = crc32fast::hash(&[&5u64.to_le_bytes()[..], b"entry"].concat());
+        assert_eq!(&data[13..17], &checksum.to_le_bytes());
+        assert_eq!(&data[17..], b"entry");
+    }
+
+    #[test]
+    fn test_log_reads_version_2() {
+        // Version 2 stores sizes and checksums big-endian
+        let mut storage = b"KLOG\x02".to_vec();
+        for i in 0..3u8 {
+            storage.extend_from_slice(&10u64.to_be_bytes());
+            storage.extend_from_slice(&crc32fast::hash(&[&10u64.to_be_bytes()[..], &[i; 10]].concat()).to_be_bytes());
+            storage.extend_from_slice(&[i; 10]);
+        }
+        let mut log = Log::new_with_checksum(RefCell::new(std::io::Cursor::new(storage)));
+        assert!(!log.is_little_endian().unwrap());
+        log.append(&[3; 10]).unwrap();
+
+        let read: Vec<_> = log.into_iter().map(Result::unwrap).collect();
+        assert_eq!(read, (0..4u8).map(|i| Box::from([i; 10])).collect::<Vec<_>>());
+        assert_eq!(log.count_entries().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_log_refuses_newer_version() {
+        let mut storage = b"KLOG".to_vec();
+        storage.push(LOG_FORMAT_VERSION + 1);
+        storage.extend_from_slice(&[0; 16]);
+        let mut log = Log::new(RefCell::new(std::io::Cursor::new(storage)));
+        let supported = LOG_FORMAT_VERSION as u64;
+        assert!(matches!(log.is_little_endian(),
+            Err(Error::UnsupportedVersion { found, supported: s }) if found == supported + 1 && s == supported));
+        assert!(matches!(log.append(b"entry"), Err(Error::UnsupportedVersion { .. })));
+        assert!(matches!(log.into_iter().next(), Some(Err(Error::UnsupportedVersion { .. }))));
+    }
+
+    #[test]
+    fn test_log_reads_legacy_format() {
+        // Version 1 logs have no header and u32 entry sizes
+        let mut storage = Vec::new();
+        for i in 0..3u8 {
+            storage.extend_from_slice(&10u32.to_be_bytes());
+            storage.extend_from_slice(&[i; 10]);
+        }
+        let mut log = Log::new(RefCell::new(std::io::Cursor::new(storage)));
+        log.append(&[3; 10]).unwrap();
+
+        let read: Vec<_> = log.into_iter().map(Result::unwrap).collect();
+        assert_eq!(read.len(), 4);
+        assert_eq!(&*read[3], &[3; 10]);
+        assert_eq!(log.storage.borrow().get_ref().len(), 4 * 14);
+    }
+
+    #[test]
+    fn test_log_size_encoding() {
+        let boundary = u32::MAX as u64;
+        assert_eq!(encode_size(boundary, LEGACY_LOG_VERSION), boundary.to_be_bytes()[4..]);
+        assert_eq!(encode_size(boundary, 2), boundary.to_be_bytes());
+        assert_eq!(encode_size(boundary, LOG_FORMAT_VERSION), boundary.to_le_bytes());
+        assert_eq!(encode_size(boundary + 1, LOG_FORMAT_VERSION), (boundary + 1).to_le_bytes());
+    }
+
+    #[test]
+    #[ignore = "allocates more than 8 GiB"]
+    fn test_log_entry_larger_than_u32() {
+        let storage: Vec<u8> = Vec::new();
+        let mut log = Log::new(RefCell::new(std::io::Cursor::new(storage))).with_max_entry_size(usize::MAX);
+        let mut entry = vec![0; u32::MAX as usize + 1];
+        entry[u32::MAX as usize] = 1;
+        log.append(&entry).unwrap();
+        drop(entry);
+
+        let read = log.into_iter().next().unwrap().unwrap();
+        assert_eq!(read.len(), u32::MAX as usize + 1);
+        assert_eq!(read[u32::MAX as usize], 1);
+    }
+
+    #[test]
+    fn test_log_max_entry_size() {
+        let mut log = Log::new(RefCell::new(std::io::Cursor::new(Vec::new())));
+        log.append(b"entry").unwrap();
+        // A bogus size close to u32::MAX, which must fail instead of being allocated
+        let bogus = log.end().unwrap();
+        log.storage.borrow_mut().get_mut().extend_from_slice(&(u32::MAX as u64 - 1).to_le_bytes());
+        log.storage.borrow_mut().get_mut().extend_from_slice(b"garbage");
+
+        let mut entries = log.iter_from(0);
+        assert_eq!(&*entries.next().unwrap().unwrap(), b"entry");
+        let error = entries.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(entries.next().is_none());
+        assert!(entries.buf.capacity() < 1024);
+        assert!(log.read_at(bogus).is_err());
+        assert_eq!(log.valid_len().unwrap(), bogus);
+
+        let mut log = log.with_max_entry_size(4);
+        assert_eq!(log.append(b"entry").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert!(log.into_iter().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_log_entries_span_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileSegmentStream::new(dir.path().to_path_buf(), 16);
+        let mut log = Log::new(RefCell::new(storage));
+        let entries: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 40]).collect();
+        for entry in &entries {
+            log.append(entry).unwrap();
+        }
+
+        let read: Vec<_> = log.into_iter().map(Result::unwrap).collect();
+        assert_eq!(read.len(), entries.len());
+        for (read, entry) in read.iter().zip(&entries) {
+            assert_eq!(&**read, &entry[..]);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::{Path, PathBuf};
+
+// How numbered files such as segments are named: the number, zero-padded to a width, and an
+// extension. Padding makes the names sort the same way as the numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileNaming {
+    extension: String,
+    pad_width: usize,
+}
+
+impl FileNaming {
+    // The extension can't be empty, contain a dot or a path separator, or be "tmp", which marks
+    // files being written
+    pub fn new(extension: &str, pad_width: usize) -> crate::Result<FileNaming> {
+        if extension.is_empty() || extension == "tmp" || extension.contains(['.', '/', '\\']) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                format!("Invalid file extension {:?}", extension)).into());
+        }
+        Ok(FileNaming { extension: extension.to_owned(), pad_width })
+    }
+
+    // Unpadded names with an extension known to be valid
+    pub(crate) fn unpadded(extension: &str) -> FileNaming {
+        FileNaming { extension: extension.to_owned(), pad_width: 0 }
+    }
+
+    pub fn extension(&self) -> &str {
+        &self.extension
+    }
+
+    pub fn pad_width(&self) -> usize {
+        self.pad_width
+    }
+
+    pub fn file_name(&self, number: u64) -> String {
+        format!("{:0width$}.{}", number, self.extension, width = self.pad_width)
+    }
+
+    pub fn path(&self, dir: &Path, number: u64) -> PathBuf {
+        dir.join(self.file_name(number))
+    }
+
+    // Number of a file named by this scheme. Names it wouldn't produce, such as ones padded to
+    // another width, give None.
+    pub fn parse(&self, file_name: &str) -> Option<u64> {
+        let number = file_name.strip_suffix(&self.extension)?.strip_suffix('.')?;
+        if !number.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let parsed = number.parse().ok()?;
+        (self.file_name(parsed) == file_name).then_some(parsed)
+    }
+
+    pub fn parse_path(&self, path: &Path) -> Option<u64> {
+        self.parse(path.file_name()?.to_str()?)
+    }
+
+    // Whether the file has the extension of the scheme, whatever its name
+    pub fn has_extension(&self, path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext == self.extension.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_naming() {
+        let naming = FileNaming::unpadded("sst");
+        assert_eq!(naming.file_name(42), "42.sst");
+        assert_eq!(naming.parse("42.sst"), Some(42));
+
+        let naming = FileNaming::new("seg", 6).unwrap();
+        assert_eq!(naming.file_name(42), "000042.seg");
+        assert_eq!(naming.file_name(1234567), "1234567.seg");
+        assert_eq!(naming.parse("000042.seg"), Some(42));
+        assert_eq!(naming.parse("1234567.seg"), Some(1234567));
+        for name in ["42.seg", "0000042.seg", "000042.sst", "000042", "+00042.seg", "00004a.seg", ".seg"] {
+            assert_eq!(naming.parse(name), None, "{}", name);
+        }
+
+        for extension in ["", "tmp", "a.b", "a/b"] {
+            assert!(FileNaming::new(extension, 0).is_err());
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{Result, SSTEngine};
+
+// Handle to an engine that can be cloned and shared across threads. Reads take a shared lock and
+// run concurrently, writes take the exclusive lock and run one at a time while no read is in
+// progress. A write that panics poisons the lock, every later call then fails.
+#[derive(Clone)]
+pub struct SharedEngine {
+    engine: Arc<RwLock<SSTEngine>>,
+}
+
+impl SharedEngine {
+    pub fn new(engine: SSTEngine) -> SharedEngine {
+        SharedEngine { engine: Arc::new(RwLock::new(engine)) }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Box<[u8]>>> {
+        self.read()?.get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        self.read()?.contains_key(key)
+    }
+
+    // Returns the value the key had before, if any
+    pub fn insert(&self, key: &str, value: &[u8]) -> Result<Option<Box<[u8]>>> {
+        self.write()?.insert(key, value)
+    }
+
+    // Returns the value the key had before, if any
+    pub fn delete(&self, key: &str) -> Result<Option<Box<[u8]>>> {
+        self.write()?.delete(key)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.write()?.flush()
+    }
+
+    // Compact on a separate thread whenever the policy asks for it. Compaction takes the write
+    // lock like any other write, so writers wait for it instead of racing it and serials stay in
+    // order. The policy is checked under a read lock, so reads go on between compactions.
+    pub fn start_background_compaction(&self, policy: CompactionPolicy) -> CompactionHandle {
+        let engine = self.clone();
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(policy.interval) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => return Ok(()),
+            }
+            let due = policy.is_due(&*engine.read()?)?;
+            if due {
+                let mut engine = engine.write()?;
+                // A compaction through another handle may have happened in between
+                if policy.is_due(&engine)? {
+                    engine.compact()?;
+                }
+            }
+        });
+        CompactionHandle { stop, thread: Some(thread) }
+    }
+
+    // Shared access for anything else, blocking writers while the guard is held
+    pub fn read(&self) -> Result<RwLockReadGuard<'_, SSTEngine>> {
+        self.engine.read().map_err(|_| poisoned())
+    }
+
+    // Exclusive access for anything else, blocking readers and writers while the guard is held
+    pub fn write(&self) -> Result<RwLockWriteGuard<'_, SSTEngine>> {
+        self.engine.write().map_err(|_| poisoned())
+    }
+}
+
+// When background compaction runs
+#[derive(Debug, Clone)]
+pub struct CompactionPolicy {
+    // How often the engine is checked
+    pub interval: Duration,
+    // Compact once there are more segments than this, the active one included. Compaction
+    // writes segments of up to data_segment_size, so this must leave room for all the data or
+    // every check compacts again.
+    pub max_segments: usize,
+    // Compact once the segment files take more bytes than this
+    pub max_disk_bytes: Option<u64>,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy { interval: Duration::from_secs(1), max_segments: 8, max_disk_bytes: None }
+    }
+}
+
+impl CompactionPolicy {
+    fn is_due(&self, engine: &SSTEngine) -> Result<bool> {
+        let stats = engine.stats()?;
+        Ok(stats.segment_count > self.max_segments
+            || self.max_disk_bytes.is_some_and(|max| stats.disk_bytes > max))
+    }
+}
+
+// Background compaction started by SharedEngine::start_background_compaction. Dropping the handle
+// stops the thread too, but discards its error.
+pub struct CompactionHandle {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl CompactionHandle {
+    // Wait for a compaction in progress to finish and end the thread. Returns the error that
+    // ended it early, if any.
+    pub fn stop(mut self) -> Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<()> {
+        let _ = self.stop.send(());
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(std::io::Error::other("Background compaction panicked").into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CompactionHandle {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+fn poisoned() -> crate::Error {
+    std::io::Error::other("Engine lock poisoned by a panicked writer").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LogSync, SSTEngineOptions};
+    use tempfile::tempdir;
+
+    const KEYS: usize = 8;
+    const ROUNDS: u64 = 200;
+
+    #[test]
+    fn test_shared_engine_readers_and_writer() {
+        let root = tempdir().unwrap();
+        let options = SSTEngineOptions {
+            log_sync: LogSync::GroupCommit { max_operations: 64, max_bytes: usize::MAX, max_delay: Duration::from_millis(10) },
+            ..Default::default()
+        };
+        let engine = SharedEngine::new(options.open(root.path()).unwrap());
+
+        let writer = {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for round in 1..=ROUNDS {
+                    for key in 0..KEYS {
+                        engine.insert(&format!("key{}", key), &round.to_le_bytes()).unwrap();
+                    }
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                // Values only ever grow, so a reader never sees one go back
+                let mut seen = [0u64; KEYS];
+                while seen.iter().any(|&round| round < ROUNDS) {
+                    for (key, seen) in seen.iter_mut().enumerate() {
+                        let round = engine.get(&format!("key{}", key)).unwrap()
+                            .map_or(0, |value| u64::from_le_bytes((*value).try_into().unwrap()));
+                        assert!(round >= *seen);
+                        *seen = round;
+                    }
+                }
+            })
+        }).collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        for key in 0..KEYS {
+            assert_eq!(&*engine.get(&format!("key{}", key)).unwrap().unwrap(), &ROUNDS.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn test_background_compaction() {
+        let root = tempdir().unwrap();
+        let engine = SharedEngine::new(SSTEngine::try_new(root.path()).unwrap());
+        for i in 0..100 {
+            engine.insert(&format!("key{:03}", i), b"value").unwrap();
+            if i % 10 == 9 {
+                engine.flush().unwrap();
+            }
+        }
+        let segments_before = engine.read().unwrap().stats().unwrap().segment_count;
+        assert!(segments_before > 4);
+
+        let policy = CompactionPolicy { interval: Duration::from_millis(10), max_segments: 4, max_disk_bytes: None };
+        let handle = engine.start_background_compaction(policy);
+        // Writes keep going while the thread compacts
+        for i in 100..150 {
+            engine.insert(&format!("key{:03}", i), b"value").unwrap();
+            if i % 10 == 9 {
+                engine.flush().unwrap();
+            }
+        }
+        for _ in 0..500 {
+            if engine.read().unwrap().stats().unwrap().segment_count <= 4 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.stop().unwrap();
+
+        assert!(engine.read().unwrap().stats().unwrap().segment_count <= 4);
+        for i in 0..150 {
+            assert_eq!(&*engine.get(&format!("key{:03}", i)).unwrap().unwrap(), b"value");
+        }
+    }
+}
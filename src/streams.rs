@@ -1,117 +1,623 @@
-use std::{io::{Read, Seek, SeekFrom, Write}, path::PathBuf};
+use std::{cell::RefCell, collections::VecDeque, fs::File, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+
+use crate::{naming::FileNaming, Error};
+
+// File recording where the stream starts after truncate_to: the index and start position of the
+// first kept segment file, and the first position kept
+const START_FILE: &str = "start";
+
+// File recording the index and logical length of the last segment file when segment files are
+// pre-allocated, as their file size says nothing about the data in them. Two u64, little-endian.
+const END_FILE: &str = "end";
+
+// Size in bytes at which buffered writes are written to the last segment file
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+// Extension of segment files unless the stream is opened with a naming of its own
+pub const SEGMENT_EXTENSION: &str = "log";
+
+// Default number of segment files kept open for reading besides the last one
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
 
 pub struct FileSegmentStream {
     root: PathBuf,
+    // Names of the segment files in root
+    naming: FileNaming,
     segments: Vec<Segment>,
     position: u64,
+    // Data before this position was dropped by truncate_to
+    start: u64,
     max_segment_size: u64,
+    // Written bytes at the end of the last segment not written to its file yet
+    buffer: Vec<u8>,
+    // Read handles of the segments that are no longer written to
+    files: RefCell<FileCache>,
+    // Whether new segment files are created at their full size
+    preallocate: bool,
+    // Open handle on the end file once one is written
+    end_file: Option<File>,
+    // Opened by open_read_only, every write fails and no file is touched
+    read_only: bool,
 }
 
 impl FileSegmentStream {
-    pub fn new(root: PathBuf, max_segment_size: u64) -> FileSegmentStream {
-        if !root.exists() {
-            std::fs::create_dir_all(&root).unwrap();
+    // Open the stream in root, picking up segments written by a previous stream. Writes are split
+    // so no segment grows past max_segment_size, which can't be 0.
+    pub fn try_new(root: PathBuf, max_segment_size: u64) -> crate::Result<FileSegmentStream> {
+        FileSegmentStream::open(root, max_segment_size, false, FileNaming::unpadded(SEGMENT_EXTENSION))
+    }
+
+    // Like try_new, with segment files named by the naming instead of {index}.log. Files named
+    // otherwise are not part of the stream.
+    pub fn try_new_with_naming(root: PathBuf, max_segment_size: u64, naming: FileNaming) -> crate::Result<FileSegmentStream> {
+        FileSegmentStream::open(root, max_segment_size, false, naming)
+    }
+
+    // Open the segments of an existing stream for reading only. Files left behind by an
+    // interrupted truncation stay where they are, and writes fail with Error::ReadOnly.
+    pub fn open_read_only(root: PathBuf, max_segment_size: u64) -> crate::Result<FileSegmentStream> {
+        FileSegmentStream::open(root, max_segment_size, true, FileNaming::unpadded(SEGMENT_EXTENSION))
+    }
+
+    pub(crate) fn open(root: PathBuf, max_segment_size: u64, read_only: bool, naming: FileNaming)
+        -> crate::Result<FileSegmentStream> {
+        if max_segment_size == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "Segment size limit must be at least 1 byte").into());
+        }
+        if !root.exists() && !read_only {
+            std::fs::create_dir_all(&root)?;
         }
         if !root.is_dir() {
-            panic!("Root path must be a directory");
+            return Err(Error::NotADirectory(root));
         }
 
-        FileSegmentStream {
+        let (segments, start) = FileSegmentStream::open_segments(&root, &naming, read_only)?;
+        let position = segments.last().map_or(start, |s| s.end);
+        // Segments pre-allocated before keep their data length up to date, preallocation or not
+        let end_file = if read_only {
+            None
+        } else {
+            match std::fs::OpenOptions::new().write(true).open(root.join(END_FILE)) {
+                Ok(file) => Some(file),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let stream = FileSegmentStream {
             root,
-            segments: Vec::new(),
-            position: 0,
+            naming,
+            segments,
+            position,
+            start,
             max_segment_size,
+            buffer: Vec::new(),
+            files: RefCell::new(FileCache::new(DEFAULT_MAX_OPEN_FILES)),
+            preallocate: false,
+            end_file,
+            read_only,
+        };
+        stream.debug_assert_contiguous();
+        Ok(stream)
+    }
+
+    pub fn new(root: PathBuf, max_segment_size: u64) -> FileSegmentStream {
+        FileSegmentStream::try_new(root, max_segment_size).expect("Failed to open file segment stream")
+    }
+
+    // Keep at most this many segment files open for reading, closing the least recently read
+    // ones. The last segment stays open for writing on top of these.
+    pub fn with_max_open_files(self, max_open_files: usize) -> FileSegmentStream {
+        self.files.borrow_mut().set_capacity(max_open_files);
+        self
+    }
+
+    // Create segment files at max_segment_size up front, instead of growing them with every
+    // write. The length of the data in the last one is then kept in a file of its own, updated
+    // whenever buffered bytes are written. Streams reopen segment files written either way.
+    pub fn with_preallocation(mut self, preallocate: bool) -> FileSegmentStream {
+        self.preallocate = preallocate;
+        self
+    }
+
+    // Bytes held by the segment files, buffered ones included
+    pub fn len(&self) -> u64 {
+        self.segments.iter().map(Segment::size).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    // A read-only handle on the segment files, for reading while this stream is written to
+    pub fn reader(&self) -> SegmentReader {
+        SegmentReader {
+            root: self.root.clone(),
+            naming: self.naming.clone(),
+            segments: Vec::new(),
+            position: self.start,
+            files: FileCache::new(DEFAULT_MAX_OPEN_FILES),
         }
     }
-}
 
-impl Read for FileSegmentStream {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.segments.is_empty() {
-            return Ok(0);
+    // Reopen segment files left in the root directory by a previous stream, returning them
+    // with the start of the stream
+    fn open_segments(root: &Path, naming: &FileNaming, read_only: bool) -> std::io::Result<(Vec<Segment>, u64)> {
+        let (first_index, mut start, stream_start) = FileSegmentStream::read_start(root)?;
+
+        let mut entries: Vec<(u64, PathBuf)> = root.read_dir()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file())
+            .filter_map(|p| Some((naming.parse_path(&p)?, p)))
+            .collect();
+        entries.sort_by_key(|(index, _)| *index);
+
+        let mut segments = Vec::with_capacity(entries.len());
+        for (index, path) in entries {
+            // Left behind by a truncation that was interrupted
+            if index < first_index {
+                if !read_only {
+                    std::fs::remove_file(path)?;
+                }
+                continue;
+            }
+
+            // Only the last segment is written to, the others are opened when read
+            segments.push(Segment {
+                file: None,
+                index,
+                start,
+                end: start + path.metadata()?.len(),
+                dirty: false,
+            });
+            start = segments.last().unwrap().end;
         }
-        
-        // Find segment using binary search
-        let segment_index = match self.segments.binary_search_by(|segment| {
-            if self.position < segment.start {
+        if let Some(last) = segments.last_mut() {
+            if let Some(len) = FileSegmentStream::read_end(root, last.index)? {
+                last.end = last.end.min(last.start + len);
+            }
+            last.file = Some(std::fs::OpenOptions::new()
+                .read(true)
+                .write(!read_only)
+                .open(naming.path(root, last.index))?);
+        }
+
+        Ok((segments, stream_start))
+    }
+
+    // Length of the data in the last segment file if it is pre-allocated, which the end file
+    // records for it
+    fn read_end(root: &Path, last_index: u64) -> std::io::Result<Option<u64>> {
+        let contents = match std::fs::read(root.join(END_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let fields: [u8; 16] = contents.try_into()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid stream end file"))?;
+        let index = u64::from_le_bytes(fields[..8].try_into().unwrap());
+        let len = u64::from_le_bytes(fields[8..].try_into().unwrap());
+        Ok((index == last_index).then_some(len))
+    }
+
+    // Record the length of the data in the last segment file, overwriting the end file in place
+    fn write_end(&mut self, index: u64, len: u64) -> std::io::Result<()> {
+        let file = match &mut self.end_file {
+            Some(file) => file,
+            None => self.end_file.insert(std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(self.root.join(END_FILE))?),
+        };
+        let mut fields = [0u8; 16];
+        fields[..8].copy_from_slice(&index.to_le_bytes());
+        fields[8..].copy_from_slice(&len.to_le_bytes());
+        write_file_at(file, &fields, 0)
+    }
+
+    fn read_start(root: &Path) -> std::io::Result<(u64, u64, u64)> {
+        let contents = match std::fs::read_to_string(root.join(START_FILE)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0, 0)),
+            Err(e) => return Err(e),
+        };
+        let fields: Vec<u64> = contents.split_whitespace()
+            .map(|field| field.parse::<u64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid stream start file"))?;
+        match fields[..] {
+            [index, segment_start, start] => Ok((index, segment_start, start)),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid stream start file")),
+        }
+    }
+
+    // Drop the data before offset. Segment files ending at or before it are deleted, except the
+    // last one, and positions of the kept data don't change.
+    pub fn truncate_to(&mut self, offset: u64) -> crate::Result<()> {
+        let end = self.segments.last().map_or(self.start, |s| s.end);
+        let offset = offset.min(end);
+        if offset <= self.start {
+            return Ok(());
+        }
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let removed = self.segments[..self.segments.len() - 1].iter()
+            .take_while(|s| s.end <= offset)
+            .count();
+        let first = &self.segments[removed];
+
+        // The new start is recorded before any file goes away, so files left behind by a crash
+        // in between are recognized on reopen
+        let temp_path = self.root.join(format!("{}.tmp", START_FILE));
+        let mut file = std::fs::File::create(&temp_path)?;
+        writeln!(file, "{} {} {}", first.index, first.start, offset)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, self.root.join(START_FILE))?;
+
+        for segment in self.segments.drain(..removed) {
+            self.files.get_mut().remove(segment.index);
+            std::fs::remove_file(self.naming.path(&self.root, segment.index))?;
+        }
+        self.start = offset;
+        self.position = self.position.max(offset);
+        Ok(())
+    }
+
+    // Flush and have written data reach the disk, including data spilled into segments since
+    // rolled over
+    pub fn sync_all(&mut self) -> std::io::Result<()> {
+        self.write_buffer()?;
+        for segment in self.segments.iter_mut().filter(|s| s.dirty) {
+            match &segment.file {
+                Some(file) => file.sync_all()?,
+                // Closed by a flush since it was written, syncing another handle syncs the file
+                None => std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(self.naming.path(&self.root, segment.index))?
+                    .sync_all()?,
+            }
+            segment.dirty = false;
+        }
+        if let Some(file) = &self.end_file {
+            file.sync_all()?;
+        }
+        self.flush()
+    }
+
+    // Write the buffered bytes to the last segment file
+    fn write_buffer(&mut self) -> std::io::Result<()> {
+        let Some(segment) = self.segments.last_mut().filter(|_| !self.buffer.is_empty()) else {
+            return Ok(());
+        };
+        // A pre-allocated file is longer than its data
+        let offset = segment.size() - self.buffer.len() as u64;
+        let file = segment.file.as_mut().expect("The last segment is open for writing");
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&self.buffer)?;
+        segment.dirty = true;
+        self.buffer.clear();
+        let (index, size) = (segment.index, segment.size());
+        if self.preallocate || self.end_file.is_some() {
+            self.write_end(index, size)?;
+        }
+        Ok(())
+    }
+
+    fn add_segment(&mut self) -> std::io::Result<()> {
+        let stream_end = self.segments.last().map_or(self.start, |s| s.end);
+        // Never reuse the name of an existing segment file
+        let index = self.segments.last().map_or(0, |s| s.index + 1);
+        // Recorded first, so a crash can't leave a pre-allocated file whose data length is unknown
+        if self.preallocate || self.end_file.is_some() {
+            self.write_end(index, 0)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(self.naming.path(&self.root, index))?;
+        if self.preallocate {
+            file.set_len(self.max_segment_size)?;
+        }
+        self.segments.push(Segment::new(file, index, stream_end));
+        Ok(())
+    }
+
+    // Segments must cover the stream without gaps or overlaps
+    fn debug_assert_contiguous(&self) {
+        debug_assert!(self.segments.windows(2).all(|w| w[1].start == w[0].end),
+            "Segments are not contiguous");
+    }
+
+    // Read from an absolute offset without moving the position, so a shared stream can be read
+    // from anywhere. Offsets outside of the stream read nothing.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut total_read = 0;
+        let mut offset = offset;
+
+        while total_read < buf.len() {
+            let Some(segment_index) = self.find_segment(offset) else {
+                break;
+            };
+
+            let segment = &self.segments[segment_index];
+            let available = (segment.end - offset) as usize;
+            let len = available.min(buf.len() - total_read);
+
+            // The tail of the last segment may still be buffered
+            let file_end = if segment_index == self.segments.len() - 1 {
+                segment.end - self.buffer.len() as u64
+            } else {
+                segment.end
+            };
+            let read = if offset >= file_end {
+                let buffered = (offset - file_end) as usize;
+                buf[total_read..total_read + len].copy_from_slice(&self.buffer[buffered..buffered + len]);
+                len
+            } else {
+                let len = len.min((file_end - offset) as usize);
+                let buf = &mut buf[total_read..total_read + len];
+                match &segment.file {
+                    Some(file) => read_file_at(file, buf, offset - segment.start)?,
+                    None => self.files.borrow_mut().read_at(&self.root, &self.naming, segment.index, buf,
+                        offset - segment.start)?,
+                }
+            };
+            if read == 0 {
+                break;
+            }
+            total_read += read;
+            offset += read as u64;
+        }
+
+        Ok(total_read)
+    }
+
+    fn find_segment(&self, position: u64) -> Option<usize> {
+        self.segments.binary_search_by(|segment| {
+            if position < segment.start {
                 std::cmp::Ordering::Greater
-            } else if self.position >= segment.end {
+            } else if position >= segment.end {
                 std::cmp::Ordering::Less
             } else {
                 std::cmp::Ordering::Equal
             }
-        }) {
-            Ok(index) => index,
-            Err(_) => return Ok(0), // Position is outside of any segment
-        };
+        }).ok()
+    }
+}
 
+// Reads the segment files of a stream through handles of its own, independently of the position
+// of the stream. It sees what the writer has written to the files, so buffered bytes show up once
+// the writer flushes them, and segment files deleted by truncate_to can't be read any more.
+pub struct SegmentReader {
+    root: PathBuf,
+    naming: FileNaming,
+    // Segment files seen so far, the last one may have grown since
+    segments: Vec<Segment>,
+    position: u64,
+    files: FileCache,
+}
+
+impl SegmentReader {
+    // Read from an absolute offset without moving the position, looking for data written since
+    // the last read when the known segments run out
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.read_known(offset, buf)?;
+        if read == buf.len() {
+            return Ok(read);
+        }
+        self.refresh()?;
+        Ok(read + self.read_known(offset + read as u64, &mut buf[read..])?)
+    }
+
+    fn read_known(&mut self, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
         let mut total_read = 0;
-        let mut current_segment = segment_index;
+        let mut offset = offset;
 
-        while total_read < buf.len() && current_segment < self.segments.len() {
-            let segment = &mut self.segments[current_segment];
-            let offset = if current_segment == segment_index {
-                self.position - segment.start
-            } else {
-                0
+        while total_read < buf.len() {
+            let Some(segment) = self.segments.iter().find(|s| s.start <= offset && offset < s.end) else {
+                break;
             };
-            
-            segment.file.seek(SeekFrom::Start(offset))?;
-            let read = segment.file.read(&mut buf[total_read..])?;
+            let len = ((segment.end - offset) as usize).min(buf.len() - total_read);
+            let read = self.files.read_at(&self.root, &self.naming, segment.index, &mut buf[total_read..total_read + len],
+                offset - segment.start)?;
             if read == 0 {
-                if current_segment + 1 < self.segments.len() {
-                    current_segment += 1;
-                } else {
-                    break;
-                }
+                break;
             }
             total_read += read;
+            offset += read as u64;
         }
 
         Ok(total_read)
     }
+
+    // Pick up the growth of the last known segment and the segments added after it. A segment is
+    // only rolled over once its file is complete, so its size is final when a later one exists.
+    fn refresh(&mut self) -> std::io::Result<()> {
+        if self.segments.is_empty() {
+            let (index, start, _) = FileSegmentStream::read_start(&self.root)?;
+            if !self.naming.path(&self.root, index).exists() {
+                return Ok(());
+            }
+            self.segments.push(Segment { file: None, index, start, end: start, dirty: false });
+        }
+        loop {
+            let next_exists = self.naming.path(&self.root, self.segments.last().unwrap().index + 1).exists();
+            let last = self.segments.last_mut().unwrap();
+            last.end = last.start + std::fs::metadata(self.naming.path(&self.root, last.index))?.len();
+            if !next_exists {
+                if let Some(len) = FileSegmentStream::read_end(&self.root, last.index)? {
+                    last.end = last.end.min(last.start + len);
+                }
+                return Ok(());
+            }
+            let (index, start) = (last.index + 1, last.end);
+            self.segments.push(Segment { file: None, index, start, end: start, dirty: false });
+        }
+    }
+
+    fn end(&mut self) -> std::io::Result<u64> {
+        self.refresh()?;
+        Ok(self.segments.last().map_or(0, |s| s.end))
+    }
+}
+
+impl Read for SegmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.read_at(self.position, buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SegmentReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.end()?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "Invalid seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+// Read handles of segment files, closing the least recently used beyond the capacity
+struct FileCache {
+    capacity: usize,
+    // Least recently used first
+    files: VecDeque<(u64, File)>,
+}
+
+impl FileCache {
+    fn new(capacity: usize) -> FileCache {
+        FileCache { capacity: capacity.max(1), files: VecDeque::new() }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        while self.files.len() > self.capacity {
+            self.files.pop_front();
+        }
+    }
+
+    fn read_at(&mut self, root: &Path, naming: &FileNaming, index: u64, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let entry = match self.files.iter().position(|(i, _)| *i == index) {
+            Some(position) => self.files.remove(position).unwrap(),
+            None => (index, File::open(naming.path(root, index))?),
+        };
+        if self.files.len() >= self.capacity {
+            self.files.pop_front();
+        }
+        self.files.push_back(entry);
+        read_file_at(&self.files.back().unwrap().1, buf, offset)
+    }
+
+    fn remove(&mut self, index: u64) {
+        self.files.retain(|(i, _)| *i != index);
+    }
+}
+
+#[cfg(unix)]
+fn read_file_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_file_at(file: &std::fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_file_at(file: &std::fs::File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_file_at(file: &std::fs::File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        written += std::os::windows::fs::FileExt::seek_write(file, &buf[written..], offset + written as u64)?;
+    }
+    Ok(())
+}
+
+impl Read for FileSegmentStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.read_at(self.position, buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
 }
 
 impl Write for FileSegmentStream {
+    // The stream is append-only, writing anywhere but at the end fails
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let size = buf.len() as u64;
-        let current_pos = self.position;
-        
-        if self.segments.is_empty() || self.segments.last().map(|s| s.size()).unwrap() > self.max_segment_size {
-            let file = std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(self.root.join(format!("{}.log", self.segments.len())))?;
-            let segment = Segment::new(file, current_pos);
-            self.segments.push(segment);
+        if self.read_only {
+            return Err(Error::ReadOnly.into());
         }
+        if self.position != self.segments.last().map_or(self.start, |s| s.end) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                "Writes must be at the end of the stream"));
+        }
+        let mut written = 0;
 
-        let segment = self.segments.last_mut().unwrap();
-        segment.file.write_all(buf)?;
-        segment.end = current_pos + size;
-        self.position += size;
-        Ok(size as usize)
+        // Fill the last segment up to its limit and spill the rest into new ones
+        while written < buf.len() {
+            if self.segments.last().is_none_or(|s| s.size() >= self.max_segment_size) {
+                self.write_buffer()?;
+                self.add_segment()?;
+            }
+
+            let segment = self.segments.last_mut().unwrap();
+            let room = (self.max_segment_size - segment.size()) as usize;
+            let chunk = &buf[written..buf.len().min(written + room)];
+            self.buffer.extend_from_slice(chunk);
+            segment.end += chunk.len() as u64;
+            written += chunk.len();
+        }
+        if self.buffer.len() >= WRITE_BUFFER_SIZE {
+            self.write_buffer()?;
+        }
+
+        self.position = self.segments.last().map_or(0, |s| s.end);
+        self.debug_assert_contiguous();
+        Ok(written)
     }
 
+    // Written data reaches the OS, where it survives the process crashing. Use sync_all to have
+    // it survive the machine crashing too.
     fn flush(&mut self) -> std::io::Result<()> {
-        if let Some(segment) = self.segments.last_mut() {
-            segment.file.flush()
-        } else {
-            Ok(())
+        self.write_buffer()?;
+        // Segments rolled over since are only read from now on
+        if let Some((_, rolled_over)) = self.segments.split_last_mut() {
+            for segment in rolled_over {
+                segment.file = None;
+            }
         }
+        Ok(())
     }
 }
 
 impl Seek for FileSegmentStream {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
         match pos {
+            // Positions before the start are gone, seeking there lands on the first kept byte
             SeekFrom::Start(position) => {
-                self.position = position;
+                self.position = position.max(self.start);
             },
             SeekFrom::End(position) => {
-                let end_position = self.segments.iter().fold(0, |acc, segment| acc + segment.size()) as i64 + position;
+                let end_position = self.segments.last().map_or(self.start, |s| s.end) as i64 + position;
                 if end_position < 0 {
                     return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative position"));
                 }
@@ -130,19 +636,33 @@ impl Seek for FileSegmentStream {
     }
 }
 
+impl Drop for FileSegmentStream {
+    // Buffered bytes still reach the segment file, without waiting for the disk
+    fn drop(&mut self) {
+        let _ = self.write_buffer();
+    }
+}
+
 pub struct Segment {
-    file: std::fs::File,
+    // Open while the segment is written to
+    file: Option<File>,
+    // Numeric part of the segment file name
+    index: u64,
     start: u64,
     end: u64,
+    // Written to since the last flush
+    dirty: bool,
 }
 
 impl Segment {
-    pub fn new(file: std::fs::File, start: u64) -> Segment {
+    pub fn new(file: std::fs::File, index: u64, start: u64) -> Segment {
         let end = start;
         Segment {
-            file,
+            file: Some(file),
+            index,
             start,
             end,
+            dirty: false,
         }
     }
 
@@ -178,12 +698,13 @@ mod tests {
         let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
         
         let data = b"Hello, World!";
-        stream.write(data).unwrap();
-        stream.write(data).unwrap();
+        stream.write_all(data).unwrap();
+        stream.write_all(data).unwrap();
         
-        assert_eq!(stream.segments.len(), 2);
-        assert!(stream.segments[0].size() == 13);
-        assert!(stream.segments[1].size() == 13);
+        assert_eq!(stream.segments.len(), 3);
+        assert!(stream.segments[0].size() == 10);
+        assert!(stream.segments[1].size() == 10);
+        assert!(stream.segments[2].size() == 6);
     }
 
     #[test]
@@ -192,7 +713,7 @@ mod tests {
         let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 1024);
         
         let data = b"Hello, World!";
-        stream.write(data).unwrap();
+        stream.write_all(data).unwrap();
         
         stream.seek(SeekFrom::Start(0)).unwrap();
         let mut buf = vec![0; data.len()];
@@ -206,11 +727,11 @@ mod tests {
         let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 1024);
         
         let data = b"Hello, World!";
-        stream.write(data).unwrap();
+        stream.write_all(data).unwrap();
         
         stream.seek(SeekFrom::Start(7)).unwrap();
         let mut buf = vec![0; 6];
-        stream.read(&mut buf).unwrap();
+        stream.read_exact(&mut buf).unwrap();
         assert_eq!(&buf, b"World!");
     }
 
@@ -219,9 +740,9 @@ mod tests {
         let dir = setup_test_dir();
         let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
         
-        stream.write(b"Hello, ").unwrap();
+        stream.write_all(b"Hello, ").unwrap();
         
-        stream.write(b"World!").unwrap();
+        stream.write_all(b"World!").unwrap();
         
         stream.seek(SeekFrom::Start(0)).unwrap();
         let mut buf = vec![0; 13];
@@ -229,13 +750,165 @@ mod tests {
         assert_eq!(&buf, b"Hello, World!");
     }
 
+    #[test]
+    fn test_file_segment_stream_read_at() {
+        let dir = setup_test_dir();
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+        stream.write_all(b"Hello, World!").unwrap();
+        assert_eq!(stream.segments.len(), 2);
+
+        let mut buf = vec![0; 5];
+        assert_eq!(stream.read_at(5, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b", Wor");
+        assert_eq!(stream.position, 13);
+
+        // Reads stop at the end of the stream
+        assert_eq!(stream.read_at(10, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"ld!");
+        assert_eq!(stream.read_at(13, &mut buf).unwrap(), 0);
+        assert_eq!(stream.position, 13);
+    }
+
+    #[test]
+    fn test_file_segment_stream_buffered_writes() {
+        let dir = setup_test_dir();
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+        let mut expected = Vec::new();
+        for word in [&b"Hello"[..], b", ", b"World", b"!", b" Again", b" and again"] {
+            stream.write_all(word).unwrap();
+            expected.extend_from_slice(word);
+
+            let mut buf = vec![0; expected.len()];
+            assert_eq!(stream.read_at(0, &mut buf).unwrap(), expected.len());
+            assert_eq!(buf, expected);
+            stream.seek(SeekFrom::Current(-(word.len() as i64))).unwrap();
+            let mut buf = vec![0; word.len()];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, word);
+        }
+
+        // Only the last segment holds buffered bytes
+        let on_disk = |stream: &FileSegmentStream| -> u64 {
+            stream.segments.iter().map(|s| stream.naming.path(&stream.root, s.index).metadata().unwrap().len()).sum()
+        };
+        assert!(on_disk(&stream) < expected.len() as u64);
+        stream.flush().unwrap();
+        assert_eq!(on_disk(&stream), expected.len() as u64);
+        assert!(stream.segments.iter().any(|s| s.dirty));
+        stream.sync_all().unwrap();
+        assert!(stream.segments.iter().all(|s| !s.dirty && s.file.is_none() == (s.index != stream.segments.last().unwrap().index)));
+
+        stream.write_all(b"!").unwrap();
+        expected.push(b'!');
+        drop(stream);
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+        let mut buf = Vec::new();
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_file_segment_stream_max_open_files() {
+        let dir = setup_test_dir();
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10)
+            .with_max_open_files(3);
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        stream.write_all(&data).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(stream.segments.len(), 20);
+
+        #[cfg(target_os = "linux")]
+        let open_files = || std::fs::read_dir("/proc/self/fd").unwrap()
+            .filter_map(|e| std::fs::read_link(e.unwrap().path()).ok())
+            .filter(|target| target.starts_with(dir.path()))
+            .count();
+        for _ in 0..2 {
+            for offset in (0..200).step_by(7) {
+                let mut buf = [0; 13];
+                let read = stream.read_at(offset, &mut buf).unwrap();
+                assert_eq!(&buf[..read], &data[offset as usize..(offset as usize + 13).min(200)]);
+                assert!(stream.files.borrow().files.len() <= 3);
+                #[cfg(target_os = "linux")]
+                assert!(open_files() <= 4);
+            }
+        }
+
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10)
+            .with_max_open_files(3);
+        let mut buf = Vec::new();
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert!(stream.files.borrow().files.len() <= 3);
+    }
+
+    #[test]
+    fn test_file_segment_stream_write_is_append_only() {
+        let dir = setup_test_dir();
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+        stream.write_all(b"Hello, World!").unwrap();
+
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        let err = stream.write(b"Bye").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(stream.position, 0);
+
+        stream.seek(SeekFrom::End(0)).unwrap();
+        stream.write_all(b" Again").unwrap();
+        let mut buf = Vec::new();
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"Hello, World! Again");
+    }
+
+    #[test]
+    fn test_file_segment_stream_len() {
+        let dir = setup_test_dir();
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+        assert!(stream.is_empty());
+        assert_eq!(stream.segment_count(), 0);
+
+        stream.write_all(b"Hello, World!").unwrap();
+        stream.write_all(b" Again").unwrap();
+        assert_eq!(stream.len(), 19);
+        assert!(!stream.is_empty());
+        assert_eq!(stream.segment_count(), 2);
+
+        stream.flush().unwrap();
+        let stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+        assert_eq!(stream.len(), 19);
+        assert_eq!(stream.segment_count(), 2);
+    }
+
+    #[test]
+    fn test_file_segment_stream_zero_segment_size() {
+        let dir = setup_test_dir();
+        let result = FileSegmentStream::try_new(dir.path().to_path_buf(), 0);
+        assert!(matches!(result, Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput));
+    }
+
+    #[test]
+    fn test_file_segment_stream_one_byte_segments() {
+        let dir = setup_test_dir();
+        let mut stream = FileSegmentStream::try_new(dir.path().to_path_buf(), 1).unwrap();
+        stream.write_all(b"abc").unwrap();
+        stream.write_all(b"de").unwrap();
+        assert_eq!(stream.segment_count(), 5);
+        assert!(stream.segments.iter().all(|s| s.size() == 1));
+
+        let mut buf = [0; 5];
+        assert_eq!(stream.read_at(0, &mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"abcde");
+    }
+
     #[test]
     fn test_file_segment_stream_seek_negative() {
         let dir = setup_test_dir();
         let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 1024);
         
         let data = b"Hello, World!";
-        stream.write(data).unwrap();
+        stream.write_all(data).unwrap();
         
         // Should succeed - seeking from end
         assert!(stream.seek(SeekFrom::End(-5)).is_ok());
@@ -244,4 +917,178 @@ mod tests {
         assert!(stream.seek(SeekFrom::End(-20)).is_err());
         assert!(stream.seek(SeekFrom::Current(-20)).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_file_segment_stream_reopen() {
+        let dir = setup_test_dir();
+        {
+            let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+            stream.write_all(b"Hello, World!").unwrap();
+            stream.write_all(b" Again").unwrap();
+            stream.flush().unwrap();
+        }
+
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+        assert_eq!(stream.segments.len(), 2);
+        assert_eq!(stream.position, 19);
+
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = vec![0; 19];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Hello, World! Again");
+    }
+
+    #[test]
+    fn test_file_segment_stream_append_after_reopen() {
+        let dir = setup_test_dir();
+        {
+            let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 13);
+            stream.write_all(b"Hello, World!").unwrap();
+        }
+        // Older segments may have been removed, leaving a gap in the numbering
+        std::fs::rename(dir.path().join("0.log"), dir.path().join("1.log")).unwrap();
+
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 13);
+        stream.write_all(b" Again").unwrap();
+        assert_eq!(stream.segments.len(), 2);
+        assert!(dir.path().join("2.log").exists());
+
+        assert_eq!(std::fs::read(dir.path().join("1.log")).unwrap(), b"Hello, World!");
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = vec![0; 19];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Hello, World! Again");
+    }
+
+    #[test]
+    fn test_file_segment_stream_read_only() {
+        let dir = setup_test_dir();
+        {
+            let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+            stream.write_all(b"Hello, World!").unwrap();
+        }
+
+        let mut stream = FileSegmentStream::open_read_only(dir.path().to_path_buf(), 10).unwrap();
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = vec![0; 13];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Hello, World!");
+        let error = stream.write(b" Again").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(matches!(stream.truncate_to(5), Err(Error::ReadOnly)));
+        drop(stream);
+        assert_eq!(std::fs::read(dir.path().join("1.log")).unwrap(), b"ld!");
+
+        let missing = dir.path().join("missing");
+        assert!(FileSegmentStream::open_read_only(missing.clone(), 10).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn test_file_segment_stream_contiguous_segments() {
+        let dir = setup_test_dir();
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 4);
+
+        stream.write_all(b"first").unwrap();
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        stream.seek(SeekFrom::End(0)).unwrap();
+        stream.write_all(b"second").unwrap();
+        stream.write_all(b"third").unwrap();
+
+        assert_eq!(stream.segments.len(), 4);
+        for pair in stream.segments.windows(2) {
+            assert_eq!(pair[1].start, pair[0].end);
+        }
+        assert_eq!(stream.segments[3].end, 16);
+
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = vec![0; 16];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"firstsecondthird");
+    }
+
+    #[test]
+    fn test_file_segment_stream_split_write() {
+        let dir = setup_test_dir();
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 10);
+
+        let data = [7u8; 25];
+        assert_eq!(stream.write(&data).unwrap(), 25);
+        let sizes: Vec<_> = stream.segments.iter().map(|s| s.size()).collect();
+        assert_eq!(sizes, vec![10, 10, 5]);
+
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = vec![0; 25];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_file_segment_stream_root_is_file() {
+        let dir = setup_test_dir();
+        let file_path = dir.path().join("not_a_dir");
+        std::fs::write(&file_path, b"data").unwrap();
+
+        let result = FileSegmentStream::try_new(file_path.clone(), 1024);
+        assert!(matches!(result, Err(Error::NotADirectory(path)) if path == file_path));
+    }
+
+    #[test]
+    fn test_file_segment_stream_preallocation() {
+        let dir = setup_test_dir();
+        let file_len = |index: u64| dir.path().join(format!("{}.log", index)).metadata().unwrap().len();
+        {
+            let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 16).with_preallocation(true);
+            stream.write_all(b"Hello, World!").unwrap();
+            stream.flush().unwrap();
+            assert_eq!(file_len(0), 16);
+            assert_eq!(stream.len(), 13);
+            assert_eq!(stream.seek(SeekFrom::End(0)).unwrap(), 13);
+
+            // Readers of the files see the data, not the zeros after it
+            let mut reader = stream.reader();
+            let mut contents = Vec::new();
+            reader.read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, b"Hello, World!");
+
+            stream.write_all(b" Again").unwrap();
+            stream.flush().unwrap();
+            assert_eq!(file_len(1), 16);
+            assert_eq!(stream.len(), 19);
+        }
+
+        // The data length survives reopening, also without preallocation
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 16);
+        assert_eq!(stream.len(), 19);
+        stream.write_all(b"!").unwrap();
+        stream.flush().unwrap();
+        drop(stream);
+
+        let mut stream = FileSegmentStream::new(dir.path().to_path_buf(), 16);
+        assert_eq!(stream.len(), 20);
+        stream.seek(SeekFrom::Start(0)).unwrap();
+        let mut contents = Vec::new();
+        stream.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"Hello, World! Again!");
+    }
+
+    #[test]
+    fn test_log_on_preallocated_stream() {
+        let dir = setup_test_dir();
+        {
+            let stream = FileSegmentStream::new(dir.path().to_path_buf(), 1024).with_preallocation(true);
+            let mut log = crate::log::Log::new_with_checksum(RefCell::new(stream));
+            for i in 0..10u8 {
+                log.append(&[i; 50]).unwrap();
+            }
+            log.flush().unwrap();
+        }
+
+        let stream = FileSegmentStream::new(dir.path().to_path_buf(), 1024);
+        let log = crate::log::Log::new_with_checksum(RefCell::new(stream));
+        assert_eq!(log.count_entries().unwrap(), 10);
+        assert_eq!(log.valid_len().unwrap(), log.end().unwrap());
+        let entries: Vec<_> = log.into_iter().map(Result::unwrap).collect();
+        assert_eq!(entries.last().unwrap().as_ref(), [9; 50]);
+    }
+}
@@ -0,0 +1,86 @@
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Result, SSTEngine};
+
+// SSTEngine storing values of one serde type, encoded as JSON
+pub struct TypedEngine<V> {
+    engine: SSTEngine,
+    values: PhantomData<V>,
+}
+
+impl<V: Serialize + DeserializeOwned> TypedEngine<V> {
+    pub fn new(engine: SSTEngine) -> TypedEngine<V> {
+        TypedEngine { engine, values: PhantomData }
+    }
+
+    // Returns the value the key had before, if any
+    pub fn insert(&mut self, key: &str, value: &V) -> Result<Option<V>> {
+        let previous = self.engine.insert(key, &serde_json::to_vec(value)?)?;
+        previous.map(|bytes| decode(&bytes)).transpose()
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<V>> {
+        self.engine.get(key)?.map(|bytes| decode(&bytes)).transpose()
+    }
+
+    // Returns the value the key had before, if any
+    pub fn delete(&mut self, key: &str) -> Result<Option<V>> {
+        self.engine.delete(key)?.map(|bytes| decode(&bytes)).transpose()
+    }
+
+    pub fn engine(&mut self) -> &mut SSTEngine {
+        &mut self.engine
+    }
+
+    pub fn into_inner(self) -> SSTEngine {
+        self.engine
+    }
+}
+
+fn decode<V: DeserializeOwned>(bytes: &[u8]) -> Result<V> {
+    Ok(serde_json::from_slice(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use serde::Deserialize;
+    use tempfile::tempdir;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Address {
+        city: String,
+        zip: Option<u32>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        tags: Vec<String>,
+        address: Address,
+    }
+
+    #[test]
+    fn test_typed_engine() {
+        let root = tempdir().unwrap();
+        let user = User {
+            name: "alice".to_string(),
+            tags: vec!["admin".to_string()],
+            address: Address { city: "Paris".to_string(), zip: Some(75001) },
+        };
+        {
+            let mut engine = TypedEngine::new(SSTEngine::try_new(root.path()).unwrap());
+            assert_eq!(engine.insert("user:1", &user).unwrap(), None);
+            assert_eq!(engine.get("user:1").unwrap(), Some(user));
+            assert_eq!(engine.get("user:2").unwrap(), None);
+        }
+
+        let mut engine = TypedEngine::<User>::new(SSTEngine::try_new(root.path()).unwrap());
+        assert_eq!(engine.get("user:1").unwrap().unwrap().address.city, "Paris");
+        engine.engine().insert("user:2", b"not json").unwrap();
+        assert!(matches!(engine.get("user:2"), Err(Error::Serialization(_))));
+    }
+}